@@ -0,0 +1,711 @@
+use std::{
+	collections::HashMap,
+	fs,
+	path::{Path, PathBuf},
+	str::FromStr,
+	sync::{Arc, RwLock},
+};
+
+use lazy_static::lazy_static;
+
+use mdbutler::{LogFormat, Overflow};
+
+use serde::Deserialize;
+
+/// Name of the configuration file looked up in the site root.
+pub const FILE_NAME: &str = "mdbutler.toml";
+
+/// Prefix for environment variables that override the configuration file.
+const ENV_PREFIX: &str = "MDBUTLER_";
+
+lazy_static! {
+	static ref CONFIG: RwLock<Arc<Config>> = RwLock::new(Arc::new(Config::default()));
+}
+
+/// Get a handle to the currently active configuration.
+pub fn get() -> Arc<Config> {
+	CONFIG.read().unwrap().clone()
+}
+
+/// Replace the currently active configuration.
+pub fn set(config: Config) {
+	*CONFIG.write().unwrap() = Arc::new(config);
+}
+
+/// # Config
+/// Site configuration.
+///
+/// Settings are layered, each layer overriding the previous one:
+/// 1. Built-in defaults.
+/// 2. `mdbutler.toml` in the site root (or the file passed with `--config`).
+/// 3. `MDBUTLER_*` environment variables, e.g. `MDBUTLER_PORT=8081`.
+/// 4. Command line arguments.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+	/// Address to bind to.
+	pub address: String,
+	/// Port to listen on.
+	pub port: u16,
+	/// Document root of the site.
+	pub root: String,
+	/// URL path under which assets are served.
+	pub asset_path: String,
+	/// URL of the stylesheet linked from generated pages (error pages etc.).
+	pub css_path: String,
+	/// Amount of worker threads, `None` lets the backend decide.
+	pub threads: Option<usize>,
+	/// Whether to pretty-print generated HTML by default.
+	///
+	/// Clients can still override this per request with the `Pretty` header.
+	pub pretty: bool,
+	/// Development mode: watch the site roots and live reload open pages on changes.
+	pub dev: bool,
+	/// Seconds requests in flight get to finish on SIGINT/SIGTERM before we exit anyway.
+	pub shutdown_grace: u64,
+	/// Whether to serve files reached through symlinks.
+	pub follow_symlinks: SymlinkPolicy,
+	/// URL prefixes of directories that get a generated listing when they have no index,
+	/// `["/"]` enables it everywhere.
+	pub autoindex: Vec<String>,
+	/// Extra content types by file extension, e.g. `gmi = "text/gemini"`.
+	pub mime_types: HashMap<String, String>,
+	/// Response compression, the `[compression]` table.
+	pub compression: CompressionConfig,
+	/// Cache for rendered markdown and SCSS, the `[cache]` table.
+	pub cache: CacheConfig,
+	/// HTTPS listener, the `[tls]` table; needs the `tls` feature.
+	pub tls: Option<TlsConfig>,
+	/// Per-request access log, the `[access_log]` table.
+	pub access_log: AccessLogConfig,
+	/// What the server itself logs, the `[log]` table.
+	pub log: LogConfig,
+	/// Worker pool of the native backend, the `[pool]` table.
+	pub pool: PoolConfig,
+	/// Redirect and rewrite rules, written as `[[rule]]` tables.
+	///
+	/// These are checked before the `_redirects` file of a site.
+	#[serde(rename = "rule")]
+	pub rules: Vec<RuleConfig>,
+	/// Allowed methods per path prefix, written as `[[methods]]` tables.
+	#[serde(rename = "methods")]
+	pub method_policies: Vec<MethodPolicy>,
+	/// Upstream backends mounted on path prefixes or hosts, written as `[[proxy]]` tables.
+	#[serde(rename = "proxy")]
+	pub proxies: Vec<ProxyMount>,
+	/// Name-based virtual hosts, written as `[[vhost]]` tables.
+	#[serde(rename = "vhost")]
+	pub vhosts: Vec<VirtualHost>,
+	/// The file these settings were loaded from, never served even if it sits in a site root.
+	#[serde(skip)]
+	pub file: Option<PathBuf>,
+}
+
+impl Default for Config {
+	fn default() -> Self {
+		Self {
+			address: String::from("0.0.0.0"),
+			port: 8080,
+			root: String::from("/var/www/html"),
+			asset_path: String::from("/assets"),
+			css_path: String::from("/assets/css/master.css"),
+			threads: None,
+			pretty: false,
+			dev: false,
+			shutdown_grace: 10,
+			follow_symlinks: SymlinkPolicy::default(),
+			autoindex: Vec::new(),
+			mime_types: HashMap::new(),
+			compression: CompressionConfig::default(),
+			cache: CacheConfig::default(),
+			tls: None,
+			access_log: AccessLogConfig::default(),
+			log: LogConfig::default(),
+			pool: PoolConfig::default(),
+			rules: Vec::new(),
+			method_policies: Vec::new(),
+			proxies: Vec::new(),
+			vhosts: Vec::new(),
+			file: None,
+		}
+	}
+}
+
+impl Config {
+	/// Load the configuration for a site.
+	///
+	/// Arguments:
+	/// - root: Option<&str> | Site root from the command line, `mdbutler.toml` is looked up in
+	///   here; falls back to `MDBUTLER_ROOT` and then the built-in default. If given it also wins
+	///   over the `root` in the file and the environment.
+	/// - file: Option<&Path> | Explicit configuration file, must exist if passed.
+	///
+	/// Environment overrides are applied on top of the file, other command line overrides are
+	/// left to the caller.
+	pub fn load(root: Option<&str>, file: Option<&Path>) -> Result<Self, ConfigError> {
+		let cli_root = root;
+		let root = match root {
+			Some(root) => root.to_string(),
+			None => std::env::var(format!("{ENV_PREFIX}ROOT"))
+				.unwrap_or_else(|_| Config::default().root),
+		};
+
+		let file = match file {
+			Some(file) => Some(file.to_path_buf()),
+			None => match std::env::var_os(format!("{ENV_PREFIX}CONFIG")) {
+				Some(file) => Some(PathBuf::from(file)),
+				None => {
+					let file = Path::new(&root).join(FILE_NAME);
+					// A missing config file in the site root just means "use the defaults".
+					if file.is_file() {
+						Some(file)
+					} else {
+						None
+					}
+				}
+			},
+		};
+
+		let mut config = match file {
+			Some(file) => Self {
+				file: Some(file.canonicalize().unwrap_or_else(|_| file.clone())),
+				..Self::from_file(&file, &root)?
+			},
+			None => Self {
+				root,
+				..Default::default()
+			},
+		};
+
+		config.apply_env()?;
+		if let Some(root) = cli_root {
+			config.root = root.to_string();
+		}
+
+		Ok(config)
+	}
+
+	/// Parse a configuration file, without applying any overrides.
+	///
+	/// `root` is used if the file doesn't set one itself.
+	pub fn from_file(path: &Path, root: &str) -> Result<Self, ConfigError> {
+		let text =
+			fs::read_to_string(path).map_err(|why| ConfigError::Io(path.to_path_buf(), why))?;
+
+		let mut table: toml::Table =
+			toml::from_str(&text).map_err(|why| ConfigError::Parse(path.to_path_buf(), why))?;
+		table
+			.entry("root")
+			.or_insert_with(|| toml::Value::String(root.to_string()));
+
+		toml::Value::Table(table)
+			.try_into()
+			.map_err(|why| ConfigError::Parse(path.to_path_buf(), why))
+	}
+
+	/// Settings for requests that don't match any virtual host.
+	///
+	/// This is the `default = true` vhost if there is one, otherwise the top level settings.
+	pub fn default_site(&self) -> Site {
+		match self.vhosts.iter().find(|vhost| vhost.default) {
+			Some(vhost) => vhost.site(self),
+			None => Site {
+				root: self.root.clone(),
+				asset_path: self.asset_path.clone(),
+				css_path: self.css_path.clone(),
+				pretty: self.pretty,
+				follow_symlinks: self.follow_symlinks,
+				autoindex: self.autoindex.clone(),
+			},
+		}
+	}
+
+	/// Pick the site to serve for a `Host` header.
+	///
+	/// Exact names win over wildcards, longer wildcards win over shorter ones; if nothing matches
+	/// the default site is used.
+	pub fn site_for_host(&self, host: Option<&str>) -> Site {
+		let Some(host) = host.map(normalize_host) else {
+			return self.default_site();
+		};
+
+		let mut best: Option<(&VirtualHost, usize)> = None;
+		for vhost in &self.vhosts {
+			for name in &vhost.names {
+				let score = match host_matches(name, &host) {
+					Some(score) => score,
+					None => continue,
+				};
+				match best {
+					Some((_, best_score)) if best_score >= score => (),
+					_ => best = Some((vhost, score)),
+				}
+			}
+		}
+
+		match best {
+			Some((vhost, _)) => vhost.site(self),
+			None => self.default_site(),
+		}
+	}
+
+	/// Apply `MDBUTLER_*` environment variable overrides.
+	fn apply_env(&mut self) -> Result<(), ConfigError> {
+		env_override("ADDRESS", &mut self.address)?;
+		env_override("PORT", &mut self.port)?;
+		env_override("ROOT", &mut self.root)?;
+		env_override("ASSET_PATH", &mut self.asset_path)?;
+		env_override("CSS_PATH", &mut self.css_path)?;
+		env_override("PRETTY", &mut self.pretty)?;
+		env_override("DEV", &mut self.dev)?;
+		env_override("SHUTDOWN_GRACE", &mut self.shutdown_grace)?;
+		env_override("LOG_FORMAT", &mut self.log.format)?;
+		// The filter goes by the name everyone knows.
+		if let Ok(filter) = std::env::var("RUST_LOG") {
+			self.log.filter = filter;
+		}
+
+		let mut threads = 0;
+		env_override("THREADS", &mut threads)?;
+		if threads > 0 {
+			self.threads = Some(threads);
+		}
+
+		Ok(())
+	}
+}
+
+/// # VirtualHost
+/// A site selected by the `Host` header.
+///
+/// Names are either exact (`wiki.example.com`), a leading wildcard matching any subdomain
+/// (`*.example.com`) or `*` to match everything.
+/// Anything not set here is inherited from the top level settings.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VirtualHost {
+	/// Host names this site answers to.
+	pub names: Vec<String>,
+	/// Document root of the site.
+	pub root: String,
+	/// Use this site for requests that don't match any name.
+	#[serde(default)]
+	pub default: bool,
+	pub asset_path: Option<String>,
+	pub css_path: Option<String>,
+	pub pretty: Option<bool>,
+	pub follow_symlinks: Option<SymlinkPolicy>,
+	pub autoindex: Option<Vec<String>>,
+}
+
+impl VirtualHost {
+	pub fn site(&self, config: &Config) -> Site {
+		Site {
+			root: self.root.clone(),
+			asset_path: self
+				.asset_path
+				.clone()
+				.unwrap_or_else(|| config.asset_path.clone()),
+			css_path: self
+				.css_path
+				.clone()
+				.unwrap_or_else(|| config.css_path.clone()),
+			pretty: self.pretty.unwrap_or(config.pretty),
+			follow_symlinks: self.follow_symlinks.unwrap_or(config.follow_symlinks),
+			autoindex: self
+				.autoindex
+				.clone()
+				.unwrap_or_else(|| config.autoindex.clone()),
+		}
+	}
+}
+
+/// Settings for a single site, resolved from the top level config and its vhost.
+#[derive(Clone, Debug)]
+pub struct Site {
+	pub root: String,
+	pub asset_path: String,
+	pub css_path: String,
+	pub pretty: bool,
+	pub follow_symlinks: SymlinkPolicy,
+	pub autoindex: Vec<String>,
+}
+
+/// # CompressionConfig
+/// When and how to compress responses.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompressionConfig {
+	pub enabled: bool,
+	/// Compression level, 1 (fast) to 9 (small).
+	pub level: u32,
+	/// Bodies smaller than this many bytes are sent as-is.
+	pub min_size: usize,
+	/// Content type prefixes worth compressing; images, video and fonts already are.
+	pub mime_types: Vec<String>,
+	/// Serve `file.br`/`file.gz` from disk if they exist and are newer than `file`.
+	pub precompressed: bool,
+}
+
+impl Default for CompressionConfig {
+	fn default() -> Self {
+		Self {
+			enabled: true,
+			level: 6,
+			min_size: 1024,
+			mime_types: [
+				"text/",
+				"application/json",
+				"application/manifest+json",
+				"application/javascript",
+				"application/xml",
+				"application/rss+xml",
+				"application/atom+xml",
+				"application/wasm",
+				"image/svg+xml",
+			]
+			.map(String::from)
+			.to_vec(),
+			precompressed: true,
+		}
+	}
+}
+
+/// # CacheConfig
+/// Limits for the in-memory render cache.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+	pub enabled: bool,
+	/// Upper bound on the total size of cached output.
+	pub max_bytes: usize,
+}
+
+impl Default for CacheConfig {
+	fn default() -> Self {
+		Self {
+			enabled: true,
+			max_bytes: 64 * 1024 * 1024,
+		}
+	}
+}
+
+/// # PoolConfig
+/// Job queue of the native backend's worker pool.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+#[cfg_attr(not(feature = "native-server"), allow(dead_code))]
+pub struct PoolConfig {
+	/// Connections waiting for a free worker.
+	pub queue_size: usize,
+	/// What to do with connections once the queue is full: `block`, `reject` (503) or
+	/// `drop_oldest`.
+	pub overflow: Overflow,
+}
+
+impl Default for PoolConfig {
+	fn default() -> Self {
+		Self {
+			queue_size: mdbutler::DEFAULT_QUEUE_SIZE,
+			overflow: Overflow::Reject,
+		}
+	}
+}
+
+/// # RuleConfig
+/// A single redirect/rewrite rule, the same as a line in a `_redirects` file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleConfig {
+	/// Path to match: exact, a prefix ending in `*` or a regex starting with `~`.
+	pub from: String,
+	/// Where to send the request, may use `$1`, `${name}` etc.; not needed for `410`.
+	#[serde(default)]
+	pub to: String,
+	/// `301`, `302`, `307` and `308` redirect, `200` rewrites internally, `410` is gone.
+	#[serde(default = "default_rule_status")]
+	pub status: u16,
+}
+
+fn default_rule_status() -> u16 {
+	301
+}
+
+/// # ProxyMount
+/// Requests to forward to upstream HTTP backends.
+///
+/// A mount needs a `prefix`, `hosts` or both; a request has to match everything that is set.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyMount {
+	/// Path prefix, matched on whole segments (`/api` matches `/api/x` but not `/apix`).
+	pub prefix: Option<String>,
+	/// Host names like vhost names: exact, `*.example.com` or `*`.
+	#[serde(default)]
+	pub hosts: Vec<String>,
+	/// Backends to spread requests over round-robin, e.g. `http://127.0.0.1:9000`.
+	pub upstreams: Vec<String>,
+	/// Remove `prefix` from the path before forwarding.
+	#[serde(default)]
+	pub strip_prefix: bool,
+	/// Seconds to wait for a connection to an upstream.
+	#[serde(default = "default_connect_timeout")]
+	pub connect_timeout: u64,
+	/// Seconds to wait for an upstream to send (more of) its response.
+	#[serde(default = "default_read_timeout")]
+	pub read_timeout: u64,
+}
+
+fn default_connect_timeout() -> u64 {
+	5
+}
+
+fn default_read_timeout() -> u64 {
+	30
+}
+
+impl ProxyMount {
+	/// Whether a request for `path` on `host` goes to this mount.
+	pub fn matches(&self, host: Option<&str>, path: &str) -> bool {
+		if self.prefix.is_none() && self.hosts.is_empty() {
+			return false;
+		}
+
+		if !self.hosts.is_empty() {
+			let Some(host) = host.map(normalize_host) else {
+				return false;
+			};
+			if !self.hosts.iter().any(|name| host_matches(name, &host).is_some()) {
+				return false;
+			}
+		}
+
+		match &self.prefix {
+			Some(prefix) => prefix_matches(prefix, path),
+			None => true,
+		}
+	}
+}
+
+/// # MethodPolicy
+/// Methods allowed below a path prefix, written as `[[methods]]` tables.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MethodPolicy {
+	/// Path prefix, matched on whole segments; the longest matching policy wins.
+	pub prefix: String,
+	/// Method names like `GET` or `POST`, `HEAD` comes with `GET` and `OPTIONS` is always allowed.
+	pub allow: Vec<String>,
+}
+
+/// # TlsConfig
+/// Certificates and ports for serving HTTPS.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "tls"), allow(dead_code))]
+pub struct TlsConfig {
+	/// Port of the HTTPS listener, the plain one keeps using `port`.
+	#[serde(default = "default_tls_port")]
+	pub port: u16,
+	/// Certificate chain (PEM) used when no `[[tls.certificate]]` matches the requested name.
+	pub cert: Option<String>,
+	/// Private key (PEM) belonging to `cert`.
+	pub key: Option<String>,
+	/// Certificates picked by SNI.
+	#[serde(default, rename = "certificate")]
+	pub certificates: Vec<TlsCertificate>,
+	/// What the plain HTTP listener does.
+	#[serde(default)]
+	pub http: PlainHttp,
+}
+
+fn default_tls_port() -> u16 {
+	8443
+}
+
+/// A certificate for a set of host names, written as `[[tls.certificate]]` tables.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "tls"), allow(dead_code))]
+pub struct TlsCertificate {
+	/// Names like vhost names: exact, `*.example.com` or `*`.
+	pub names: Vec<String>,
+	pub cert: String,
+	pub key: String,
+}
+
+/// What to do with plain HTTP requests when HTTPS is enabled.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[cfg_attr(not(feature = "tls"), allow(dead_code))]
+pub enum PlainHttp {
+	/// Redirect everything to HTTPS.
+	#[default]
+	Redirect,
+	/// Serve the site over plain HTTP as well.
+	Serve,
+	/// Don't listen for plain HTTP at all.
+	Off,
+}
+
+/// # AccessLogConfig
+/// Where and how to write the access log.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessLogConfig {
+	/// File to append to, `-` for stdout; no access log if unset.
+	pub path: Option<String>,
+	/// `common`, `combined` or a custom format using Apache's `LogFormat` directives.
+	pub format: String,
+	/// Rotate the file once it would grow past this many bytes, `0` never rotates.
+	pub max_size: u64,
+	/// Amount of rotated files (`access.log.1`, `access.log.2`, ...) to keep around.
+	pub keep: usize,
+}
+
+impl Default for AccessLogConfig {
+	fn default() -> Self {
+		Self {
+			path: None,
+			format: String::from("combined"),
+			max_size: 0,
+			keep: 5,
+		}
+	}
+}
+
+/// # LogConfig
+/// Which log lines to write and how.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+	/// `RUST_LOG` style directives, e.g. `info,mdbutler::serve::proxy=debug`.
+	pub filter: String,
+	/// `human` or `json` (one object per line).
+	pub format: LogFormat,
+}
+
+impl Default for LogConfig {
+	fn default() -> Self {
+		Self {
+			filter: String::from("info"),
+			format: LogFormat::Human,
+		}
+	}
+}
+
+/// What to do when a requested file is (or passes through) a symlink.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SymlinkPolicy {
+	/// Follow symlinks wherever they point.
+	Follow,
+	/// Follow symlinks as long as they resolve to something inside the document root.
+	#[default]
+	WithinRoot,
+	/// Never serve anything reached through a symlink.
+	Never,
+}
+
+/// Lowercase a `Host` header and strip the port and trailing dot.
+pub fn normalize_host(host: &str) -> String {
+	let host = host.trim();
+	let host = if let Some(v6) = host.strip_prefix('[') {
+		// `[::1]:8080`
+		v6.split_once(']').map_or(v6, |(addr, _)| addr)
+	} else {
+		host.rsplit_once(':').map_or(host, |(name, _)| name)
+	};
+
+	host.trim_end_matches('.').to_lowercase()
+}
+
+/// Whether a normalized `path` lies below `prefix`, on whole segments: `/api` matches `/api`
+/// and `/api/x` but not `/apix`.
+pub fn prefix_matches(prefix: &str, path: &str) -> bool {
+	let prefix = prefix.trim_end_matches('/');
+
+	match path.strip_prefix(prefix) {
+		Some(rest) => rest.is_empty() || rest.starts_with('/'),
+		None => false,
+	}
+}
+
+/// Match a host against a vhost name, returning how specific the match was.
+pub fn host_matches(name: &str, host: &str) -> Option<usize> {
+	let name = name.trim_end_matches('.').to_lowercase();
+
+	if name == "*" {
+		Some(0)
+	} else if let Some(suffix) = name.strip_prefix("*.") {
+		match host.strip_suffix(suffix) {
+			Some(sub) if sub.ends_with('.') && sub.len() > 1 => Some(suffix.len() + 1),
+			_ => None,
+		}
+	} else if name == host {
+		Some(usize::MAX)
+	} else {
+		None
+	}
+}
+
+/// Overwrite `field` with the value of `MDBUTLER_{name}` if it is set.
+fn env_override<T: FromStr>(name: &str, field: &mut T) -> Result<(), ConfigError> {
+	let var = format!("{ENV_PREFIX}{name}");
+
+	if let Ok(val) = std::env::var(&var) {
+		*field = val.parse().map_err(|_| ConfigError::Env(var, val))?;
+	}
+
+	Ok(())
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+	/// The configuration file could not be read.
+	Io(PathBuf, std::io::Error),
+	/// The configuration file is not valid.
+	Parse(PathBuf, toml::de::Error),
+	/// An environment variable holds a value of the wrong type.
+	Env(String, String),
+}
+
+impl std::fmt::Display for ConfigError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			Self::Io(path, why) => write!(f, "Could not read `{}`: {why}", path.display()),
+			Self::Parse(path, why) => write!(f, "Invalid config `{}`: {why}", path.display()),
+			Self::Env(var, val) => write!(f, "Invalid value for `{var}`: `{val}`"),
+		}
+	}
+}
+
+impl std::error::Error for ConfigError {}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn root_precedence() {
+		let dir = std::env::temp_dir().join(format!("mdbutler-config-{}", std::process::id()));
+		fs::create_dir_all(&dir).unwrap();
+		let file = dir.join(FILE_NAME);
+		fs::write(&file, "root = \"/from/file\"\n").unwrap();
+
+		let var = format!("{ENV_PREFIX}ROOT");
+		std::env::remove_var(&var);
+		let load = |root| Config::load(root, Some(&file)).unwrap().root;
+
+		assert_eq!(load(None), "/from/file");
+
+		std::env::set_var(&var, "/from/env");
+		assert_eq!(load(None), "/from/env");
+		assert_eq!(load(Some("/from/cli")), "/from/cli");
+
+		std::env::remove_var(&var);
+		assert_eq!(load(Some("/from/cli")), "/from/cli");
+
+		fs::remove_dir_all(dir).unwrap();
+	}
+}
@@ -1,8 +1,7 @@
 use std::{
-	error::Error,
+	cell::RefCell,
 	fs,
-	path::PathBuf,
-	//str::FromStr,
+	path::{Path, PathBuf},
 };
 
 use html_node::{
@@ -17,8 +16,25 @@ use regex::Regex;
 
 use markdown::mdast;
 
+use crate::error::{MdButlerError, SourceLocation};
+use mdbutler::{debug, warn};
+
 #[cfg(feature = "ftags")]
 use ftags::FTag;
+#[cfg(feature = "ftags")]
+use std::str::FromStr;
+
+thread_local! {
+	/// Problems found in the document rendered on this thread, see [`diagnose`].
+	static DIAGNOSTICS: RefCell<Vec<Diagnostic>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Part of a document that couldn't be rendered as written. The page is still rendered, with that
+/// part left out or simplified.
+struct Diagnostic {
+	message: String,
+	location: Option<SourceLocation>,
+}
 
 /// Markdown parser options,
 /// These are options set by the page itself using the `md-opts` property in the front matter.
@@ -87,27 +103,30 @@ impl std::default::Default for MDOpts {
 	}
 }
 
-pub fn convert_to_file(path: &PathBuf, output_file: PathBuf) -> Result<(), Box<dyn Error>> {
+pub fn convert_to_file(path: &PathBuf, output_file: PathBuf) -> Result<(), MdButlerError> {
 	// Create dir if it doesn't exist
 	let parent = match output_file.parent() {
 		Some(dir) => dir,
-		None => todo!(),
+		None => {
+			return Err(MdButlerError::io(
+				&output_file,
+				std::io::Error::other("Could not find parent Directory"),
+			))
+		}
 	};
 
 	if !parent.exists() {
-		fs::create_dir_all(&parent)?;
+		fs::create_dir_all(parent).map_err(|why| MdButlerError::io(parent, why))?;
 	}
 
-	fs::write(
-		output_file,
-		convert(path.to_str().unwrap())?
-	)?;
+	let html = convert(&path.to_string_lossy())?;
+	fs::write(&output_file, html).map_err(|why| MdButlerError::io(&output_file, why))?;
 
 	Ok(())
 }
 
-pub fn convert(path: &str) -> Result<String, Box<dyn Error>> {
-	let md = fs::read_to_string(path)?;
+pub fn convert(path: &str) -> Result<String, MdButlerError> {
+	let md = fs::read_to_string(path).map_err(|why| MdButlerError::io(Path::new(path), why))?;
 
 	let html = markdown::to_html_with_options(
 		&md,
@@ -129,7 +148,8 @@ pub fn convert(path: &str) -> Result<String, Box<dyn Error>> {
 				..markdown::CompileOptions::default()
 			},
 		},
-	)?;
+	)
+	.map_err(|why| MdButlerError::markdown(Some(Path::new(path)), why))?;
 
 	Ok(format!(
 		"<!DOCTYPE html>
@@ -163,11 +183,27 @@ pub fn convert(path: &str) -> Result<String, Box<dyn Error>> {
 /// you.
 ///
 /// - Scraft161
-pub fn convert_wiki(path: &str, css_path: Option<&str>) -> Result<html_node::Node, Box<dyn Error>> {
-	let md = fs::read_to_string(path)?;
+pub fn convert_wiki(path: &str, css_path: Option<&str>) -> Result<html_node::Node, MdButlerError> {
+	let md = fs::read_to_string(path).map_err(|why| MdButlerError::io(Path::new(path), why))?;
+
+	render_wiki(&md, css_path, Some(Path::new(path))).map_err(|why| why.in_file(Path::new(path)))
+}
 
+/// # convert_wiki_str
+/// [`convert_wiki`] for markdown that doesn't come straight from a file, like error pages with
+/// their variables filled in.
+pub fn convert_wiki_str(md: &str, css_path: Option<&str>) -> Result<html_node::Node, MdButlerError> {
+	render_wiki(md, css_path, None)
+}
+
+/// Render wiki markdown, `path` is only used to point at the file in diagnostics.
+fn render_wiki(
+	md: &str,
+	css_path: Option<&str>,
+	path: Option<&Path>,
+) -> Result<html_node::Node, MdButlerError> {
 	let mdast = markdown::to_mdast(
-		&md,
+		md,
 		&markdown::ParseOptions {
 			constructs: markdown::Constructs {
 				//character_reference: true,
@@ -179,9 +215,14 @@ pub fn convert_wiki(path: &str, css_path: Option<&str>) -> Result<html_node::Nod
 			gfm_strikethrough_single_tilde: false,
 			..markdown::ParseOptions::gfm()
 		},
-	)?;
+	)
+	.map_err(|why| MdButlerError::markdown(None, why))?;
+
+	DIAGNOSTICS.with_borrow_mut(Vec::clear);
+	let doc = traverse_mdast(&MDOpts::new(), mdast, false, css_path);
+	report_diagnostics(path);
 
-	if let Some(doc) = traverse_mdast(&MDOpts::new(), mdast, false, css_path) {
+	if let Some(doc) = doc {
 		if let html_node::Node::Fragment(ref fragment) = doc {
 			//dbg!(fragment);
 			//let index = generate_index(fragment.clone());
@@ -200,10 +241,57 @@ pub fn convert_wiki(path: &str, css_path: Option<&str>) -> Result<html_node::Nod
 
 		Ok(doc)
 	} else {
-		Err("help".into())
+		Err(MdButlerError::markdown(None, String::from("The document has nothing to render")))
+	}
+}
+
+/// Note part of the document that can't be rendered as written, it is reported once rendering is
+/// done.
+fn diagnose(position: Option<&markdown::unist::Position>, message: impl Into<String>) {
+	let location = position.map(|position| SourceLocation {
+		path: None,
+		line: position.start.line,
+		column: position.start.column,
+	});
+	DIAGNOSTICS.with_borrow_mut(|diagnostics| {
+		diagnostics.push(Diagnostic {
+			message: message.into(),
+			location,
+		})
+	});
+}
+
+/// Print what [`diagnose`] collected while rendering the document at `path`.
+fn report_diagnostics(path: Option<&Path>) {
+	for diagnostic in DIAGNOSTICS.take() {
+		let location = diagnostic.location.map(|location| SourceLocation {
+			path: path.map(Path::to_path_buf),
+			..location
+		});
+
+		match (location, path) {
+			(Some(location), _) => warn!("{location}: {}", diagnostic.message),
+			(None, Some(path)) => warn!("{}: {}", path.display(), diagnostic.message),
+			(None, None) => warn!("{}", diagnostic.message),
+		}
 	}
 }
 
+/// # page_title
+/// Get the `title:` from the front matter of a markdown file without rendering it.
+pub fn page_title(path: &Path) -> Option<String> {
+	let md = fs::read_to_string(path).ok()?;
+
+	let front_matter = md.strip_prefix("---\n")?;
+	let end = front_matter.find("\n---")?;
+
+	DIAGNOSTICS.with_borrow_mut(Vec::clear);
+	let meta = PageMeta::from_yaml(&front_matter[..end], None);
+	report_diagnostics(Some(path));
+
+	meta.title
+}
+
 fn traverse_mdast(
 	md_opts: &MDOpts,
 	node: markdown::mdast::Node,
@@ -222,12 +310,13 @@ fn traverse_mdast(
 					Some(child) => children.push(child),
 					None => match md_child {
 						mdast::Node::Yaml(yaml) => {
-							page_meta = PageMeta::from_yaml(&yaml.value);
+							page_meta = PageMeta::from_yaml(&yaml.value, yaml.position.as_ref());
 							md_opts = MDOpts::from_yaml(&yaml.value);
 						}
-						mdast::Node::Toml(_toml) => {
-							todo!()
-						}
+						mdast::Node::Toml(toml) => diagnose(
+							toml.position.as_ref(),
+							"TOML front matter isn't supported, use YAML (`---`) instead",
+						),
 						_ => continue,
 					},
 				};
@@ -304,7 +393,7 @@ fn traverse_mdast(
 			// If we didn't attach the index yet; just put it at the top.
 			if !index_attached {
 				children.insert(
-					1,
+					children.len().min(1),
 					html!(
 						<div id="index" class="index">
 							{index}
@@ -372,8 +461,19 @@ fn traverse_mdast(
 				}
 			}
 
-			let html_node::Node::Text(text) = &children[0] else {
-				return None;
+			let Some(html_node::Node::Text(text)) = children.first() else {
+				// Nothing to take an id from, keep whatever the heading has.
+				if !children.is_empty() {
+					diagnose(
+						heading.position.as_ref(),
+						"Heading doesn't start with plain text, it gets no id",
+					);
+				}
+				return Some(html_node::Node::Element(html_node::Element {
+					name: format!("h{}", heading.depth),
+					attributes: Vec::new(),
+					children: Some(children),
+				}));
 			};
 			let mut title = String::from(&text.text);
 
@@ -390,7 +490,7 @@ fn traverse_mdast(
 		}
 		mdast::Node::Text(text) => {
 			if text.value.starts_with(":>! ") {
-				Some(render_spoiler(&text.value))
+				Some(render_spoiler(&text.value, text.position.as_ref()))
 			} else if INLINE_SPOILER.is_match(&text.value) {
 				Some(render_inline_spoiler(&text.value))
 			} else {
@@ -469,12 +569,19 @@ fn traverse_mdast(
 			};
 			let img_alt = image.alt.clone();
 
-			let (img_url_jxl, img_url_webp) = {
-				let (img_basename, _) = image.url.rsplit_once(".").unwrap();
-				(
-					img_basename.to_owned() + ".jxl",
-					img_basename.to_owned() + ".webp",
-				)
+			// Offer JXL and WebP versions next to the original, which needs an extension to swap.
+			let sources = match image.url.rsplit_once('.') {
+				Some((img_basename, ext)) if !ext.contains('/') => html!(
+					<source srcset=format!("{img_basename}.jxl") type="image/jxl">
+					<source srcset=format!("{img_basename}.webp") type="image/webp">
+				),
+				_ => {
+					diagnose(
+						image.position.as_ref(),
+						format!("Image `{}` has no extension, not offering JXL or WebP versions", image.url),
+					);
+					html!(<>)
+				}
 			};
 
 			if IMAGE_W_H.is_match(&image.alt) {
@@ -483,32 +590,28 @@ fn traverse_mdast(
 				if let (Some(width), Some(height)) = (&m_width, &m_height) {
 					Some(html!(
 						<picture>
-							<source srcset=img_url_jxl type="image/jxl">
-							<source srcset=img_url_webp type="image/webp">
+							{sources}
 							<img alt=alt src=image.url title=title width=format!("{width}") height=format!("{height}") loading="lazy">
 						</picture>
 					))
 				} else if let Some(width) = &m_width {
 					Some(html!(
 						<picture>
-							<source srcset=img_url_jxl type="image/jxl">
-							<source srcset=img_url_webp type="image/webp">
+							{sources}
 							<img alt=alt src=image.url title=title width=format!("{width}") loading="lazy">
 						</picture>
 					))
 				} else if let Some(height) = &m_height {
 					Some(html!(
 						<picture>
-							<source srcset=img_url_jxl type="image/jxl">
-							<source srcset=img_url_webp type="image/webp">
+							{sources}
 							<img alt=alt src=image.url title=title height=format!("{height}") loading="lazy">
 						</picture>
 					))
 				} else {
 					Some(html!(
 						<picture>
-							<source srcset=img_url_jxl type="image/jxl">
-							<source srcset=img_url_webp type="image/webp">
+							{sources}
 							<img alt=img_alt src=image.url title=title loading="lazy">
 						</picture>
 					))
@@ -516,8 +619,7 @@ fn traverse_mdast(
 			} else {
 				Some(html!(
 					<picture>
-						<source srcset=img_url_jxl type="image/jxl">
-						<source srcset=img_url_webp type="image/webp">
+						{sources}
 						<img alt=img_alt src=image.url title=title loading="lazy">
 					</picture>
 				))
@@ -611,20 +713,32 @@ fn traverse_mdast(
 		mdast::Node::Table(table) => {
 			//dbg!(&table);
 
+			// Cells beyond what the delimiter row covers (ragged tables) get no alignment.
+			let align = |i: usize| table.align.get(i).copied().unwrap_or(mdast::AlignKind::None);
+			for row in &table.children {
+				let cells = row.children().map_or(0, Vec::len);
+				if cells > table.align.len() {
+					diagnose(
+						row.position(),
+						format!("Table row has {cells} cells but the header only {}", table.align.len()),
+					);
+				}
+			}
+
 			//let mut children: Vec<html_node::Node> = Vec::new();
 			let thead = html!(
 				<tr>
-				{table.children[0].children().unwrap().into_iter().zip(0..).map(|(th, i)|
-					if mdast::AlignKind::None != table.align[i] {
+				{table.children.first().and_then(mdast::Node::children).into_iter().flatten().zip(0..).map(|(th, i)|
+					if mdast::AlignKind::None != align(i) {
 						html!(
-							<th class=match table.align[i] {
+							<th class=match align(i) {
 								mdast::AlignKind::Left   => "align-left",
 								mdast::AlignKind::Center => "align-center",
 								mdast::AlignKind::Right  => "align-right",
 								mdast::AlignKind::None   => "",
 							}>{
 								let mut children = Vec::new();
-								for child in th.children().unwrap() {
+								for child in th.children().into_iter().flatten() {
 									if let Some(child) = traverse_mdast(md_opts, child.clone(), false, css_path) {
 										children.push(child);
 									}
@@ -637,7 +751,7 @@ fn traverse_mdast(
 						html!(
 							<th>{
 								let mut children = Vec::new();
-								for child in th.children().unwrap() {
+								for child in th.children().into_iter().flatten() {
 									if let Some(child) = traverse_mdast(md_opts, child.clone(), false, css_path) {
 										children.push(child);
 									}
@@ -654,22 +768,22 @@ fn traverse_mdast(
 				<>
 				{
 					let mut data = Vec::new();
-					for child in &table.children[1..] {
+					for child in table.children.iter().skip(1) {
 						//dbg!(&child);
 						data.push(html!(
 							<tr>
 							{
-								child.children().unwrap().into_iter().zip(0..).map(|(tc, i)| {
-									if mdast::AlignKind::None != table.align[i] {
+								child.children().into_iter().flatten().zip(0..).map(|(tc, i)| {
+									if mdast::AlignKind::None != align(i) {
 										html!{
-											<td class=match table.align[i] {
+											<td class=match align(i) {
 												mdast::AlignKind::Left   => "align-left",
 												mdast::AlignKind::Center => "align-center",
 												mdast::AlignKind::Right  => "align-right",
 												mdast::AlignKind::None   => "",
 											}>{
 												let mut children = Vec::new();
-												for child in tc.children().unwrap() {
+												for child in tc.children().into_iter().flatten() {
 													if let Some(child) = traverse_mdast(md_opts, child.clone(), false, css_path) {
 														children.push(child);
 													}
@@ -679,7 +793,8 @@ fn traverse_mdast(
 											}</td>
 										}
 									} else {
-										traverse_mdast(md_opts, tc.clone(), true, css_path).unwrap()
+										traverse_mdast(md_opts, tc.clone(), true, css_path)
+											.unwrap_or_else(|| html!(<td></td>))
 									}
 								})
 							}
@@ -782,20 +897,26 @@ fn traverse_mdast(
 			))
 		}
 		_ => {
-			dbg!(node);
+			debug!("Skipping unsupported markdown node: {node:?}");
 			None
 		}
 	}
 }
 
-fn render_spoiler(text: &str) -> html_node::Node {
+fn render_spoiler(text: &str, position: Option<&markdown::unist::Position>) -> html_node::Node {
 	element! {
 		summary("summary") {
 			custom_attr,
 		}
 	}
-	let (title, content) = text[4..].split_once('\n').unwrap();
-	let (content, remainder) = content.rsplit_once("!<:").unwrap();
+	let (title, content) = text[4..].split_once('\n').unwrap_or((&text[4..], ""));
+	let (content, remainder) = match content.rsplit_once("!<:") {
+		Some(split) => split,
+		None => {
+			diagnose(position, "Spoiler isn't closed with `!<:`, it runs to the end of the paragraph");
+			(content, "")
+		}
+	};
 	html!(
 		<details>
 			<summary>{text!("{title}")}</summary>
@@ -843,27 +964,26 @@ lazy_static! {
 }
 
 fn id_from_text(text: &str) -> (String, Option<usize>) {
-	if HEADING_WITH_ID.is_match(text) {
-		let re_match = HEADING_WITH_ID.find(text).unwrap();
-		let Some((_, id)) = re_match.as_str().split_once('{') else {
-			todo!()
-		};
-		let Some((id, _)) = id.rsplit_once('}') else {
-			todo!()
-		};
-		(String::from(id), Some(re_match.start()))
-	} else {
-		(text.to_lowercase().replace(' ', "-"), None)
+	match HEADING_WITH_ID.find(text) {
+		Some(re_match) => {
+			// The pattern makes sure of the braces, ` {id}`.
+			let id = re_match.as_str().trim_start_matches(" {").trim_end_matches('}');
+			(String::from(id), Some(re_match.start()))
+		}
+		None => (text.to_lowercase().replace(' ', "-"), None),
 	}
 }
 
 fn image_props_from_text(text: &str) -> (String, Option<String>, Option<String>) {
-	let caps = IMAGE_W_H.captures(text).unwrap();
+	let Some(caps) = IMAGE_W_H.captures(text) else {
+		return (text.to_string(), None, None);
+	};
+	let props = caps.get(0).map_or(0..0, |m| m.range());
 	let width = caps.name("width").map_or("", |m| m.as_str());
 	let height = caps.name("height").map_or("", |m| m.as_str());
 
 	(
-		text[0..text.len() - caps[0].len()].to_string(),
+		format!("{}{}", &text[..props.start], &text[props.end..]),
 		if width != "" {
 			Some(format!("{}", &caps["width"]))
 		} else {
@@ -917,7 +1037,7 @@ fn generate_index(root: html_node::Fragment) -> html_node::Node {
 				match element.name.as_str() {
 					"h2" => {
 						let mut name = String::new();
-						for child in element.children.unwrap() {
+						for child in element.children.into_iter().flatten() {
 							match child {
 								html_node::Node::Text(text) => {
 									name = text.text;
@@ -934,7 +1054,7 @@ fn generate_index(root: html_node::Fragment) -> html_node::Node {
 					}
 					"h3" => {
 						let mut name = String::new();
-						for child in element.children.unwrap() {
+						for child in element.children.into_iter().flatten() {
 							match child {
 								html_node::Node::Text(text) => {
 									name = text.text;
@@ -955,7 +1075,7 @@ fn generate_index(root: html_node::Fragment) -> html_node::Node {
 					}
 					"h4" => {
 						let mut name = String::new();
-						for child in element.children.unwrap() {
+						for child in element.children.into_iter().flatten() {
 							match child {
 								html_node::Node::Text(text) => {
 									name = text.text;
@@ -1044,7 +1164,10 @@ impl PageMeta {
 		}
 	}
 
-	fn from_yaml(yaml: &str) -> Self {
+	/// Read the front matter found at `position`, tags that don't parse are left out and reported
+	/// through [`diagnose`].
+	#[cfg_attr(not(feature = "ftags"), allow(unused_variables))]
+	fn from_yaml(yaml: &str, position: Option<&markdown::unist::Position>) -> Self {
 		// Loop over the yaml string and match based on prefix
 		let mut title = String::new();
 		#[cfg(feature = "ftags")]
@@ -1054,9 +1177,18 @@ impl PageMeta {
 				title = yaml_title.to_string();
 			} else if let Some(yaml_tags) = str.strip_prefix("tags: ") {
 				#[cfg(feature = "ftags")]
-				yaml_tags
-					.split(',')
-					.for_each(|s| tags.push(FTag::from_str(s.trim()).unwrap()))
+				for tag in yaml_tags.split(',').map(str::trim) {
+					if tag.is_empty() {
+						diagnose(position, "Empty tag, it is left out");
+						continue;
+					}
+					match FTag::from_str(tag) {
+						Ok(tag) => tags.push(tag),
+						Err(_) => {
+							diagnose(position, format!("Invalid tag `{tag}`, it is left out"))
+						}
+					}
+				}
 			}
 		}
 
@@ -1067,3 +1199,37 @@ impl PageMeta {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const BAD_TAGS: &str = "---\ntitle: Tagged\ntags: news, , %%not a tag%%\n---\n\n# Tagged\n";
+
+	#[test]
+	fn bad_tags_dont_panic() {
+		assert!(convert_wiki_str(BAD_TAGS, None).is_ok());
+	}
+
+	#[test]
+	fn page_title_survives_bad_tags() {
+		let path = std::env::temp_dir().join(format!("mdbutler-tags-{}.md", std::process::id()));
+		fs::write(&path, BAD_TAGS).unwrap();
+
+		assert_eq!(page_title(&path).as_deref(), Some("Tagged"));
+
+		fs::remove_file(path).unwrap();
+	}
+
+	#[cfg(feature = "ftags")]
+	#[test]
+	fn bad_tags_are_diagnosed() {
+		DIAGNOSTICS.with_borrow_mut(Vec::clear);
+		let meta = PageMeta::from_yaml("title: Tagged\ntags: news, ", None);
+
+		assert_eq!(meta.title.as_deref(), Some("Tagged"));
+		assert!(DIAGNOSTICS.with_borrow(|diagnostics| diagnostics
+			.iter()
+			.any(|diagnostic| diagnostic.message.contains("Empty tag"))));
+	}
+}
@@ -1,13 +1,48 @@
+use std::cell::RefCell;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+use crate::error::MdButlerError;
+use mdbutler::debug;
 
 //fn convert() {}
 
-pub fn convert_to_file(
-	path: &PathBuf,
-	output_file: PathBuf,
-) -> Result<(), Box<dyn std::error::Error>> {
-	if path.file_name().unwrap().to_str().unwrap().starts_with('_') {
+/// # convert_with_dependencies
+/// Compile a SCSS/SASS file, also returning every file that was read to do so.
+///
+/// The first entry is `path` itself, followed by any partials it (indirectly) imported.
+pub fn convert_with_dependencies(path: &Path) -> Result<(String, Vec<PathBuf>), MdButlerError> {
+	let fs = RecordingFs::default();
+	let css = grass::from_path(path, &grass::Options::default().fs(&fs))
+		.map_err(|why| MdButlerError::sass(path, why))?;
+
+	Ok((css, fs.read.into_inner()))
+}
+
+/// A `grass::Fs` that remembers which files it has read.
+#[derive(Debug, Default)]
+struct RecordingFs {
+	read: RefCell<Vec<PathBuf>>,
+}
+
+impl grass::Fs for RecordingFs {
+	fn is_dir(&self, path: &Path) -> bool {
+		path.is_dir()
+	}
+
+	fn is_file(&self, path: &Path) -> bool {
+		path.is_file()
+	}
+
+	fn read(&self, path: &Path) -> std::io::Result<Vec<u8>> {
+		self.read.borrow_mut().push(path.to_path_buf());
+		fs::read(path)
+	}
+}
+
+pub fn convert_to_file(path: &PathBuf, output_file: PathBuf) -> Result<(), MdButlerError> {
+	// Partials only exist to be imported.
+	if path.file_name().is_some_and(|name| name.to_string_lossy().starts_with('_')) {
 		return Ok(());
 	}
 
@@ -20,22 +55,26 @@ pub fn convert_to_file(
 	//	}
 	//}
 
-	println!("{path:?}:{output_file:?}");
+	debug!("{} -> {}", path.display(), output_file.display());
 
 	// Create dir if it doesn't exist
 	let parent = match output_file.parent() {
 		Some(dir) => dir,
-		None => return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "Could not find parent Directory"))),
+		None => {
+			return Err(MdButlerError::io(
+				&output_file,
+				std::io::Error::other("Could not find parent Directory"),
+			))
+		}
 	};
 
 	if !parent.exists() {
-		fs::create_dir_all(&parent)?;
+		fs::create_dir_all(parent).map_err(|why| MdButlerError::io(parent, why))?;
 	}
 
-	fs::write(
-		output_file,
-		grass::from_path(path, &grass::Options::default())?,
-	)?;
+	let css = grass::from_path(path, &grass::Options::default())
+		.map_err(|why| MdButlerError::sass(path, why))?;
+	fs::write(&output_file, css).map_err(|why| MdButlerError::io(&output_file, why))?;
 
 	Ok(())
 }
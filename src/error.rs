@@ -0,0 +1,197 @@
+use std::{
+	fmt, io,
+	path::{Path, PathBuf},
+};
+
+/// Raw OS errors for running out of file descriptors (`ENFILE`, `EMFILE`); `io::ErrorKind` has no
+/// stable variant for them.
+const OUT_OF_FILES: [i32; 2] = [23, 24];
+
+/// # SourceLocation
+/// Where in a source file something went wrong, lines and columns start at 1.
+#[derive(Debug, Clone)]
+pub struct SourceLocation {
+	/// Unknown for sources that don't come from a file.
+	pub path: Option<PathBuf>,
+	pub line: usize,
+	pub column: usize,
+}
+
+impl fmt::Display for SourceLocation {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match &self.path {
+			Some(path) => write!(f, "{}:{}:{}", path.display(), self.line, self.column),
+			None => write!(f, "{}:{}", self.line, self.column),
+		}
+	}
+}
+
+/// # MdButlerError
+/// Why a file couldn't be served or converted.
+#[derive(Debug)]
+pub enum MdButlerError {
+	/// Nothing there to serve.
+	NotFound,
+	/// The file exists but may not be served, or we may not read it.
+	Forbidden(String),
+	/// Reading or writing failed in some other way.
+	Io(PathBuf, io::Error),
+	/// A markdown page failed to render.
+	#[cfg(feature = "markdown")]
+	Markdown {
+		message: String,
+		location: Option<SourceLocation>,
+	},
+	/// A stylesheet failed to compile.
+	#[cfg(feature = "sass")]
+	Sass {
+		message: String,
+		location: Option<SourceLocation>,
+	},
+	/// We ran out of something (file descriptors, memory), trying again later may work.
+	Unavailable(String),
+}
+
+impl MdButlerError {
+	/// # io
+	/// Sort an I/O error on `path` into the variant it deserves.
+	pub fn io(path: &Path, why: io::Error) -> Self {
+		let out_of_files = why
+			.raw_os_error()
+			.is_some_and(|code| OUT_OF_FILES.contains(&code));
+
+		match why.kind() {
+			io::ErrorKind::NotFound => Self::NotFound,
+			io::ErrorKind::PermissionDenied => {
+				Self::Forbidden(format!("`{}`: {why}", path.display()))
+			}
+			io::ErrorKind::WouldBlock
+			| io::ErrorKind::TimedOut
+			| io::ErrorKind::Interrupted
+			| io::ErrorKind::OutOfMemory => Self::Unavailable(format!("`{}`: {why}", path.display())),
+			_ if out_of_files => Self::Unavailable(format!("`{}`: {why}", path.display())),
+			_ => Self::Io(path.to_path_buf(), why),
+		}
+	}
+
+	/// # markdown
+	/// Wrap an error from the markdown parser, which puts the position in front of the message
+	/// (`line:column: message`).
+	#[cfg(feature = "markdown")]
+	pub fn markdown(path: Option<&Path>, message: String) -> Self {
+		let parsed = message.split_once(": ").and_then(|(position, rest)| {
+			let (line, column) = position.split_once(':')?;
+			Some((line.parse().ok()?, column.parse().ok()?, rest))
+		});
+
+		match parsed {
+			Some((line, column, rest)) => Self::Markdown {
+				message: rest.to_string(),
+				location: Some(SourceLocation {
+					path: path.map(Path::to_path_buf),
+					line,
+					column,
+				}),
+			},
+			None => Self::Markdown {
+				message,
+				location: None,
+			},
+		}
+	}
+
+	/// # sass
+	/// Wrap an error from grass, keeping where in which file (maybe a partial) it happened.
+	#[cfg(feature = "sass")]
+	pub fn sass(path: &Path, why: Box<grass::Error>) -> Self {
+		// The full message comes with an excerpt of the offending line, keep that around.
+		let message = why.to_string();
+
+		match why.kind() {
+			grass::ErrorKind::ParseError { loc, .. } => Self::Sass {
+				message,
+				location: Some(SourceLocation {
+					path: Some(PathBuf::from(loc.file.name())),
+					line: loc.begin.line + 1,
+					column: loc.begin.column + 1,
+				}),
+			},
+			grass::ErrorKind::IoError(why) => {
+				Self::io(path, io::Error::new(why.kind(), why.to_string()))
+			}
+			_ => Self::Sass {
+				message,
+				location: None,
+			},
+		}
+	}
+
+	/// Fill in the file a location refers to, if the error didn't know it.
+	#[cfg(feature = "markdown")]
+	pub fn in_file(mut self, path: &Path) -> Self {
+		if let Self::Markdown {
+			location: Some(location),
+			..
+		} = &mut self
+		{
+			location.path.get_or_insert_with(|| path.to_path_buf());
+		}
+		self
+	}
+
+	/// The HTTP status to answer with.
+	#[cfg(feature = "serve")]
+	pub fn status(&self) -> u16 {
+		match self {
+			Self::NotFound => 404,
+			Self::Forbidden(_) => 403,
+			Self::Io(..) => 500,
+			#[cfg(feature = "markdown")]
+			Self::Markdown { .. } => 500,
+			#[cfg(feature = "sass")]
+			Self::Sass { .. } => 500,
+			Self::Unavailable(_) => 503,
+		}
+	}
+
+	/// Where in the source the error happened, if we know.
+	#[cfg(feature = "serve")]
+	pub fn location(&self) -> Option<&SourceLocation> {
+		match self {
+			#[cfg(feature = "markdown")]
+			Self::Markdown { location, .. } => location.as_ref(),
+			#[cfg(feature = "sass")]
+			Self::Sass { location, .. } => location.as_ref(),
+			_ => None,
+		}
+	}
+}
+
+impl fmt::Display for MdButlerError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::NotFound => write!(f, "File not found"),
+			Self::Forbidden(why) => write!(f, "Forbidden: {why}"),
+			Self::Io(path, why) => write!(f, "`{}`: {why}", path.display()),
+			#[cfg(feature = "markdown")]
+			Self::Markdown {
+				message,
+				location: Some(location),
+			} => write!(f, "Failed to render markdown at {location}: {message}"),
+			#[cfg(feature = "markdown")]
+			Self::Markdown { message, .. } => write!(f, "Failed to render markdown: {message}"),
+			#[cfg(feature = "sass")]
+			Self::Sass { message, .. } => write!(f, "Failed to compile SCSS: {message}"),
+			Self::Unavailable(why) => write!(f, "Temporarily unavailable: {why}"),
+		}
+	}
+}
+
+impl std::error::Error for MdButlerError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			Self::Io(_, why) => Some(why),
+			_ => None,
+		}
+	}
+}
@@ -1,191 +1,1450 @@
 use std::{
-	sync::{mpsc, Arc, Mutex},
+	cell::Cell,
+	collections::VecDeque,
+	io::Write,
+	marker::PhantomData,
+	net::SocketAddr,
+	panic::{self, AssertUnwindSafe},
+	sync::{
+		atomic::{AtomicU64, AtomicUsize, Ordering},
+		mpsc, Arc, Condvar, Mutex, MutexGuard, OnceLock, PoisonError,
+	},
 	thread,
 };
 
+use serde::Deserialize;
+
+/// Jobs [`ThreadPool::new`] queues up before [`Overflow`] kicks in.
+pub const DEFAULT_QUEUE_SIZE: usize = 1024;
+
+/// # Overflow
+/// What [`ThreadPool::execute`] does when the queue is full.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Overflow {
+	/// Wait for a worker to take a job off the queue.
+	#[default]
+	Block,
+	/// Refuse the new job, servers answer with 503.
+	Reject,
+	/// Throw away the job that waited longest to make room.
+	DropOldest,
+}
+
+/// # Rejected
+/// The job wasn't queued, see [`Overflow::Reject`].
+#[derive(Debug)]
+pub struct Rejected;
+
+impl std::fmt::Display for Rejected {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "Job queue is full")
+	}
+}
+
+impl std::error::Error for Rejected {}
+
+/// # PoolStats
+/// A snapshot of what a [`ThreadPool`] is up to.
+#[derive(Clone, Copy, Debug)]
+pub struct PoolStats {
+	pub workers: usize,
+	/// Workers running a job.
+	pub active: usize,
+	/// Workers waiting for a job.
+	pub idle: usize,
+	/// Jobs waiting for a worker.
+	pub queued: usize,
+	pub queue_size: usize,
+	/// Jobs that panicked, each cost a worker that got replaced.
+	pub panicked: usize,
+	/// Jobs turned away by [`Overflow::Reject`].
+	pub rejected: usize,
+	/// Jobs thrown away by [`Overflow::DropOldest`].
+	pub dropped: usize,
+}
+
 pub struct ThreadPool {
-	workers: Vec<Worker>,
-	sender: Option<mpsc::Sender<Job>>,
+	size: usize,
+	shared: Arc<Shared>,
 }
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
+/// Everything the pool and its workers share.
+struct Shared {
+	queue: Mutex<Queue>,
+	/// Signalled when a job is queued or the pool closes.
+	available: Condvar,
+	/// Signalled when a job leaves the queue.
+	space: Condvar,
+	queue_size: usize,
+	overflow: Overflow,
+	/// Worker threads by id, replaced workers swap in their successor.
+	threads: Mutex<Vec<Option<thread::JoinHandle<()>>>>,
+	active: AtomicUsize,
+	panicked: AtomicUsize,
+	rejected: AtomicUsize,
+	dropped: AtomicUsize,
+}
+
+struct Queue {
+	jobs: VecDeque<Job>,
+	closed: bool,
+}
+
+impl Shared {
+	fn push(&self, job: Job, overflow: Overflow) -> Result<(), Rejected> {
+		let mut queue = lock(&self.queue);
+		while queue.jobs.len() >= self.queue_size {
+			match overflow {
+				Overflow::Block => {
+					queue = self
+						.space
+						.wait(queue)
+						.unwrap_or_else(PoisonError::into_inner);
+				}
+				Overflow::Reject => {
+					self.rejected.fetch_add(1, Ordering::Relaxed);
+					return Err(Rejected);
+				}
+				Overflow::DropOldest => {
+					// Dropped outside the lock below, whatever it holds may take a while to close.
+					let oldest = queue.jobs.pop_front();
+					self.dropped.fetch_add(1, Ordering::Relaxed);
+					queue.jobs.push_back(job);
+					drop(queue);
+					drop(oldest);
+					self.available.notify_one();
+					return Ok(());
+				}
+			}
+		}
+		queue.jobs.push_back(job);
+		drop(queue);
+
+		self.available.notify_one();
+		Ok(())
+	}
+}
+
+/// # JobError
+/// Why a job has no result.
+#[derive(Debug)]
+pub enum JobError {
+	/// The job panicked, with the panic message if there was one.
+	Panicked(String),
+	/// The job was thrown away before it ran, see [`Overflow::DropOldest`].
+	Cancelled,
+}
+
+impl std::fmt::Display for JobError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Panicked(message) => write!(f, "Job panicked: {message}"),
+			Self::Cancelled => write!(f, "Job was cancelled"),
+		}
+	}
+}
+
+impl std::error::Error for JobError {}
+
+/// # JobHandle
+/// The pending result of a job from [`ThreadPool::submit`].
+#[derive(Debug)]
+pub struct JobHandle<T> {
+	result: mpsc::Receiver<Result<T, JobError>>,
+}
+
+impl<T> JobHandle<T> {
+	/// Wait for the job to finish.
+	pub fn join(self) -> Result<T, JobError> {
+		self.result.recv().unwrap_or(Err(JobError::Cancelled))
+	}
+}
+
+/// # Scope
+/// Queues jobs that may borrow from outside, see [`ThreadPool::scope`].
+pub struct Scope<'scope, 'env: 'scope> {
+	shared: Arc<Shared>,
+	pending: Arc<Pending>,
+	/// Invariant lifetimes like `std::thread::Scope`, so jobs can't escape.
+	scope: PhantomData<&'scope mut &'scope ()>,
+	env: PhantomData<&'env mut &'env ()>,
+}
+
+/// Jobs of a [`Scope`] that haven't finished yet.
+#[derive(Debug)]
+struct Pending {
+	count: Mutex<usize>,
+	done: Condvar,
+	/// Jobs that panicked without anyone joining them.
+	panicked: AtomicUsize,
+}
+
+/// Counts a scoped job as finished when dropped, whether it ran, panicked or was thrown away.
+struct Finished(Arc<Pending>);
+
+impl Drop for Finished {
+	fn drop(&mut self) {
+		let mut count = lock(&self.0.count);
+		*count -= 1;
+		if *count == 0 {
+			self.0.done.notify_all();
+		}
+	}
+}
+
+impl<'scope, 'env> Scope<'scope, 'env> {
+	/// Queue `f`, returning a handle to wait for its result.
+	pub fn execute<F, T>(&'scope self, f: F) -> ScopedJobHandle<'scope, T>
+	where
+		F: FnOnce() -> T + Send + 'scope,
+		T: Send + 'scope,
+	{
+		let (sender, result) = mpsc::sync_channel(1);
+
+		*lock(&self.pending.count) += 1;
+		let finished = Finished(Arc::clone(&self.pending));
+		let pending = Arc::clone(&self.pending);
+
+		let job: Box<dyn FnOnce() + Send + 'scope> = Box::new(move || {
+			let _finished = finished;
+			run_reporting(f, &sender, || {
+				pending.panicked.fetch_add(1, Ordering::Relaxed);
+			});
+		});
+		// SAFETY: The transmute only widens the lifetime of the box to `'static`, the type is
+		// otherwise the same. `Scope` is only ever handed out by `ThreadPool::scope`, which
+		// waits for the pending count to reach zero before it returns or unwinds, even when `f`
+		// or a job panicked. The count only drops when `Finished` does, which the job owns: after
+		// it ran, when it panicked, or when the queue threw it away unrun. So the job, and all it
+		// borrows for `'scope`, is gone before anything it borrows can be.
+		let job = unsafe { std::mem::transmute::<Box<dyn FnOnce() + Send + 'scope>, Job>(job) };
+
+		// Blocking never rejects.
+		let _ = self.shared.push(job, Overflow::Block);
+
+		ScopedJobHandle {
+			result,
+			pending: Arc::clone(&self.pending),
+			scope: PhantomData,
+		}
+	}
+}
+
+/// # ScopedJobHandle
+/// The pending result of a job from [`Scope::execute`].
+#[derive(Debug)]
+pub struct ScopedJobHandle<'scope, T> {
+	result: mpsc::Receiver<Result<T, JobError>>,
+	pending: Arc<Pending>,
+	scope: PhantomData<&'scope ()>,
+}
+
+impl<T> ScopedJobHandle<'_, T> {
+	/// Wait for the job to finish, a panic reported here doesn't fail the whole scope.
+	pub fn join(self) -> Result<T, JobError> {
+		let result = self.result.recv().unwrap_or(Err(JobError::Cancelled));
+		if let Err(JobError::Panicked(_)) = result {
+			self.pending.panicked.fetch_sub(1, Ordering::Relaxed);
+		}
+		result
+	}
+}
+
+/// Run `f` and send its result.
+///
+/// A panic is reported (after calling `on_panic`) and passed on, so the worker still gets
+/// replaced.
+fn run_reporting<T>(
+	f: impl FnOnce() -> T,
+	sender: &mpsc::SyncSender<Result<T, JobError>>,
+	on_panic: impl FnOnce(),
+) {
+	match panic::catch_unwind(AssertUnwindSafe(f)) {
+		Ok(value) => {
+			let _ = sender.send(Ok(value));
+		}
+		Err(payload) => {
+			let message = payload
+				.downcast_ref::<&str>()
+				.map(|message| message.to_string())
+				.or_else(|| payload.downcast_ref::<String>().cloned())
+				.unwrap_or_default();
+			on_panic();
+			let _ = sender.send(Err(JobError::Panicked(message)));
+			panic::resume_unwind(payload)
+		}
+	}
+}
+
+/// Jobs run outside of any lock, so a poisoned one only means a panic elsewhere.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+	mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
 impl ThreadPool {
 	/// Create a new ThreadPool.
 	///
-	/// The size is the number of threads in the pool.
+	/// The size is the number of threads in the pool, jobs beyond that wait in a queue of
+	/// [`DEFAULT_QUEUE_SIZE`] and [`ThreadPool::execute`] blocks once it's full.
 	///
 	/// # Panics
 	///
 	/// The `new` function will panic if the size is zero.
 	pub fn new(size: usize) -> ThreadPool {
-		assert!(size > 0);
-
-		let (sender, receiver) = mpsc::channel();
+		Self::with_queue(size, DEFAULT_QUEUE_SIZE, Overflow::Block)
+	}
 
-		let receiver = Arc::new(Mutex::new(receiver));
+	/// Create a new ThreadPool with room for `queue_size` waiting jobs.
+	///
+	/// # Panics
+	///
+	/// Panics if the size or the queue size is zero.
+	pub fn with_queue(size: usize, queue_size: usize, overflow: Overflow) -> ThreadPool {
+		assert!(size > 0);
+		assert!(queue_size > 0);
 
-		let mut workers = Vec::with_capacity(size);
+		let shared = Arc::new(Shared {
+			queue: Mutex::new(Queue {
+				jobs: VecDeque::with_capacity(queue_size.min(DEFAULT_QUEUE_SIZE)),
+				closed: false,
+			}),
+			available: Condvar::new(),
+			space: Condvar::new(),
+			queue_size,
+			overflow,
+			threads: Mutex::new(Vec::with_capacity(size)),
+			active: AtomicUsize::new(0),
+			panicked: AtomicUsize::new(0),
+			rejected: AtomicUsize::new(0),
+			dropped: AtomicUsize::new(0),
+		});
 
 		for id in 0..size {
-			workers.push(Worker::new(id, Arc::clone(&receiver)));
+			let thread = Worker::spawn(id, Arc::clone(&shared));
+			lock(&shared.threads).push(Some(thread));
 		}
 
-		ThreadPool {
-			workers,
-			sender: Some(sender),
-		}
+		ThreadPool { size, shared }
 	}
 
-	pub fn execute<F>(&self, f: F)
+	/// Queue `f` to run on the next free worker.
+	///
+	/// Fails only with [`Overflow::Reject`] when the queue is full.
+	pub fn execute<F>(&self, f: F) -> Result<(), Rejected>
 	where
 		F: FnOnce() + Send + 'static,
 	{
-		let job = Box::new(f);
+		self.shared.push(Box::new(f), self.shared.overflow)
+	}
 
-		self.sender.as_ref().unwrap().send(job).unwrap();
+	/// # submit
+	/// Queue `f` like [`ThreadPool::execute`], returning a handle to wait for its result.
+	pub fn submit<F, T>(&self, f: F) -> Result<JobHandle<T>, Rejected>
+	where
+		F: FnOnce() -> T + Send + 'static,
+		T: Send + 'static,
+	{
+		let (sender, result) = mpsc::sync_channel(1);
+		self.execute(move || run_reporting(f, &sender, || ()))?;
+
+		Ok(JobHandle { result })
+	}
+
+	/// # scope
+	/// Run jobs that borrow from the caller's stack, like [`std::thread::scope`].
+	///
+	/// Returns once every job queued through the [`Scope`] has finished. Scoped jobs wait for
+	/// room in the queue whatever the [`Overflow`] policy says, so none get lost; calling this
+	/// from a job of the same pool can deadlock once all workers do it.
+	///
+	/// # Panics
+	///
+	/// Panics if a job panicked and its handle wasn't joined.
+	pub fn scope<'env, F, R>(&self, f: F) -> R
+	where
+		F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> R,
+	{
+		let scope = Scope {
+			shared: Arc::clone(&self.shared),
+			pending: Arc::new(Pending {
+				count: Mutex::new(0),
+				done: Condvar::new(),
+				panicked: AtomicUsize::new(0),
+			}),
+			scope: PhantomData,
+			env: PhantomData,
+		};
+
+		// The jobs may borrow what `f` returns into, so wait for them even if it panicked.
+		let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+
+		let mut count = lock(&scope.pending.count);
+		while *count > 0 {
+			count = scope
+				.pending
+				.done
+				.wait(count)
+				.unwrap_or_else(PoisonError::into_inner);
+		}
+		drop(count);
+
+		match result {
+			Err(payload) => panic::resume_unwind(payload),
+			Ok(_) if scope.pending.panicked.load(Ordering::Relaxed) > 0 => {
+				panic!("a scoped job panicked")
+			}
+			Ok(result) => result,
+		}
+	}
+
+	pub fn stats(&self) -> PoolStats {
+		let queued = lock(&self.shared.queue).jobs.len();
+		let active = self.shared.active.load(Ordering::Relaxed);
+
+		PoolStats {
+			workers: self.size,
+			active,
+			idle: self.size.saturating_sub(active),
+			queued,
+			queue_size: self.shared.queue_size,
+			panicked: self.shared.panicked.load(Ordering::Relaxed),
+			rejected: self.shared.rejected.load(Ordering::Relaxed),
+			dropped: self.shared.dropped.load(Ordering::Relaxed),
+		}
 	}
 }
 
 impl Drop for ThreadPool {
 	fn drop(&mut self) {
-		drop(self.sender.take());
+		lock(&self.shared.queue).closed = true;
+		self.shared.available.notify_all();
+		self.shared.space.notify_all();
 
-		for worker in &mut self.workers {
-			println!("Shutting down worker {}", worker.id);
+		for id in 0..self.size {
+			crate::debug!("Shutting down worker {id}");
 
-			if let Some(thread) = worker.thread.take() {
-				thread.join().unwrap();
+			// A worker replaced while we wait leaves its successor behind, join that one too.
+			loop {
+				let thread = lock(&self.shared.threads)[id].take();
+				match thread {
+					Some(thread) => {
+						let _ = thread.join();
+					}
+					None => break,
+				}
 			}
 		}
 	}
 }
 
-struct Worker {
-	id: usize,
-	thread: Option<thread::JoinHandle<()>>,
-}
+struct Worker;
 
 impl Worker {
-	fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
-		let thread = thread::spawn(move || loop {
-			let message = receiver.lock().unwrap().recv();
+	fn spawn(id: usize, shared: Arc<Shared>) -> thread::JoinHandle<()> {
+		thread::spawn(move || Self::run(id, shared))
+	}
+
+	fn run(id: usize, shared: Arc<Shared>) {
+		loop {
+			let job = {
+				let mut queue = lock(&shared.queue);
+				loop {
+					if let Some(job) = queue.jobs.pop_front() {
+						break job;
+					}
+					// Queued jobs still run after closing, so nothing gets lost on shutdown.
+					if queue.closed {
+						crate::debug!("Worker {id} disconnected; shutting down.");
+						return;
+					}
+					queue = shared
+						.available
+						.wait(queue)
+						.unwrap_or_else(PoisonError::into_inner);
+				}
+			};
+			shared.space.notify_one();
+
+			shared.active.fetch_add(1, Ordering::Relaxed);
+			let result = panic::catch_unwind(AssertUnwindSafe(job));
+			shared.active.fetch_sub(1, Ordering::Relaxed);
+
+			if result.is_err() {
+				shared.panicked.fetch_add(1, Ordering::Relaxed);
+				crate::error!("Worker {id} panicked, replacing it");
+
+				// A fresh thread rather than carrying on, so no thread-local state the job
+				// left half-done sticks around.
+				let successor = Self::spawn(id, Arc::clone(&shared));
+				lock(&shared.threads)[id] = Some(successor);
+				return;
+			}
+		}
+	}
+}
+
+/// # RequestLimits
+/// How much of a request [`HttpRequest::parse`] is willing to take.
+#[derive(Clone, Copy, Debug)]
+pub struct RequestLimits {
+	/// Longest request target, in bytes.
+	pub max_uri_len: usize,
+	/// Longest request head (request line and headers, along with any empty lines in front and
+	/// the one ending it), in bytes.
+	pub max_head_size: usize,
+	/// Most header fields.
+	pub max_headers: usize,
+	/// Largest body, in bytes. Chunked bodies count with their framing and trailer fields.
+	pub max_body_size: usize,
+}
+
+impl Default for RequestLimits {
+	fn default() -> Self {
+		Self {
+			max_uri_len: 8 * 1024,
+			max_head_size: 16 * 1024,
+			max_headers: 100,
+			max_body_size: 8 * 1024 * 1024,
+		}
+	}
+}
+
+/// # RequestError
+/// Why a request couldn't be parsed, see [`RequestError::status_code`] for what to answer with.
+#[derive(Debug)]
+pub enum RequestError {
+	/// The request stops before its head or body is complete.
+	Incomplete,
+	/// Reading the request failed.
+	Io(std::io::Error),
+	/// The request line isn't `METHOD target HTTP/x.y`.
+	MalformedRequestLine(String),
+	/// A header field that can't be parsed.
+	MalformedHeader(String),
+	/// A broken `Content-Length`, or one that contradicts another.
+	InvalidContentLength(String),
+	/// A broken chunk in a chunked body, or a size line or trailer field that is too long.
+	MalformedChunk,
+	/// `Content-Length` and `Transfer-Encoding` at the same time.
+	AmbiguousLength,
+	/// HTTP/1.1 requires exactly one `Host`.
+	MissingHost,
+	UriTooLong,
+	TooManyHeaders,
+	HeadTooLarge,
+	PayloadTooLarge,
+	/// An `Expect` other than `100-continue`.
+	ExpectationFailed(String),
+	/// A transfer coding other than `chunked`.
+	UnsupportedTransferEncoding(String),
+	UnsupportedVersion(String),
+}
+
+impl RequestError {
+	/// The status code to answer with.
+	pub fn status_code(&self) -> usize {
+		match self {
+			Self::Io(why)
+				if matches!(
+					why.kind(),
+					std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock
+				) =>
+			{
+				408
+			}
+			Self::Incomplete
+			| Self::Io(_)
+			| Self::MalformedRequestLine(_)
+			| Self::MalformedHeader(_)
+			| Self::InvalidContentLength(_)
+			| Self::MalformedChunk
+			| Self::AmbiguousLength
+			| Self::MissingHost => 400,
+			Self::UriTooLong => 414,
+			Self::TooManyHeaders | Self::HeadTooLarge => 431,
+			Self::PayloadTooLarge => 413,
+			Self::ExpectationFailed(_) => 417,
+			Self::UnsupportedTransferEncoding(_) => 501,
+			Self::UnsupportedVersion(_) => 505,
+		}
+	}
+}
+
+impl std::fmt::Display for RequestError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Incomplete => write!(f, "Request is incomplete"),
+			Self::Io(why) => write!(f, "Failed to read request: {why}"),
+			Self::MalformedRequestLine(line) => write!(f, "Malformed request line `{line}`"),
+			Self::MalformedHeader(line) => write!(f, "Malformed header `{line}`"),
+			Self::InvalidContentLength(value) => write!(f, "Invalid Content-Length `{value}`"),
+			Self::MalformedChunk => write!(f, "Malformed chunked body"),
+			Self::AmbiguousLength => write!(f, "Both Content-Length and Transfer-Encoding are set"),
+			Self::MissingHost => write!(f, "HTTP/1.1 requests need exactly one Host header"),
+			Self::UriTooLong => write!(f, "Request target is too long"),
+			Self::TooManyHeaders => write!(f, "Too many header fields"),
+			Self::HeadTooLarge => write!(f, "Request head is too large"),
+			Self::PayloadTooLarge => write!(f, "Request body is too large"),
+			Self::ExpectationFailed(value) => write!(f, "Unsupported expectation `{value}`"),
+			Self::UnsupportedTransferEncoding(value) => {
+				write!(f, "Unsupported transfer coding `{value}`")
+			}
+			Self::UnsupportedVersion(version) => write!(f, "Unsupported protocol `{version}`"),
+		}
+	}
+}
+
+impl std::error::Error for RequestError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			Self::Io(why) => Some(why),
+			_ => None,
+		}
+	}
+}
+
+impl From<std::io::Error> for RequestError {
+	fn from(why: std::io::Error) -> Self {
+		Self::Io(why)
+	}
+}
+
+#[derive(Debug)]
+pub struct HttpRequest {
+	pub method: HttpMethod,
+	pub uri: String,
+	pub protocol_ver: String,
+	pub headers: Vec<HttpHeader>,
+	/// The body with any chunked encoding removed, empty if there is none.
+	pub content: Vec<u8>,
+	/// Address of the client, filled in by whoever accepted the connection.
+	pub peer: Option<SocketAddr>,
+}
+
+/// How the body of a request is delimited.
+enum BodyLength {
+	Fixed(usize),
+	Chunked,
+}
+
+/// Longest chunk size line (extensions included) or trailer field in a chunked body.
+const MAX_CHUNK_LINE: usize = 4 * 1024;
+
+impl HttpRequest {
+	/// # parse
+	/// Parse a complete request out of `bytes`.
+	///
+	/// Returns [`RequestError::Incomplete`] if more bytes are needed, bytes past the end of the
+	/// request are ignored.
+	pub fn parse(bytes: &[u8], limits: &RequestLimits) -> Result<Self, RequestError> {
+		match RequestParser::new(limits).advance(bytes)? {
+			Some((request, _)) => Ok(request),
+			None => Err(RequestError::Incomplete),
+		}
+	}
+
+	/// # read_from
+	/// Read a request from `stream`, answering `Expect: 100-continue` once the head is in.
+	pub fn read_from<S>(stream: &mut S, limits: &RequestLimits) -> Result<Self, RequestError>
+	where
+		S: std::io::Read + std::io::Write,
+	{
+		let mut parser = RequestParser::new(limits);
+		let mut bytes = Vec::new();
+		let mut chunk = [0; 4096];
+		let mut continued = false;
+
+		loop {
+			if let Some((request, _)) = parser.advance(&bytes)? {
+				return Ok(request);
+			}
 
-			match message {
-				Ok(job) => {
-					//println!("Worker {id} got a job; executing.");
+			if !continued && parser.head().is_some_and(HttpRequest::expects_continue) {
+				stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+				stream.flush()?;
+				continued = true;
+			}
 
-					job();
+			let read = stream.read(&mut chunk)?;
+			if read == 0 {
+				return Err(match bytes.is_empty() {
+					true => RequestError::Io(std::io::ErrorKind::UnexpectedEof.into()),
+					false => RequestError::Incomplete,
+				});
+			}
+			bytes.extend_from_slice(&chunk[..read]);
+		}
+	}
+
+	/// The value of the header `name`, compared case-insensitively.
+	pub fn header(&self, name: &str) -> Option<&str> {
+		self.headers
+			.iter()
+			.find(|header| header.key.eq_ignore_ascii_case(name))
+			.map(|header| header.val.as_str())
+	}
+
+	/// Whether the client waits for `100 Continue` before sending the body.
+	pub fn expects_continue(&self) -> bool {
+		self.header("Expect")
+			.is_some_and(|value| value.eq_ignore_ascii_case("100-continue"))
+	}
+
+	/// Parse the request line and header fields in `head`, returning how the body is delimited.
+	fn parse_head(head: &[u8], limits: &RequestLimits) -> Result<(Self, BodyLength), RequestError> {
+		let head = String::from_utf8_lossy(head);
+		let mut lines = head
+			.split('\n')
+			.map(|line| line.strip_suffix('\r').unwrap_or(line));
+
+		let request_line = lines.next().unwrap_or_default();
+		let malformed = || RequestError::MalformedRequestLine(request_line.to_string());
+		let mut parts = request_line.split(' ');
+		let (Some(method), Some(uri), Some(protocol_ver), None) =
+			(parts.next(), parts.next(), parts.next(), parts.next())
+		else {
+			return Err(malformed());
+		};
+
+		if method.is_empty() || !method.bytes().all(is_token) {
+			return Err(malformed());
+		}
+		if uri.is_empty() || uri.bytes().any(|byte| byte.is_ascii_control()) {
+			return Err(malformed());
+		}
+		if uri.len() > limits.max_uri_len {
+			return Err(RequestError::UriTooLong);
+		}
+		match protocol_ver {
+			"HTTP/1.1" | "HTTP/1.0" => (),
+			version
+				if version.len() == 8
+					&& version.starts_with("HTTP/")
+					&& version.as_bytes()[5].is_ascii_digit()
+					&& version.as_bytes()[6] == b'.'
+					&& version.as_bytes()[7].is_ascii_digit() =>
+			{
+				return Err(RequestError::UnsupportedVersion(version.to_string()))
+			}
+			_ => return Err(malformed()),
+		}
+
+		let mut headers = Vec::new();
+		for line in lines {
+			if headers.len() == limits.max_headers {
+				return Err(RequestError::TooManyHeaders);
+			}
+
+			// No whitespace between name and colon, and no obsolete line folding (RFC 9112, 5).
+			let malformed = || RequestError::MalformedHeader(line.to_string());
+			let (key, val) = line.split_once(':').ok_or_else(malformed)?;
+			if key.is_empty() || !key.bytes().all(is_token) {
+				return Err(malformed());
+			}
+
+			headers.push(HttpHeader {
+				key: key.to_string(),
+				val: val.trim_matches([' ', '\t']).to_string(),
+			});
+		}
+
+		let request = Self {
+			method: HttpMethod::from(method),
+			uri: uri.to_string(),
+			protocol_ver: protocol_ver.to_string(),
+			headers,
+			content: Vec::new(),
+			peer: None,
+		};
+
+		let hosts = request
+			.headers
+			.iter()
+			.filter(|header| header.key.eq_ignore_ascii_case("Host"));
+		if request.protocol_ver == "HTTP/1.1" && hosts.count() != 1 {
+			return Err(RequestError::MissingHost);
+		}
+
+		if let Some(expect) = request.header("Expect") {
+			if !expect.eq_ignore_ascii_case("100-continue") {
+				return Err(RequestError::ExpectationFailed(expect.to_string()));
+			}
+		}
+
+		let length = request.body_length(limits)?;
+
+		Ok((request, length))
+	}
+
+	fn body_length(&self, limits: &RequestLimits) -> Result<BodyLength, RequestError> {
+		let lengths: Vec<&str> = self
+			.headers
+			.iter()
+			.filter(|header| header.key.eq_ignore_ascii_case("Content-Length"))
+			.flat_map(|header| header.val.split(','))
+			.map(str::trim)
+			.collect();
+
+		if let Some(encoding) = self.header("Transfer-Encoding") {
+			// Letting one of them win is how request smuggling happens.
+			if !lengths.is_empty() {
+				return Err(RequestError::AmbiguousLength);
+			}
+			return match encoding.trim().eq_ignore_ascii_case("chunked") {
+				true => Ok(BodyLength::Chunked),
+				false => Err(RequestError::UnsupportedTransferEncoding(
+					encoding.to_string(),
+				)),
+			};
+		}
+
+		let Some(first) = lengths.first() else {
+			return Ok(BodyLength::Fixed(0));
+		};
+		if lengths.iter().any(|length| length != first) {
+			return Err(RequestError::InvalidContentLength(lengths.join(", ")));
+		}
+		if first.is_empty() || !first.bytes().all(|byte| byte.is_ascii_digit()) {
+			return Err(RequestError::InvalidContentLength(first.to_string()));
+		}
+
+		match first.parse::<usize>() {
+			Ok(length) if length <= limits.max_body_size => Ok(BodyLength::Fixed(length)),
+			_ => Err(RequestError::PayloadTooLarge),
+		}
+	}
+}
+
+impl TryFrom<&[u8]> for HttpRequest {
+	type Error = RequestError;
+
+	/// Parse a complete request with the default [`RequestLimits`].
+	fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+		Self::parse(bytes, &RequestLimits::default())
+	}
+}
+
+/// Characters allowed in methods and header names (RFC 9110, section 5.6.2).
+fn is_token(byte: u8) -> bool {
+	byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)
+}
+
+/// # RequestParser
+/// Parses a request as its bytes come in.
+///
+/// [`RequestParser::advance`] is handed everything received so far and carries on where the last
+/// call stopped, so a request sent a few bytes at a time isn't scanned over and over. All of it
+/// is bounded by the [`RequestLimits`]: the head including any empty lines in front of it, and
+/// chunked bodies including their framing and trailers.
+struct RequestParser<'a> {
+	limits: &'a RequestLimits,
+	state: ParseState,
+}
+
+enum ParseState {
+	/// Looking for the end of the head. `start` is where the request line starts once the empty
+	/// lines in front of it are skipped, `from` where to continue looking.
+	Head { start: Option<usize>, from: usize },
+	/// Waiting for the rest of a body with a `Content-Length`.
+	Fixed {
+		request: HttpRequest,
+		start: usize,
+		length: usize,
+	},
+	Chunked {
+		request: HttpRequest,
+		chunks: Chunks,
+	},
+}
+
+/// Progress through a chunked body.
+struct Chunks {
+	/// Where the body starts in the request.
+	start: usize,
+	/// Where decoding continues.
+	offset: usize,
+	state: ChunkState,
+	body: Vec<u8>,
+}
+
+#[derive(Clone, Copy)]
+enum ChunkState {
+	Size,
+	/// Bytes of the current chunk still to come.
+	Data(usize),
+	/// The line break after a chunk's data.
+	DataEnd,
+	Trailer,
+}
+
+impl<'a> RequestParser<'a> {
+	fn new(limits: &'a RequestLimits) -> Self {
+		Self {
+			limits,
+			state: ParseState::Head {
+				start: None,
+				from: 0,
+			},
+		}
+	}
+
+	/// The request, once its head is parsed.
+	fn head(&self) -> Option<&HttpRequest> {
+		match &self.state {
+			ParseState::Head { .. } => None,
+			ParseState::Fixed { request, .. } | ParseState::Chunked { request, .. } => {
+				Some(request)
+			}
+		}
+	}
+
+	/// # advance
+	/// Parse on, `bytes` is everything received so far.
+	///
+	/// Returns the request along with where it ends in `bytes` once it's complete, `None` while
+	/// more bytes are needed. A request following it needs a parser of its own.
+	fn advance(&mut self, bytes: &[u8]) -> Result<Option<(HttpRequest, usize)>, RequestError> {
+		let limits = self.limits;
+
+		if let ParseState::Head { start, from } = &mut self.state {
+			// Empty lines ahead of the request line are allowed (RFC 9112, section 2.2), they
+			// count towards the head all the same.
+			let request_start = match start {
+				Some(request_start) => *request_start,
+				None => match bytes[*from..]
+					.iter()
+					.position(|byte| !b"\r\n".contains(byte))
+				{
+					Some(position) => *start.insert(*from + position),
+					None if bytes.len() > limits.max_head_size => {
+						return Err(RequestError::HeadTooLarge)
+					}
+					None => {
+						*from = bytes.len();
+						return Ok(None);
+					}
+				},
+			};
+
+			let Some((end, body_start)) =
+				head_end(bytes, request_start, (*from).max(request_start))
+			else {
+				if bytes.len() > limits.max_head_size {
+					return Err(RequestError::HeadTooLarge);
+				}
+				*from = bytes.len();
+				return Ok(None);
+			};
+			if body_start > limits.max_head_size {
+				return Err(RequestError::HeadTooLarge);
+			}
+
+			let (request, length) = HttpRequest::parse_head(&bytes[request_start..end], limits)?;
+			self.state = match length {
+				BodyLength::Fixed(length) => ParseState::Fixed {
+					request,
+					start: body_start,
+					length,
+				},
+				BodyLength::Chunked => ParseState::Chunked {
+					request,
+					chunks: Chunks {
+						start: body_start,
+						offset: body_start,
+						state: ChunkState::Size,
+						body: Vec::new(),
+					},
+				},
+			};
+		}
+
+		let end = match &mut self.state {
+			ParseState::Head { .. } => unreachable!("the head is parsed above"),
+			ParseState::Fixed { start, length, .. } if bytes.len() - *start < *length => {
+				return Ok(None)
+			}
+			ParseState::Fixed { start, length, .. } => *start + *length,
+			ParseState::Chunked { chunks, .. } => match chunks.advance(bytes, limits)? {
+				Some(end) => end,
+				None => return Ok(None),
+			},
+		};
+
+		let state = std::mem::replace(
+			&mut self.state,
+			ParseState::Head {
+				start: None,
+				from: 0,
+			},
+		);
+		let request = match state {
+			ParseState::Head { .. } => unreachable!("the head is parsed above"),
+			ParseState::Fixed {
+				mut request,
+				start,
+				length,
+			} => {
+				request.content = bytes[start..start + length].to_vec();
+				request
+			}
+			ParseState::Chunked {
+				mut request,
+				chunks,
+			} => {
+				request.content = chunks.body;
+				request
+			}
+		};
+
+		Ok(Some((request, end)))
+	}
+}
+
+/// Find the empty line that ends a head starting at `start`, looking at line breaks from `from`
+/// on. Returns where the head ends (without its last line break) and where the body starts.
+///
+/// Lines end in CRLF, a bare LF is tolerated.
+fn head_end(bytes: &[u8], start: usize, from: usize) -> Option<(usize, usize)> {
+	(from..bytes.len())
+		.filter(|i| bytes[*i] == b'\n' && *i > start)
+		.find_map(
+			|i| match (bytes[i - 1], i.checked_sub(2).map(|j| bytes[j])) {
+				(b'\n', _) => Some((i - 1, i + 1)),
+				(b'\r', Some(b'\n')) if i - 2 >= start => Some((i - 2, i + 1)),
+				_ => None,
+			},
+		)
+}
+
+impl Chunks {
+	/// Decode on, returning where the body ends once it's complete.
+	///
+	/// Everything from the first size line to the end of the trailers counts towards the body
+	/// size limit, size lines and trailer fields may not be longer than [`MAX_CHUNK_LINE`].
+	fn advance(
+		&mut self,
+		bytes: &[u8],
+		limits: &RequestLimits,
+	) -> Result<Option<usize>, RequestError> {
+		loop {
+			if self.offset - self.start > limits.max_body_size {
+				return Err(RequestError::PayloadTooLarge);
+			}
+
+			match self.state {
+				ChunkState::Size => {
+					let Some((line, next)) = chunk_line(&bytes[self.offset..])? else {
+						return Ok(None);
+					};
+					let line = String::from_utf8_lossy(line);
+					// Chunk extensions are allowed and ignored.
+					let size = line.split(';').next().unwrap_or_default().trim();
+					let size = usize::from_str_radix(size, 16)
+						.map_err(|_| RequestError::MalformedChunk)?;
+					self.offset += next;
+
+					if self.offset - self.start > limits.max_body_size.saturating_sub(size) {
+						return Err(RequestError::PayloadTooLarge);
+					}
+					self.state = match size {
+						0 => ChunkState::Trailer,
+						size => ChunkState::Data(size),
+					};
+				}
+				ChunkState::Data(left) => {
+					let available = (bytes.len() - self.offset).min(left);
+					self.body
+						.extend_from_slice(&bytes[self.offset..self.offset + available]);
+					self.offset += available;
+
+					if available < left {
+						self.state = ChunkState::Data(left - available);
+						return Ok(None);
+					}
+					self.state = ChunkState::DataEnd;
 				}
-				Err(_) => {
-					println!("Worker {id} disconnected; shutting down.");
-					break;
+				ChunkState::DataEnd => {
+					let Some((line, next)) = chunk_line(&bytes[self.offset..])? else {
+						return Ok(None);
+					};
+					if !line.is_empty() {
+						return Err(RequestError::MalformedChunk);
+					}
+					self.offset += next;
+					self.state = ChunkState::Size;
+				}
+				// Trailer fields up to an empty line, they are dropped.
+				ChunkState::Trailer => {
+					let Some((line, next)) = chunk_line(&bytes[self.offset..])? else {
+						return Ok(None);
+					};
+					self.offset += next;
+					if line.is_empty() {
+						return Ok(Some(self.offset));
+					}
 				}
 			}
-		});
+		}
+	}
+}
+
+/// The line at the start of `bytes` without its line break, and where the next one starts.
+///
+/// `None` if the line isn't complete yet. Lines longer than [`MAX_CHUNK_LINE`] are refused
+/// without waiting for their end.
+fn chunk_line(bytes: &[u8]) -> Result<Option<(&[u8], usize)>, RequestError> {
+	let window = &bytes[..bytes.len().min(MAX_CHUNK_LINE + 2)];
+	match window.iter().position(|byte| *byte == b'\n') {
+		Some(end) => {
+			let line = &bytes[..end];
+			let line = line.strip_suffix(b"\r").unwrap_or(line);
+			match line.len() > MAX_CHUNK_LINE {
+				true => Err(RequestError::MalformedChunk),
+				false => Ok(Some((line, end + 1))),
+			}
+		}
+		None if window.len() > MAX_CHUNK_LINE + 1 => Err(RequestError::MalformedChunk),
+		None => Ok(None),
+	}
+}
+
+/// # Body
+/// What a [`HttpResponse`] sends after its head.
+pub enum Body {
+	Bytes(Vec<u8>),
+	/// Read and sent as it comes, for files too big to keep around.
+	///
+	/// Without a known `length` HTTP/1.1 responses use chunked encoding, HTTP/1.0 ones end
+	/// with the connection.
+	Stream {
+		reader: Box<dyn std::io::Read + Send>,
+		length: Option<u64>,
+	},
+}
+
+impl Body {
+	/// The length of the body, if known up front.
+	pub fn len(&self) -> Option<u64> {
+		match self {
+			Self::Bytes(bytes) => Some(bytes.len() as u64),
+			Self::Stream { length, .. } => *length,
+		}
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len() == Some(0)
+	}
+}
+
+impl Default for Body {
+	fn default() -> Self {
+		Self::Bytes(Vec::new())
+	}
+}
+
+impl std::fmt::Debug for Body {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Bytes(bytes) => write!(f, "Bytes({} bytes)", bytes.len()),
+			Self::Stream { length, .. } => write!(f, "Stream({length:?})"),
+		}
+	}
+}
+
+impl From<Vec<u8>> for Body {
+	fn from(bytes: Vec<u8>) -> Self {
+		Self::Bytes(bytes)
+	}
+}
+
+impl From<String> for Body {
+	fn from(text: String) -> Self {
+		Self::Bytes(text.into_bytes())
+	}
+}
+
+impl From<&str> for Body {
+	fn from(text: &str) -> Self {
+		Self::Bytes(text.as_bytes().to_vec())
+	}
+}
+
+#[derive(Debug)]
+pub struct HttpResponse {
+	pub protocol_ver: String,
+	pub status_code: usize,
+	/// Reason phrase, [`status_text_from_code`] is used if unset.
+	pub status_text: Option<String>,
+	pub headers: Vec<HttpHeader>,
+	pub content: Body,
+}
+
+impl Default for HttpResponse {
+	fn default() -> Self {
+		Self {
+			protocol_ver: String::from("HTTP/1.1"),
+			status_code: 200,
+			status_text: None,
+			headers: Vec::new(),
+			content: Body::default(),
+		}
+	}
+}
+
+impl HttpResponse {
+	/// # builder
+	/// Start building a response with `status_code`.
+	///
+	/// ```
+	/// use mdbutler::HttpResponse;
+	///
+	/// let response = HttpResponse::builder(200)
+	///     .content_type("text/plain")
+	///     .body("Hello")
+	///     .build();
+	/// ```
+	pub fn builder(status_code: usize) -> HttpResponseBuilder {
+		HttpResponseBuilder {
+			response: Self {
+				status_code,
+				..Default::default()
+			},
+		}
+	}
+
+	/// The value of the header `name`, compared case-insensitively.
+	pub fn header(&self, name: &str) -> Option<&str> {
+		self.headers
+			.iter()
+			.find(|header| header.key.eq_ignore_ascii_case(name))
+			.map(|header| header.val.as_str())
+	}
+
+	/// Replace every header called `name` with a single one.
+	///
+	/// Line breaks are removed from `val`, a header with an invalid name isn't added at all.
+	pub fn set_header(&mut self, name: &str, val: impl Into<String>) {
+		self.headers
+			.retain(|header| !header.key.eq_ignore_ascii_case(name));
+		self.add_header(name.to_string(), val.into());
+	}
+
+	/// Add a header, keeping any others with the same name. See [`HttpResponse::set_header`].
+	pub fn add_header(&mut self, name: String, val: String) {
+		if !HttpHeader::valid_name(&name) {
+			crate::warn!(
+				"Dropping response header with invalid name `{}`",
+				name.escape_debug()
+			);
+			return;
+		}
+
+		let val = match HttpHeader::clean_value(&val) {
+			std::borrow::Cow::Borrowed(_) => val,
+			std::borrow::Cow::Owned(cleaned) => cleaned,
+		};
+		self.headers.push(HttpHeader { key: name, val });
+	}
+
+	/// Whether the status code allows a body at all (RFC 9110, section 6.4.1).
+	fn allows_body(&self) -> bool {
+		!matches!(self.status_code, 100..=199 | 204 | 304)
+	}
+
+	/// Whether the body goes out in chunks, because its length isn't known up front.
+	fn is_chunked(&self) -> bool {
+		self.allows_body()
+			&& self.content.len().is_none()
+			&& self.protocol_ver == "HTTP/1.1"
+			&& self.header("Content-Length").is_none()
+	}
+
+	/// # write_head_to
+	/// Write the status line and headers, like for an answer to `HEAD`.
+	///
+	/// `Content-Length` is added from the body unless it's set already. [`HttpResponse::headers`]
+	/// can be changed directly, so headers are checked here again: invalid names are left out and
+	/// line breaks removed from values.
+	pub fn write_head_to(&self, out: &mut impl std::io::Write) -> std::io::Result<()> {
+		let status_text = match &self.status_text {
+			Some(status_text) => HttpHeader::clean_value(status_text),
+			None => status_text(self.status_code).into(),
+		};
+		let mut head = format!(
+			"{} {} {}\r\n",
+			HttpHeader::clean_value(&self.protocol_ver),
+			self.status_code,
+			status_text
+		);
+
+		for header in &self.headers {
+			if HttpHeader::valid_name(&header.key) {
+				let val = HttpHeader::clean_value(&header.val);
+				head.push_str(&format!("{}: {val}\r\n", header.key));
+			}
+		}
+
+		if self.is_chunked() {
+			head.push_str("Transfer-Encoding: chunked\r\n");
+		} else if self.allows_body() && self.header("Content-Length").is_none() {
+			if let Some(length) = self.content.len() {
+				head.push_str(&format!("Content-Length: {length}\r\n"));
+			}
+		}
+		head.push_str("\r\n");
+
+		out.write_all(head.as_bytes())
+	}
+
+	/// # write_to
+	/// Write the whole response, returning how many body bytes were sent.
+	///
+	/// A streamed body is consumed on the way.
+	pub fn write_to(&mut self, out: &mut impl std::io::Write) -> std::io::Result<u64> {
+		self.write_head_to(out)?;
+
+		if !self.allows_body() {
+			return out.flush().map(|_| 0);
+		}
+
+		let chunked = self.is_chunked();
+		let written = match &mut self.content {
+			Body::Bytes(bytes) => {
+				out.write_all(bytes)?;
+				bytes.len() as u64
+			}
+			Body::Stream { reader, .. } if chunked => {
+				let mut chunk = vec![0; 16 * 1024];
+				let mut written = 0;
+				loop {
+					let read = reader.read(&mut chunk)?;
+					if read == 0 {
+						break;
+					}
+					write!(out, "{read:x}\r\n")?;
+					out.write_all(&chunk[..read])?;
+					out.write_all(b"\r\n")?;
+					written += read as u64;
+				}
+				out.write_all(b"0\r\n\r\n")?;
+				written
+			}
+			Body::Stream { reader, .. } => std::io::copy(reader, out)?,
+		};
+		out.flush()?;
+
+		Ok(written)
+	}
+}
+
+/// # HttpResponseBuilder
+/// Builds a [`HttpResponse`], see [`HttpResponse::builder`].
+#[derive(Debug)]
+pub struct HttpResponseBuilder {
+	response: HttpResponse,
+}
+
+impl HttpResponseBuilder {
+	pub fn status(mut self, status_code: usize) -> Self {
+		self.response.status_code = status_code;
+		self
+	}
+
+	/// Use a reason phrase other than the standard one.
+	pub fn status_text(mut self, status_text: impl Into<String>) -> Self {
+		self.response.status_text = Some(status_text.into());
+		self
+	}
+
+	pub fn protocol(mut self, protocol_ver: impl Into<String>) -> Self {
+		self.response.protocol_ver = protocol_ver.into();
+		self
+	}
 
-		Worker {
-			id,
-			thread: Some(thread),
-		}
+	/// Add a header, keeping any others with the same name. See [`HttpResponse::add_header`].
+	pub fn header(mut self, key: impl Into<String>, val: impl Into<String>) -> Self {
+		self.response.add_header(key.into(), val.into());
+		self
 	}
-}
 
-#[derive(Debug)]
-pub struct HttpRequest {
-	pub method: HttpMethod,
-	pub uri: String,
-	pub protocol_ver: String,
-	pub headers: Vec<HttpHeader>,
-	pub content: Option<String>,
-}
+	pub fn content_type(mut self, mime: &str) -> Self {
+		self.response.set_header("Content-Type", mime);
+		self
+	}
 
-impl HttpRequest {
-	pub fn from(request: String) -> Self {
-		let (method_str, request) = request.split_once(' ').unwrap();
-		let method = match method_str {
-			"GET" => HttpMethod::Get,
-			"POST" => HttpMethod::Post,
-			"BREW" => HttpMethod::Brew,
-			_ => HttpMethod::Unknown,
-		};
+	pub fn location(mut self, url: &str) -> Self {
+		self.response.set_header("Location", url);
+		self
+	}
+
+	pub fn allow(mut self, methods: &[HttpMethod]) -> Self {
+		let methods: Vec<&str> = methods.iter().map(HttpMethod::as_str).collect();
+		self.response.set_header("Allow", methods.join(", "));
+		self
+	}
 
-		let (uri, request) = request.split_once(' ').unwrap();
-		let uri = uri.to_string();
+	pub fn cache_control(mut self, directives: &str) -> Self {
+		self.response.set_header("Cache-Control", directives);
+		self
+	}
 
-		let (protocol_ver, request) = request.split_once("\r\n").unwrap();
-		let protocol_ver = protocol_ver.to_string();
-		//println!("{request}");
+	/// Set an `ETag`, `tag` is quoted here.
+	pub fn etag(mut self, tag: &str, weak: bool) -> Self {
+		let prefix = if weak { "W/" } else { "" };
+		self.response
+			.set_header("ETag", format!("{prefix}\"{tag}\""));
+		self
+	}
 
-		let mut headers = Vec::new();
-		let mut request = request;
-		while !request.starts_with("\r\n") {
-			let (key, val);
-			(key, request) = request.split_once(": ").unwrap();
-			(val, request) = request.split_once("\r\n").unwrap();
+	pub fn last_modified(mut self, time: std::time::SystemTime) -> Self {
+		self.response.set_header("Last-Modified", http_date(time));
+		self
+	}
 
-			headers.push(HttpHeader {
-				key: key.to_string(),
-				val: val.to_string(),
-			})
-		}
+	pub fn retry_after(mut self, seconds: u64) -> Self {
+		self.response.set_header("Retry-After", seconds.to_string());
+		self
+	}
 
-		request = &request[2..];
-		//dbg!(request);
+	pub fn body(mut self, body: impl Into<Body>) -> Self {
+		self.response.content = body.into();
+		self
+	}
 
-		let content = if !request.starts_with('\0') {
-			Some(String::from(request))
-		} else {
-			None
+	/// Stream the body from `reader`, `length` becomes the `Content-Length` if known.
+	pub fn stream(
+		mut self,
+		reader: impl std::io::Read + Send + 'static,
+		length: Option<u64>,
+	) -> Self {
+		self.response.content = Body::Stream {
+			reader: Box::new(reader),
+			length,
 		};
+		self
+	}
 
-		Self {
-			method,
-			uri,
-			protocol_ver,
-			headers,
-			content,
-		}
+	pub fn build(self) -> HttpResponse {
+		self.response
 	}
 }
 
-#[derive(Default)]
-pub struct HttpResponse {
-	pub protocol_ver: String,
-	pub status_code: usize,
-	pub status_text: Option<String>,
-	pub headers: Vec<HttpHeader>,
-	pub content: String,
+/// Format `time` the way HTTP headers want it (RFC 9110, section 5.6.7).
+pub fn http_date(time: std::time::SystemTime) -> String {
+	chrono::DateTime::<chrono::Utc>::from(time)
+		.format("%a, %d %b %Y %H:%M:%S GMT")
+		.to_string()
 }
 
-impl std::fmt::Display for HttpResponse {
-	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-		let mut headers = String::new();
-		for header in &self.headers {
-			headers.push_str(&format!("{}: {}\r\n", header.key, header.val));
-		}
-
-		if let Some(status_text) = &self.status_text {
-			write!(
-				f,
-				"{} {} {}\r\n{}\r\n{}",
-				self.protocol_ver, self.status_code, status_text, headers, self.content
-			)
-		} else {
-			write!(
-				f,
-				"{} {} {}\r\n{}\r\n{}",
-				self.protocol_ver,
-				self.status_code,
-				status_text_from_code(self.status_code),
-				headers,
-				self.content
-			)
-		}
-	}
+pub fn status_text_from_code(status_code: usize) -> String {
+	status_text(status_code).to_string()
 }
 
-pub fn status_text_from_code(status_code: usize) -> String {
+/// Like [`status_text_from_code`], but without the allocation.
+pub fn status_text(status_code: usize) -> &'static str {
 	match status_code {
 		// 1XX: INFORMATIONAL
 		100 => "CONTINUE",
@@ -266,29 +1525,845 @@ pub fn status_text_from_code(status_code: usize) -> String {
 		// FALLBACK
 		_ => "NON-STANDARD ERROR",
 	}
-	.to_string()
 }
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+/// Request methods from RFC 9110 and 5789, plus RFC 2324's `BREW`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum HttpMethod {
 	Get,
+	Head,
 	Post,
+	Put,
+	Delete,
+	Connect,
+	Options,
+	Trace,
+	Patch,
 	Brew,
 	Unknown,
 }
 
-#[derive(Debug)]
+impl HttpMethod {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Self::Get => "GET",
+			Self::Head => "HEAD",
+			Self::Post => "POST",
+			Self::Put => "PUT",
+			Self::Delete => "DELETE",
+			Self::Connect => "CONNECT",
+			Self::Options => "OPTIONS",
+			Self::Trace => "TRACE",
+			Self::Patch => "PATCH",
+			Self::Brew => "BREW",
+			Self::Unknown => "UNKNOWN",
+		}
+	}
+}
+
+/// Methods are case-sensitive, `get` is [`HttpMethod::Unknown`].
+impl From<&str> for HttpMethod {
+	fn from(method: &str) -> Self {
+		match method {
+			"GET" => Self::Get,
+			"HEAD" => Self::Head,
+			"POST" => Self::Post,
+			"PUT" => Self::Put,
+			"DELETE" => Self::Delete,
+			"CONNECT" => Self::Connect,
+			"OPTIONS" => Self::Options,
+			"TRACE" => Self::Trace,
+			"PATCH" => Self::Patch,
+			"BREW" => Self::Brew,
+			_ => Self::Unknown,
+		}
+	}
+}
+
+impl std::fmt::Display for HttpMethod {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str(self.as_str())
+	}
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct HttpHeader {
 	pub key: String,
 	pub val: String,
 }
 
-pub fn log<T: std::fmt::Debug>(log: T) {
-	let now = chrono::Local::now().to_rfc3339();
-	println!("[{now}]: {log:#?}");
+impl HttpHeader {
+	/// Whether `name` can be sent as a header name, it has to be a token (RFC 9110, section 5.1).
+	pub fn valid_name(name: &str) -> bool {
+		!name.is_empty() && name.bytes().all(is_token)
+	}
+
+	/// `value` without the characters that would end the header line early (CR, LF and NUL).
+	///
+	/// Values often carry something from the request, a line break in there would let the
+	/// client add headers of its own to the response.
+	pub fn clean_value(value: &str) -> std::borrow::Cow<'_, str> {
+		match value.contains(['\r', '\n', '\0']) {
+			true => value.replace(['\r', '\n', '\0'], "").into(),
+			false => value.into(),
+		}
+	}
 }
 
-pub fn log_with_context<T: std::fmt::Debug, U: std::fmt::Debug>(err: T, ctx: U) {
+/// # Level
+/// How important a log line is, from [`Level::Error`] down to [`Level::Trace`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+	Error,
+	Warn,
+	Info,
+	Debug,
+	Trace,
+}
+
+impl Level {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Self::Error => "ERROR",
+			Self::Warn => "WARN",
+			Self::Info => "INFO",
+			Self::Debug => "DEBUG",
+			Self::Trace => "TRACE",
+		}
+	}
+}
+
+impl std::fmt::Display for Level {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.pad(self.as_str())
+	}
+}
+
+/// # LogFormat
+/// How log lines are written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+	/// `[time] LEVEL target: message`, for people.
+	#[default]
+	Human,
+	/// One JSON object per line, for log collectors.
+	Json,
+}
+
+impl std::str::FromStr for LogFormat {
+	type Err = String;
+
+	fn from_str(format: &str) -> Result<Self, Self::Err> {
+		match format {
+			"human" => Ok(Self::Human),
+			"json" => Ok(Self::Json),
+			_ => Err(format!("unknown log format `{format}`")),
+		}
+	}
+}
+
+/// # LogFilter
+/// Which log lines to write, parsed from a `RUST_LOG` style list of directives.
+///
+/// `info,mdbutler::serve=debug,mdbutler::serve::cache=off` writes `info` and up in general,
+/// `debug` and up below `mdbutler::serve` and nothing from the cache. A bare target enables
+/// everything below it; without a bare level only errors are written for other targets.
+#[derive(Clone, Debug)]
+pub struct LogFilter {
+	/// `None` is `off`.
+	default: Option<Level>,
+	directives: Vec<(String, Option<Level>)>,
+}
+
+impl LogFilter {
+	pub fn parse(spec: &str) -> Result<Self, String> {
+		let mut filter = Self {
+			default: Some(Level::Error),
+			directives: Vec::new(),
+		};
+
+		for directive in spec
+			.split(',')
+			.map(str::trim)
+			.filter(|directive| !directive.is_empty())
+		{
+			match directive.split_once('=') {
+				Some((target, level)) => filter
+					.directives
+					.push((target.trim().to_string(), parse_level(level.trim())?)),
+				None => match parse_level(directive) {
+					Ok(level) => filter.default = level,
+					// Not a level, so it's a target to log everything from.
+					Err(_) => filter
+						.directives
+						.push((directive.to_string(), Some(Level::Trace))),
+				},
+			}
+		}
+
+		// Longest targets first, the first match wins.
+		filter
+			.directives
+			.sort_by_key(|(target, _)| std::cmp::Reverse(target.len()));
+
+		Ok(filter)
+	}
+
+	/// Whether a line at `level` from `target` (a module path) gets written.
+	pub fn enabled(&self, level: Level, target: &str) -> bool {
+		let max = self
+			.directives
+			.iter()
+			.find(|(prefix, _)| {
+				target
+					.strip_prefix(prefix.as_str())
+					.is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+			})
+			.map_or(self.default, |(_, level)| *level);
+
+		max.is_some_and(|max| level <= max)
+	}
+}
+
+impl Default for LogFilter {
+	fn default() -> Self {
+		Self {
+			default: Some(Level::Info),
+			directives: Vec::new(),
+		}
+	}
+}
+
+fn parse_level(level: &str) -> Result<Option<Level>, String> {
+	match level.to_lowercase().as_str() {
+		"off" => Ok(None),
+		"error" => Ok(Some(Level::Error)),
+		"warn" => Ok(Some(Level::Warn)),
+		"info" => Ok(Some(Level::Info)),
+		"debug" => Ok(Some(Level::Debug)),
+		"trace" => Ok(Some(Level::Trace)),
+		_ => Err(format!("unknown log level `{level}`")),
+	}
+}
+
+struct Logger {
+	filter: LogFilter,
+	format: LogFormat,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+	/// Request handled on this thread, see [`RequestScope`].
+	static REQUEST_ID: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+/// # init_logger
+/// Set up logging, only the first call has any effect.
+///
+/// Until then everything at `info` and up is written in the human format.
+pub fn init_logger(filter: LogFilter, format: LogFormat) {
+	let _ = LOGGER.set(Logger { filter, format });
+}
+
+fn logger() -> &'static Logger {
+	LOGGER.get_or_init(|| Logger {
+		filter: LogFilter::default(),
+		format: LogFormat::default(),
+	})
+}
+
+/// # log
+/// Write a log line for `target`, if the filter lets it through. Use the [`error!`],
+/// [`warn!`], [`info!`], [`debug!`] and [`trace!`] macros rather than calling this directly.
+pub fn log(level: Level, target: &str, message: std::fmt::Arguments) {
+	let logger = logger();
+	if !logger.filter.enabled(level, target) {
+		return;
+	}
+
 	let now = chrono::Local::now().to_rfc3339();
-	println!("[{now}]: {err:#?} | {ctx:#?}");
+	let request = request_id();
+	let line = match logger.format {
+		LogFormat::Human => match request {
+			Some(id) => format!("[{now}] {level:<5} {target} req={id}: {message}"),
+			None => format!("[{now}] {level:<5} {target}: {message}"),
+		},
+		LogFormat::Json => {
+			let mut line = format!(
+				r#"{{"time":"{now}","level":"{}","target":"{}""#,
+				level.as_str().to_lowercase(),
+				json_escape(target)
+			);
+			if let Some(id) = request {
+				line.push_str(&format!(r#","request_id":{id}"#));
+			}
+			line.push_str(&format!(
+				r#","message":"{}"}}"#,
+				json_escape(&message.to_string())
+			));
+			line
+		}
+	};
+
+	// One write per line, so lines from different threads don't interleave.
+	let _ = writeln!(std::io::stdout().lock(), "{line}");
+}
+
+fn json_escape(text: &str) -> String {
+	let mut escaped = String::with_capacity(text.len());
+	for c in text.chars() {
+		match c {
+			'"' => escaped.push_str("\\\""),
+			'\\' => escaped.push_str("\\\\"),
+			'\n' => escaped.push_str("\\n"),
+			'\r' => escaped.push_str("\\r"),
+			'\t' => escaped.push_str("\\t"),
+			c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+			c => escaped.push(c),
+		}
+	}
+	escaped
+}
+
+/// # RequestScope
+/// Tags every log line written on this thread with a new request ID, until dropped.
+pub struct RequestScope {
+	id: u64,
+	previous: Option<u64>,
+}
+
+impl RequestScope {
+	pub fn enter() -> Self {
+		let id = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+		let previous = REQUEST_ID.with(|current| current.replace(Some(id)));
+
+		Self { id, previous }
+	}
+
+	pub fn id(&self) -> u64 {
+		self.id
+	}
+}
+
+impl Drop for RequestScope {
+	fn drop(&mut self) {
+		REQUEST_ID.with(|current| current.set(self.previous));
+	}
+}
+
+/// ID of the request handled on this thread, if any.
+pub fn request_id() -> Option<u64> {
+	REQUEST_ID.with(Cell::get)
+}
+
+#[macro_export]
+macro_rules! error {
+	($($arg:tt)+) => {
+		$crate::log($crate::Level::Error, module_path!(), format_args!($($arg)+))
+	};
+}
+
+#[macro_export]
+macro_rules! warn {
+	($($arg:tt)+) => {
+		$crate::log($crate::Level::Warn, module_path!(), format_args!($($arg)+))
+	};
+}
+
+#[macro_export]
+macro_rules! info {
+	($($arg:tt)+) => {
+		$crate::log($crate::Level::Info, module_path!(), format_args!($($arg)+))
+	};
+}
+
+#[macro_export]
+macro_rules! debug {
+	($($arg:tt)+) => {
+		$crate::log($crate::Level::Debug, module_path!(), format_args!($($arg)+))
+	};
+}
+
+#[macro_export]
+macro_rules! trace {
+	($($arg:tt)+) => {
+		$crate::log($crate::Level::Trace, module_path!(), format_args!($($arg)+))
+	};
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::time::Duration;
+
+	#[test]
+	fn scope_joins_all_jobs() {
+		let pool = ThreadPool::new(4);
+		let mut results = vec![0; 16];
+
+		pool.scope(|scope| {
+			for (i, result) in results.iter_mut().enumerate() {
+				scope.execute(move || {
+					thread::sleep(Duration::from_millis(5));
+					*result = i * 2;
+				});
+			}
+		});
+
+		assert_eq!(results, (0..16).map(|i| i * 2).collect::<Vec<_>>());
+	}
+
+	#[test]
+	fn scope_returns_joined_results() {
+		let pool = ThreadPool::new(2);
+		let words = [String::from("a"), String::from("bb"), String::from("ccc")];
+
+		let lengths = pool.scope(|scope| {
+			let handles: Vec<_> = words
+				.iter()
+				.map(|word| scope.execute(move || word.len()))
+				.collect();
+			handles
+				.into_iter()
+				.map(|handle| handle.join().unwrap())
+				.collect::<Vec<_>>()
+		});
+
+		assert_eq!(lengths, [1, 2, 3]);
+	}
+
+	#[test]
+	fn scope_waits_for_jobs_when_one_panics() {
+		let pool = ThreadPool::new(2);
+		let finished = AtomicUsize::new(0);
+
+		let result = panic::catch_unwind(AssertUnwindSafe(|| {
+			pool.scope(|scope| {
+				scope.execute(|| panic!("boom"));
+				for _ in 0..4 {
+					scope.execute(|| {
+						thread::sleep(Duration::from_millis(20));
+						finished.fetch_add(1, Ordering::SeqCst);
+					});
+				}
+			})
+		}));
+
+		assert!(result.is_err());
+		assert_eq!(finished.load(Ordering::SeqCst), 4);
+	}
+
+	#[test]
+	fn scope_survives_joined_panics() {
+		let pool = ThreadPool::new(1);
+
+		let result = pool.scope(|scope| scope.execute(|| panic!("boom")).join());
+
+		assert!(matches!(result, Err(JobError::Panicked(message)) if message == "boom"));
+	}
+
+	/// Keep the only worker of `pool` busy until the returned sender is dropped.
+	fn occupy(pool: &ThreadPool) -> mpsc::Sender<()> {
+		let (release, wait) = mpsc::channel::<()>();
+		pool.execute(move || {
+			let _ = wait.recv();
+		})
+		.unwrap();
+
+		while pool.stats().active == 0 {
+			thread::sleep(Duration::from_millis(1));
+		}
+		release
+	}
+
+	#[test]
+	fn reject_refuses_jobs_once_the_queue_is_full() {
+		let pool = ThreadPool::with_queue(1, 1, Overflow::Reject);
+		let ran = Arc::new(Mutex::new(Vec::new()));
+		let release = occupy(&pool);
+
+		let job = |i| {
+			let ran = Arc::clone(&ran);
+			move || ran.lock().unwrap().push(i)
+		};
+		assert!(pool.execute(job(1)).is_ok());
+		assert!(pool.execute(job(2)).is_err());
+
+		let stats = pool.stats();
+		assert_eq!((stats.workers, stats.active, stats.idle), (1, 1, 0));
+		assert_eq!((stats.queued, stats.queue_size), (1, 1));
+		assert_eq!((stats.rejected, stats.dropped, stats.panicked), (1, 0, 0));
+
+		drop(release);
+		drop(pool);
+		assert_eq!(*ran.lock().unwrap(), [1]);
+	}
+
+	#[test]
+	fn drop_oldest_makes_room() {
+		let pool = ThreadPool::with_queue(1, 2, Overflow::DropOldest);
+		let release = occupy(&pool);
+
+		let handles: Vec<_> = (1..=3).map(|i| pool.submit(move || i).unwrap()).collect();
+
+		let stats = pool.stats();
+		assert_eq!((stats.queued, stats.dropped, stats.rejected), (2, 1, 0));
+
+		drop(release);
+		let results: Vec<_> = handles.into_iter().map(JobHandle::join).collect();
+		assert!(matches!(results[0], Err(JobError::Cancelled)));
+		assert!(matches!(results[1..], [Ok(2), Ok(3)]));
+	}
+
+	#[test]
+	fn block_waits_for_room() {
+		let pool = Arc::new(ThreadPool::with_queue(1, 1, Overflow::Block));
+		let release = occupy(&pool);
+		pool.execute(|| ()).unwrap();
+
+		let queued = Arc::new(AtomicUsize::new(0));
+		let blocked = {
+			let (pool, queued) = (Arc::clone(&pool), Arc::clone(&queued));
+			thread::spawn(move || {
+				pool.execute(|| ()).unwrap();
+				queued.fetch_add(1, Ordering::SeqCst);
+			})
+		};
+
+		thread::sleep(Duration::from_millis(50));
+		assert_eq!(queued.load(Ordering::SeqCst), 0);
+
+		drop(release);
+		blocked.join().unwrap();
+		assert_eq!(queued.load(Ordering::SeqCst), 1);
+		assert_eq!(pool.stats().rejected, 0);
+	}
+
+	#[test]
+	fn workers_are_replaced_after_a_panic() {
+		let pool = ThreadPool::new(1);
+
+		let result = pool.submit(|| panic!("boom")).unwrap().join();
+		assert!(matches!(result, Err(JobError::Panicked(message)) if message == "boom"));
+
+		assert_eq!(pool.submit(|| 42).unwrap().join().unwrap(), 42);
+		let stats = pool.stats();
+		assert_eq!((stats.workers, stats.panicked), (1, 1));
+	}
+
+	fn limits() -> RequestLimits {
+		RequestLimits {
+			max_uri_len: 32,
+			max_head_size: 128,
+			max_headers: 4,
+			max_body_size: 64,
+		}
+	}
+
+	fn parse(bytes: &[u8]) -> Result<HttpRequest, RequestError> {
+		HttpRequest::parse(bytes, &limits())
+	}
+
+	/// A client sending its request a byte at a time, keeping what it is sent.
+	struct Trickle {
+		input: Vec<u8>,
+		read: usize,
+		output: Vec<u8>,
+	}
+
+	impl std::io::Read for Trickle {
+		fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+			match self.input.get(self.read) {
+				Some(byte) if !buf.is_empty() => {
+					buf[0] = *byte;
+					self.read += 1;
+					Ok(1)
+				}
+				_ => Ok(0),
+			}
+		}
+	}
+
+	impl Write for Trickle {
+		fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+			self.output.extend_from_slice(buf);
+			Ok(buf.len())
+		}
+
+		fn flush(&mut self) -> std::io::Result<()> {
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn parses_requests() {
+		let request =
+			parse(b"\r\nPOST /form HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nhello").unwrap();
+		assert_eq!(request.method, HttpMethod::Post);
+		assert_eq!(request.uri, "/form");
+		assert_eq!(request.header("host"), Some("a"));
+		assert_eq!(request.content, b"hello");
+
+		// Bare line feeds are tolerated.
+		let request = parse(b"GET / HTTP/1.0\n\n").unwrap();
+		assert_eq!(request.protocol_ver, "HTTP/1.0");
+
+		assert!(matches!(
+			parse(b"GET / HTTP/1.1\r\nHost: a\r\n"),
+			Err(RequestError::Incomplete)
+		));
+		assert!(matches!(
+			parse(b"GET / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nhel"),
+			Err(RequestError::Incomplete)
+		));
+		assert!(matches!(
+			parse(b"GET / HTTP/1.1\r\n\r\n"),
+			Err(RequestError::MissingHost)
+		));
+		assert!(matches!(
+			parse(b"GET / HTTP/2.0\r\n\r\n"),
+			Err(RequestError::UnsupportedVersion(_))
+		));
+	}
+
+	#[test]
+	fn limits_are_enforced() {
+		let long_uri = format!("GET /{} HTTP/1.1\r\nHost: a\r\n\r\n", "a".repeat(32));
+		assert!(matches!(
+			parse(long_uri.as_bytes()),
+			Err(RequestError::UriTooLong)
+		));
+
+		let headers = "GET / HTTP/1.1\r\nHost: a\r\nA: 1\r\nB: 2\r\nC: 3\r\nD: 4\r\n\r\n";
+		assert!(matches!(
+			parse(headers.as_bytes()),
+			Err(RequestError::TooManyHeaders)
+		));
+
+		let large = format!(
+			"GET / HTTP/1.1\r\nHost: a\r\nA: {}\r\n\r\n",
+			"a".repeat(100)
+		);
+		assert!(matches!(
+			parse(large.as_bytes()),
+			Err(RequestError::HeadTooLarge)
+		));
+		// Too large is noticed before the head is complete.
+		assert!(matches!(
+			parse(&large.as_bytes()[..large.len() - 2]),
+			Err(RequestError::HeadTooLarge)
+		));
+
+		// Empty lines in front count towards the head.
+		let padded = format!("{}GET / HTTP/1.1\r\nHost: a\r\n\r\n", "\r\n".repeat(60));
+		assert!(matches!(
+			parse(padded.as_bytes()),
+			Err(RequestError::HeadTooLarge)
+		));
+		assert!(matches!(
+			parse("\r\n".repeat(100).as_bytes()),
+			Err(RequestError::HeadTooLarge)
+		));
+
+		let body = format!(
+			"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 65\r\n\r\n{}",
+			"a".repeat(65)
+		);
+		assert!(matches!(
+			parse(body.as_bytes()),
+			Err(RequestError::PayloadTooLarge)
+		));
+	}
+
+	#[test]
+	fn decodes_chunked_bodies() {
+		let request = b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n\
+			4\r\nWiki\r\n5;name=value\r\npedia\r\n0\r\nX-Trailer: 1\r\n\r\n";
+		assert_eq!(parse(request).unwrap().content, b"Wikipedia");
+
+		// Every prefix is incomplete, not broken.
+		for end in 0..request.len() {
+			assert!(
+				matches!(parse(&request[..end]), Err(RequestError::Incomplete)),
+				"prefix of {end} bytes"
+			);
+		}
+
+		let chunked = |body: &str| {
+			parse(
+				format!("POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n{body}")
+					.as_bytes(),
+			)
+		};
+		assert!(matches!(
+			chunked("4\r\nWikiX\r\n0\r\n\r\n"),
+			Err(RequestError::MalformedChunk)
+		));
+		assert!(matches!(
+			chunked("z\r\n"),
+			Err(RequestError::MalformedChunk)
+		));
+		assert!(matches!(
+			chunked("41\r\n"),
+			Err(RequestError::PayloadTooLarge)
+		));
+
+		// Size lines and trailers are bounded, on their own and all together.
+		let extension = format!("1;{}", "a".repeat(MAX_CHUNK_LINE));
+		assert!(matches!(
+			chunked(&extension),
+			Err(RequestError::MalformedChunk)
+		));
+		let trailers = format!("0\r\n{}", "X-T: 1\r\n".repeat(10));
+		assert!(matches!(
+			chunked(&trailers),
+			Err(RequestError::PayloadTooLarge)
+		));
+		let chunks = format!("{}0\r\n\r\n", "1\r\na\r\n".repeat(20));
+		assert!(matches!(
+			chunked(&chunks),
+			Err(RequestError::PayloadTooLarge)
+		));
+	}
+
+	#[test]
+	fn rejects_bad_content_lengths() {
+		let with_lengths = |lengths: &[&str]| {
+			let headers: String = lengths
+				.iter()
+				.map(|length| format!("Content-Length: {length}\r\n"))
+				.collect();
+			parse(format!("POST / HTTP/1.1\r\nHost: a\r\n{headers}\r\nhello").as_bytes())
+		};
+
+		assert_eq!(with_lengths(&["5", "5"]).unwrap().content, b"hello");
+		assert_eq!(with_lengths(&["5, 5"]).unwrap().content, b"hello");
+
+		for lengths in [
+			&["abc"][..],
+			&["-5"],
+			&["+5"],
+			&["0x5"],
+			&[""],
+			&["5", "4"],
+			&["5, 4"],
+		] {
+			assert!(
+				matches!(
+					with_lengths(lengths),
+					Err(RequestError::InvalidContentLength(_))
+				),
+				"{lengths:?}"
+			);
+		}
+		assert!(matches!(
+			with_lengths(&["99999999999999999999999"]),
+			Err(RequestError::PayloadTooLarge)
+		));
+	}
+
+	#[test]
+	fn refuses_transfer_encoding_with_content_length() {
+		let request = b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 4\r\n\
+			Transfer-Encoding: chunked\r\n\r\n0\r\n\r\n";
+		assert!(matches!(parse(request), Err(RequestError::AmbiguousLength)));
+
+		let request = b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: gzip\r\n\r\n";
+		assert!(matches!(
+			parse(request),
+			Err(RequestError::UnsupportedTransferEncoding(_))
+		));
+	}
+
+	#[test]
+	fn pipelined_requests_stay_apart() {
+		let bytes = b"POST /a HTTP/1.1\r\nHost: a\r\nContent-Length: 3\r\n\r\none\
+			POST /b HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n3\r\ntwo\r\n0\r\n\r\n\
+			GET /c HTTP/1.1\r\nHost: a\r\n\r\n";
+
+		let mut requests = Vec::new();
+		let mut start = 0;
+		while start < bytes.len() {
+			let (request, end) = RequestParser::new(&limits())
+				.advance(&bytes[start..])
+				.unwrap()
+				.unwrap();
+			requests.push((request.uri, request.content));
+			start += end;
+		}
+
+		assert_eq!(
+			requests,
+			[
+				(String::from("/a"), b"one".to_vec()),
+				(String::from("/b"), b"two".to_vec()),
+				(String::from("/c"), Vec::new()),
+			]
+		);
+	}
+
+	#[test]
+	fn read_from_takes_requests_a_byte_at_a_time() {
+		let mut client = Trickle {
+			input: b"POST / HTTP/1.1\r\nHost: a\r\nExpect: 100-continue\r\n\
+				Transfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n"
+				.to_vec(),
+			read: 0,
+			output: Vec::new(),
+		};
+
+		let request = HttpRequest::read_from(&mut client, &limits()).unwrap();
+		assert_eq!(request.content, b"hello");
+		assert_eq!(client.output, b"HTTP/1.1 100 Continue\r\n\r\n");
+
+		let mut client = Trickle {
+			input: "\r\n".repeat(100).into_bytes(),
+			read: 0,
+			output: Vec::new(),
+		};
+		assert!(matches!(
+			HttpRequest::read_from(&mut client, &limits()),
+			Err(RequestError::HeadTooLarge)
+		));
+		// Stopped reading once over the limit.
+		assert_eq!(client.read, 129);
+	}
+
+	#[test]
+	fn response_headers_cannot_be_injected() {
+		let mut response = HttpResponse::builder(302)
+			.location("/news/x\r\nSet-Cookie: pwn=1")
+			.header("X-Bad\r\nSet-Cookie", "pwn=1")
+			.header("Content-Disposition", "attachment; filename=\"a\0\nb\"")
+			.build();
+		response.headers.push(HttpHeader {
+			key: String::from("X-Raw"),
+			val: String::from("a\r\nSet-Cookie: pwn=1"),
+		});
+		response.headers.push(HttpHeader {
+			key: String::from("Set-Cookie: pwn=1\r\nX"),
+			val: String::from("1"),
+		});
+		response.status_text = Some(String::from("Found\r\nSet-Cookie: pwn=1"));
+
+		assert_eq!(
+			response.header("Location"),
+			Some("/news/xSet-Cookie: pwn=1")
+		);
+		assert_eq!(response.headers.len(), 4);
+
+		let mut head = Vec::new();
+		response.write_head_to(&mut head).unwrap();
+		let head = String::from_utf8(head).unwrap();
+		assert!(
+			!head.lines().any(|line| line.starts_with("Set-Cookie")),
+			"{head}"
+		);
+		assert_eq!(head.matches("\r\n").count(), head.matches('\n').count());
+		assert!(head.contains("X-Raw: aSet-Cookie: pwn=1\r\n"));
+		assert!(head.contains("filename=\"ab\"\r\n"));
+	}
 }
@@ -2,13 +2,14 @@ use std::fs;
 
 use clap::{Args, Parser, Subcommand};
 
-use mdbutler::{log, log_with_context};
+use mdbutler::{debug, error, info};
 
-#[cfg(feature = "build")]
 use std::path::PathBuf;
 
-#[cfg(feature = "serve")]
-use std::sync::Mutex;
+mod config;
+
+#[cfg(any(feature = "serve", feature = "markdown", feature = "sass"))]
+mod error;
 
 #[cfg(feature = "serve")]
 mod serve;
@@ -23,31 +24,12 @@ use convert::markdown;
 use convert::sass;
 
 #[cfg(feature = "serve")]
-use lazy_static::lazy_static;
-
-#[cfg(feature = "serve")]
-const BIND_ADDRESS: &str = "0.0.0.0";
-#[cfg(feature = "serve")]
-const PORT: usize = 8080;
-//const THREADS: usize = 4;
-const PRETTY_PRINT_DEFAULT: bool = false;
+use mdbutler::{HttpMethod, HttpRequest, HttpResponse};
 
 #[cfg(feature = "serve")]
-lazy_static! {
-	static ref WORKING_DIR: Mutex<String> = Mutex::new(String::from("/var/www/html"));
-}
-
-// Our server vars
-#[cfg(feature = "serve")]
-pub const ASSET_PATH: &str = concatcp!("{WORKING_DIR}/assets");
-#[cfg(feature = "serve")]
-pub const CSS_PATH: &str = concatcp!("{ASSET_PATH}/css");
-
+use chrono::Local;
 #[cfg(feature = "serve")]
-use {
-	const_format::concatcp,
-	snowboard::{headers, response, Method, Request, Response, Server},
-};
+use std::time::Instant;
 
 #[cfg(any(feature = "serve", feature = "markdown"))]
 use html_node::{
@@ -58,10 +40,14 @@ use html_node::{
 #[derive(Debug, Parser)]
 struct Cli {
 	#[arg(long, short)]
+	/// Site root, `mdbutler.toml` is looked up in here
 	directory: Option<String>,
-	#[arg(short = 'f', long, default_value_t = true)]
+	#[arg(long, short)]
+	/// Configuration file to use instead of `<directory>/mdbutler.toml`
+	config: Option<PathBuf>,
+	#[arg(short = 'f', long)]
 	/// Whether to format output
-	format: bool,
+	format: Option<bool>,
 	#[cfg(feature = "markdown")]
 	#[arg(short, long, default_value_t = true)]
 	/// Convert markdown to HTML
@@ -81,6 +67,9 @@ enum Commands {
 	Build(BuildArgs),
 	#[cfg(feature = "serve")]
 	Serve(ServeArgs),
+	/// Show which redirect/rewrite rule a URL would hit, without serving anything
+	#[cfg(feature = "serve")]
+	Rules(RulesArgs),
 }
 
 #[derive(Args, Debug)]
@@ -93,14 +82,55 @@ struct BuildArgs {
 	output_dir: Option<String>,
 }
 
+#[cfg(feature = "serve")]
 #[derive(Args, Debug)]
 struct ServeArgs {
-	#[arg(short, long, default_value_t = String::from("0.0.0.0"))]
+	#[arg(short, long)]
 	/// Address to bind to
-	address: String,
-	#[arg(short, long, default_value_t = 8080)]
+	address: Option<String>,
+	#[arg(short, long)]
 	/// Port to use
-	port: usize,
+	port: Option<u16>,
+	#[arg(short, long)]
+	/// Amount of worker threads
+	threads: Option<usize>,
+	#[arg(long)]
+	/// Watch for changes and live reload open pages
+	dev: bool,
+	#[arg(long, value_enum, default_value_t = DEFAULT_BACKEND)]
+	/// HTTP server implementation to run
+	backend: Backend,
+}
+
+#[cfg(all(
+	feature = "serve",
+	not(any(feature = "snowboard", feature = "native-server"))
+))]
+compile_error!("The `serve` feature needs a backend, enable `snowboard` or `native-server`.");
+
+#[cfg(feature = "serve")]
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+enum Backend {
+	/// One thread per connection, named after the crate it was first built on
+	#[cfg(feature = "snowboard")]
+	Snowboard,
+	/// Our own server on a fixed pool of worker threads
+	#[cfg(feature = "native-server")]
+	Native,
+}
+
+#[cfg(feature = "snowboard")]
+const DEFAULT_BACKEND: Backend = Backend::Snowboard;
+#[cfg(all(feature = "native-server", not(feature = "snowboard")))]
+const DEFAULT_BACKEND: Backend = Backend::Native;
+
+#[derive(Args, Debug)]
+struct RulesArgs {
+	/// URL to test, e.g. `/wiki/old-page?x=1`
+	url: String,
+	#[arg(long)]
+	/// Host to pick the site by
+	host: Option<String>,
 }
 
 fn main() -> std::result::Result<(), std::io::Error> {
@@ -114,19 +144,48 @@ Either enable the features you want or remove the `--no-default-features` flag."
 
 	let cli = Cli::parse();
 
-	dbg!(&cli);
+	let mut config = config::Config::load(cli.directory.as_deref(), cli.config.as_deref())
+		.map_err(|why| std::io::Error::new(std::io::ErrorKind::InvalidInput, why))?;
+	if let Some(pretty) = cli.format {
+		config.pretty = pretty;
+	}
+
+	let log_filter = mdbutler::LogFilter::parse(&config.log.filter).map_err(|why| {
+		std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Invalid log filter: {why}"))
+	})?;
+	mdbutler::init_logger(log_filter, config.log.format);
+	debug!("{cli:?}");
 
 	match cli.command {
 		#[cfg(feature = "build")]
-		Commands::Build(args) => build(cli.directory, args.output_dir, args.threads)?,
+		Commands::Build(args) => {
+			let threads = args.threads.or(config.threads);
+			config::set(config);
+			build(cli.directory, args.output_dir, threads)?
+		}
 		#[cfg(feature = "serve")]
 		Commands::Serve(args) => {
-			if let Some(dir) = cli.directory {
-				let mut lock = WORKING_DIR.lock().unwrap();
-				*lock = dir;
-				std::mem::drop(lock);
+			if let Some(address) = args.address {
+				config.address = address;
 			}
-			serve(&args.address, args.port)?;
+			if let Some(port) = args.port {
+				config.port = port;
+			}
+			if let Some(threads) = args.threads {
+				config.threads = Some(threads);
+			}
+			if args.dev {
+				config.dev = true;
+			}
+			let (address, port) = (config.address.clone(), config.port);
+			config::set(config);
+
+			serve(&address, port, args.backend)?;
+		}
+		#[cfg(feature = "serve")]
+		Commands::Rules(args) => {
+			config::set(config);
+			rules_dry_run(&args)?;
 		}
 	}
 
@@ -144,153 +203,410 @@ fn build(
 	} else {
 		std::env::current_dir()?
 	};
+	let Some(output_dir) = output_dir else {
+		return Err(std::io::Error::new(
+			std::io::ErrorKind::InvalidInput,
+			"No output directory, pass one with `--output-dir`",
+		));
+	};
 
-	fs::read_dir(source_dir)?
-		.filter_map(|entry| entry.ok())
-		.map(|entry| entry.path())
-		.for_each(|path| {
-			if path.is_dir() {
-				// Recurse into
-
-				// Ignore the black hole
-				if path.file_name().unwrap().as_encoded_bytes() != b"node_modules" {
-					let out_dir = if let Some(dir) = &output_dir {
-						Some(
-							dir.to_string()
-								+ &("/".to_string() + path.file_name().unwrap().to_str().unwrap()),
-						)
-					} else {
-						None
-					};
-					build(Some(path.display().to_string()), out_dir, threads).unwrap();
-				}
-			} else if path.is_file() {
-				if let Some(ext) = path.extension() {
-					match ext.as_encoded_bytes() {
-						#[cfg(feature = "markdown")]
-						b"md" | b"markdown" => {
-							println!("{:?}", path)
-						}
-						#[cfg(feature = "sass")]
-						b"scss" | b"sass" => {
-							match sass::convert_to_file(
-								&path,
-								PathBuf::from(
-									output_dir.as_ref().unwrap().to_string()
-										+ &("/".to_string()
-											+ &(path
-												.file_stem()
-												.unwrap()
-												.to_str()
-												.unwrap()
-												.to_string() + ".css")),
-								),
-							) {
-								Ok(_) => (),
-								Err(why) => panic!("Could not compile sass/scss: {why}"),
-							}
-						}
-						//b"ts" => { println!("{:?}", path) },
-						_ => {}
-					}
-				}
+	let mut jobs = Vec::new();
+	collect_build_jobs(&source_dir, &PathBuf::from(output_dir), &mut jobs)?;
+
+	let threads = threads
+		.filter(|threads| *threads > 0)
+		.unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |cpus| cpus.get()));
+	let pool = mdbutler::ThreadPool::new(threads);
+
+	// Every file is independent, render them all at once and report failures at the end.
+	let failed = pool.scope(|scope| {
+		let handles: Vec<_> = jobs
+			.iter()
+			.map(|job| (job, scope.execute(move || job.run())))
+			.collect();
+
+		let mut failed = 0;
+		for (job, handle) in handles {
+			let why = match handle.join() {
+				Ok(Ok(())) => continue,
+				Ok(Err(why)) => why,
+				Err(why) => why.to_string(),
+			};
+			error!("Could not build `{}`: {why}", job.source.display());
+			failed += 1;
+		}
+		failed
+	});
+
+	match failed {
+		0 => Ok(()),
+		failed => Err(std::io::Error::other(format!(
+			"{failed} of {} files failed to build",
+			jobs.len()
+		))),
+	}
+}
+
+/// A single file for [`build`] to convert.
+#[cfg(feature = "build")]
+struct BuildJob {
+	source: PathBuf,
+	output: PathBuf,
+	kind: BuildKind,
+}
+
+#[cfg(feature = "build")]
+enum BuildKind {
+	#[cfg(feature = "markdown")]
+	Markdown,
+	#[cfg(feature = "sass")]
+	Sass,
+}
+
+#[cfg(feature = "build")]
+impl BuildJob {
+	/// Errors become strings so they can cross over from the worker thread.
+	fn run(&self) -> Result<(), String> {
+		let result = match self.kind {
+			#[cfg(feature = "markdown")]
+			BuildKind::Markdown => markdown::convert_to_file(&self.source, self.output.clone()),
+			#[cfg(feature = "sass")]
+			BuildKind::Sass => sass::convert_to_file(&self.source, self.output.clone()),
+		};
+
+		result.map_err(|why| why.to_string())
+	}
+}
+
+/// Walk `source_dir`, queueing every file we know how to convert to the same place below
+/// `output_dir`.
+#[cfg(feature = "build")]
+fn collect_build_jobs(
+	source_dir: &std::path::Path,
+	output_dir: &std::path::Path,
+	jobs: &mut Vec<BuildJob>,
+) -> std::io::Result<()> {
+	for entry in fs::read_dir(source_dir)? {
+		let path = entry?.path();
+		let Some(name) = path.file_name() else {
+			continue;
+		};
+
+		if path.is_dir() {
+			// Ignore the black hole
+			if name != "node_modules" {
+				collect_build_jobs(&path, &output_dir.join(name), jobs)?;
 			}
+			continue;
+		}
+
+		let kind = match path.extension().map(|ext| ext.as_encoded_bytes()) {
+			#[cfg(feature = "markdown")]
+			Some(b"md" | b"markdown") => BuildKind::Markdown,
+			#[cfg(feature = "sass")]
+			Some(b"scss" | b"sass") => BuildKind::Sass,
+			_ => continue,
+		};
+		let extension = match kind {
+			#[cfg(feature = "markdown")]
+			BuildKind::Markdown => "html",
+			#[cfg(feature = "sass")]
+			BuildKind::Sass => "css",
+		};
+
+		jobs.push(BuildJob {
+			output: output_dir.join(name).with_extension(extension),
+			source: path,
+			kind,
 		});
+	}
 
-	//source_dir.read_dir().into_iter().for_each(|item| {
-	//	dbg!(&item);
+	Ok(())
+}
 
-	//	if item.is_file() {
-	//		// Convert
-	//	} else if item.is_dir() {
-	//		// Recurse into
-	//	}
-	//})
+#[cfg(feature = "serve")]
+fn rules_dry_run(args: &RulesArgs) -> Result<(), std::io::Error> {
+	let site = config::get().site_for_host(args.host.as_deref());
+	serve::rules::validate(&site)
+		.map_err(|why| std::io::Error::new(std::io::ErrorKind::InvalidData, why))?;
+
+	let path = serve::path::normalize(&args.url)
+		.map_err(|why| std::io::Error::new(std::io::ErrorKind::InvalidInput, why))?;
+	match serve::rules::evaluate(&site, &path, serve::path::query(&args.url)) {
+		Some((rule, action)) => {
+			println!("Matched {rule}");
+			match action {
+				serve::rules::Action::Redirect(status, location) => {
+					println!("{status} {} -> {location}", mdbutler::status_text(status.into()))
+				}
+				serve::rules::Action::Rewrite(url) => println!("Rewrite -> {url}"),
+				serve::rules::Action::Gone => println!("410 Gone"),
+			}
+		}
+		None => println!("No rule matched, `{}` is served as-is", args.url),
+	}
 
 	Ok(())
 }
 
 #[cfg(feature = "serve")]
-fn serve(bind_address: &str, port: usize) -> snowboard::Result {
-	log("Starting web server!");
+fn serve(bind_address: &str, port: u16, backend: Backend) -> std::io::Result<()> {
+	info!("Starting web server!");
+
+	// Refuse to start with broken rules rather than finding out on the first request.
+	let sites = {
+		let config = config::get();
+		let mut sites = vec![config.default_site()];
+		sites.extend(config.vhosts.iter().map(|vhost| vhost.site(&config)));
+		sites
+	};
+	for site in &sites {
+		serve::rules::validate(site)
+			.map_err(|why| std::io::Error::new(std::io::ErrorKind::InvalidData, why))?;
+	}
+	serve::proxy::validate(&config::get().proxies)
+		.map_err(|why| std::io::Error::new(std::io::ErrorKind::InvalidData, why))?;
+	serve::methods::validate(&config::get().method_policies)
+		.map_err(|why| std::io::Error::new(std::io::ErrorKind::InvalidData, why))?;
+
+	let config = config::get();
+	serve::access_log::init(&config.access_log)?;
+	serve::shutdown::install(std::time::Duration::from_secs(config.shutdown_grace))?;
+
+	if config.dev {
+		let mut roots = vec![config.root.clone()];
+		roots.extend(config.vhosts.iter().map(|vhost| vhost.root.clone()));
+		roots.sort();
+		roots.dedup();
+
+		info!("Development mode, watching for changes");
+		serve::livereload::watch(roots);
+	}
 
-	let server = Server::new(format!("{}:{}", bind_address, port))?;
+	// With TLS enabled the HTTPS listener gets its own thread and the plain one redirects to it
+	// (unless configured otherwise).
+	let plain_handler: serve::Handler = match &config.tls {
+		#[cfg(feature = "tls")]
+		Some(tls) => {
+			let tls_config = serve::tls::server_config(tls)
+				.map_err(|why| std::io::Error::new(std::io::ErrorKind::InvalidData, why))?;
+			let listener = std::net::TcpListener::bind(format!("{}:{}", bind_address, tls.port))?;
+			serve::shutdown::register(&listener)?;
+			info!("Listening for HTTPS on {}", listener.local_addr()?);
+
+			if tls.http == config::PlainHttp::Off {
+				serve::tls::run(listener, tls_config, handle_logged);
+				serve::shutdown::wait();
+			}
+			std::thread::spawn(move || serve::tls::run(listener, tls_config, handle_logged));
 
-	log(format!("Listening on {}", server.pretty_addr()?));
+			match tls.http {
+				config::PlainHttp::Redirect => |request| log_access(request, redirect_to_https),
+				_ => handle_logged,
+			}
+		}
+		#[cfg(not(feature = "tls"))]
+		Some(_) => {
+			mdbutler::warn!("`[tls]` is configured but mdbutler was compiled without the `tls` feature, serving plain HTTP only.");
+			handle_logged
+		}
+		None => handle_logged,
+	};
+
+	match backend {
+		#[cfg(feature = "snowboard")]
+		Backend::Snowboard => {
+			let listener = std::net::TcpListener::bind(format!("{}:{}", bind_address, port))?;
+
+			serve::shutdown::register(&listener)?;
+
+			info!("Listening on {}", listener.local_addr()?);
+
+			serve::snowboard::run(listener, plain_handler);
+			serve::shutdown::wait()
+		}
+		#[cfg(feature = "native-server")]
+		Backend::Native => {
+			let listener = std::net::TcpListener::bind(format!("{}:{}", bind_address, port))?;
+
+			serve::shutdown::register(&listener)?;
+
+			info!("Listening on {} (native backend)", listener.local_addr()?);
 
-	server.run(handle_connection)
+			serve::native::run(listener, config.threads, &config.pool, plain_handler);
+			serve::shutdown::wait()
+		}
+	}
+}
+
+/// Send plain HTTP requests to the same URL over HTTPS.
+#[cfg(feature = "tls")]
+fn redirect_to_https(request: HttpRequest) -> HttpResponse {
+	let config = config::get();
+	let port = config.tls.as_ref().map_or(443, |tls| tls.port);
+
+	let host = request.header("Host").unwrap_or(&config.address);
+	// Strip the port, minding IPv6 literals like `[::1]:8080`.
+	let host = match host.rsplit_once(':') {
+		Some((name, port)) if !port.contains(']') => name,
+		_ => host,
+	};
+	let location = match port {
+		443 => format!("https://{host}{}", request.uri),
+		port => format!("https://{host}:{port}{}", request.uri),
+	};
+
+	// 308 keeps the method and body, browsers handle 301 better for plain page loads.
+	let status = match request.method {
+		HttpMethod::Get | HttpMethod::Head => 301,
+		_ => 308,
+	};
+
+	HttpResponse::builder(status).location(&location).build()
 }
 
+/// Run [`handle_connection`] and write the result to the access log.
 #[cfg(feature = "serve")]
-fn handle_connection(request: Request) -> snowboard::Response {
-	//dbg!(&request);
-	//println!("{:#?}", request);
-	
-	// Get the string data out of the mutex
-	let data = WORKING_DIR.lock().unwrap();
-	let working_dir = data.clone();
-	// Now drop the original mutex because we no longer need it.
-	std::mem::drop(data);
-
-	let pretty = if request.headers.contains_key("Pretty") {
-		match request.headers.get("Pretty") {
-			Some(val) => match val.to_lowercase().as_str() {
-				"true" => true,
-				"false" => false,
-				_ => PRETTY_PRINT_DEFAULT,
-			},
-			None => PRETTY_PRINT_DEFAULT,
-		}
-	} else {
-		PRETTY_PRINT_DEFAULT
+fn handle_logged(request: HttpRequest) -> HttpResponse {
+	log_access(request, handle_connection)
+}
+
+/// Run `handler` and write the result to the access log, if there is one.
+///
+/// The request counts as in flight meanwhile, once shutdown started it's turned away instead.
+/// It gets an ID that tags every log line written while handling it and is sent back as
+/// `X-Request-Id`.
+#[cfg(feature = "serve")]
+fn log_access(request: HttpRequest, handler: serve::Handler) -> HttpResponse {
+	let _in_flight = serve::shutdown::track();
+	let handler = match serve::shutdown::requested() {
+		true => shutting_down,
+		false => handler,
 	};
 
-	// Requests to `api.`
-	if let Some(host) = request.headers.get("Host") {
-		if host.starts_with("api.") {
-			return response! {
-				payment_required,
-				r#"{"Status": "Payment required"}"#,
-				headers! {"Content-Type" => "text/json"}
-			};
+	let scope = mdbutler::RequestScope::enter();
+	match request.peer {
+		Some(peer) => debug!("{} {} from {peer}", request.method, request.uri),
+		None => debug!("{} {}", request.method, request.uri),
+	}
+
+	let mut response = match serve::access_log::enabled() {
+		true => handle_recorded(request, handler),
+		false => handler(request),
+	};
+	response.set_header("X-Request-Id", scope.id().to_string());
+
+	response
+}
+
+/// Run `handler`, timing it for the access log. The line is written once the response was sent.
+#[cfg(feature = "serve")]
+fn handle_recorded(request: HttpRequest, handler: serve::Handler) -> HttpResponse {
+	let (start, time) = (Instant::now(), Local::now());
+	let (ip, method, url, protocol, headers) = (
+		request.peer.map(|peer| peer.ip()),
+		request.method,
+		request.uri.clone(),
+		request.protocol_ver.clone(),
+		request.headers.clone(),
+	);
+
+	let response = handler(request);
+
+	serve::access_log::defer(serve::access_log::Pending {
+		ip,
+		time,
+		start,
+		method: method.as_str(),
+		url,
+		protocol,
+		headers,
+		status: response.status_code as u16,
+	});
+
+	response
+}
+
+#[cfg(feature = "serve")]
+fn handle_connection(request: HttpRequest) -> HttpResponse {
+	let site = config::get().site_for_host(request.header("Host"));
+
+	let pretty = match request.header("Pretty") {
+		Some(val) => match val.to_lowercase().as_str() {
+			"true" => true,
+			"false" => false,
+			_ => site.pretty,
+		},
+		None => site.pretty,
+	};
+
+	// Not even a method we know the name of.
+	if request.method == HttpMethod::Unknown {
+		return serve::error_page(501, &request.uri, &site, pretty);
+	}
+
+	// Everything below goes by the normalized path, so `/upload/../wiki` can't pass for `/upload`.
+	let path = match serve::path::normalize(&request.uri) {
+		Ok(path) => path,
+		Err(why) => return serve::error_page(why.status(), &request.uri, &site, pretty),
+	};
+	let mount = serve::proxy::find(&request, &path);
+
+	if let Some(allowed) = serve::methods::allowed(&path, mount.is_some()) {
+		let allow = serve::methods::allow_header(&allowed);
+
+		if !allowed.contains(&request.method) {
+			let mut response = serve::error_page(405, &request.uri, &site, pretty);
+			response.set_header("Allow", allow);
+			return response;
+		}
+
+		if request.method == HttpMethod::Options {
+			return HttpResponse::builder(204).header("Allow", allow).build();
 		}
 	}
 
-	if request.url.ends_with(".png") {
-		let path = working_dir + &request.url;
-		let image = match fs::read(path) {
-			Ok(image) => image,
+	// HEAD is answered like GET, the backends leave out the body.
+	route(request, path, mount, &site, pretty)
+}
+
+/// Answer an allowed request, from the backend `mount`, the rules or the files of `site`.
+#[cfg(feature = "serve")]
+fn route(
+	mut request: HttpRequest,
+	mut path: String,
+	mount: Option<usize>,
+	site: &config::Site,
+	pretty: bool,
+) -> HttpResponse {
+	// Mounted upstream backends get the request as-is, even ahead of redirect rules.
+	if let Some(mount) = mount {
+		let response = serve::proxy::forward(mount, &request, &path);
+		return match response.map(serve::proxy::UpstreamResponse::into_response) {
+			Ok(response) => response,
 			Err(why) => {
-				return if why.kind() == std::io::ErrorKind::NotFound {
-					response!(not_found)
-				} else {
-					log_with_context(&why, request);
-					response!(
-						internal_server_error,
-						format!("<h1>500 Internal server error</h1><p>{why}</p>"),
-						headers! {"Content-Type" => "text/html"}
-					)
-				};
+				error!("{why}");
+				serve::error_page(why.status(), &request.uri, site, pretty)
 			}
 		};
+	}
 
-		response!(
-			ok,
-			image,
-			headers! {
-				"Content-Type" => "image/png"
-			}
-		)
-	} else if request.method == Method::UNKNOWN {
-		response!(
-			im_a_teapot,
-			format_error(418, "I'm a teapot", "Method not supported", pretty),
-			headers! {"Content-Type" => "text/html"}
-		)
-	} else if request.url.starts_with("/api") {
-		response!(
-			payment_required,
-			format_error_with_html(
+	// Requests to `api.` without a backend
+	if let Some(host) = request.header("Host") {
+		if host.starts_with("api.") {
+			return HttpResponse::builder(402)
+				.content_type("text/json")
+				.body(r#"{"Status": "Payment required"}"#)
+				.build();
+		}
+	}
+
+	if config::get().dev && path.starts_with(serve::livereload::ENDPOINT) {
+		serve::livereload::events(&site.root, request.header("Last-Event-ID"))
+	} else if path.starts_with("/api") {
+		HttpResponse::builder(402)
+			.content_type("text/html")
+			.body(format_error_with_html(
 				402,
 				"Payment required",
 				html!(
@@ -301,25 +617,54 @@ fn handle_connection(request: Request) -> snowboard::Response {
 						</a>
 					</p>
 				),
+				&site.css_path,
 				pretty
-			),
-			headers! {"Content-Type" => "text/html"}
-		)
+			))
+			.build()
 	} else {
-		let (status, headers, doc) = serve::serve_file(request, &working_dir, pretty);
-
-		Response {
-			version: snowboard::DEFAULT_HTTP_VERSION,
-			status,
-			status_text: "banana",
-			bytes: doc.into(),
-			headers: Some(headers),
+		let query = serve::path::query(&request.uri);
+		match serve::rules::evaluate(site, &path, query) {
+			Some((_, serve::rules::Action::Redirect(status, location))) => {
+				return HttpResponse::builder(status.into())
+					.location(&location)
+					.build();
+			}
+			Some((_, serve::rules::Action::Gone)) => {
+				return serve::error_page(410, &request.uri, site, pretty);
+			}
+			// The target is a URL of its own, normalized again before it is looked up.
+			Some((_, serve::rules::Action::Rewrite(url))) => {
+				path = match serve::path::normalize(&url) {
+					Ok(path) => path,
+					Err(why) => return serve::error_page(why.status(), &url, site, pretty),
+				};
+				request.uri = url;
+			}
+			None => (),
 		}
+
+		serve::serve_file(request, &path, site, pretty)
 	}
 }
 
+/// Turn requests away while we are shutting down.
 #[cfg(feature = "serve")]
-fn format_error(err_code: usize, err_desc: &str, err_details: &str, pretty: bool) -> String {
+fn shutting_down(_: HttpRequest) -> HttpResponse {
+	HttpResponse::builder(503)
+		.retry_after(5)
+		.content_type("text/plain")
+		.body("Shutting down, try again in a moment\n")
+		.build()
+}
+
+#[cfg(feature = "serve")]
+fn format_error(
+	err_code: usize,
+	err_desc: &str,
+	err_details: &str,
+	css_path: &str,
+	pretty: bool,
+) -> String {
 	#[cfg(feature = "serve")]
 	{
 		format_error_with_html(
@@ -328,6 +673,7 @@ fn format_error(err_code: usize, err_desc: &str, err_details: &str, pretty: bool
 			html!(
 				<p>{text!("{err_details}")}</p>
 			),
+			css_path,
 			pretty,
 		)
 	}
@@ -341,18 +687,27 @@ fn format_error_with_html(
 	err_code: usize,
 	err_desc: &str,
 	custom_html: html_node::Node,
+	css_path: &str,
 	pretty: bool,
 ) -> String {
+	format_page(&format!("{err_code}: {err_desc}"), custom_html, css_path, pretty)
+}
+
+/// # format_page
+/// Wrap `content` in the page shell used for everything the server generates itself (error pages,
+/// directory listings).
+#[cfg(feature = "serve")]
+fn format_page(title: &str, content: html_node::Node, css_path: &str, pretty: bool) -> String {
 	let doc = html!(
 		<!DOCTYPE html>
 		<html>
 			<head>
-				<title>{text!("{err_code}: {err_desc}")}</title>
-				<link rel="style/css" href=format!("{CSS_PATH}")>
+				<title>{text!("{title}")}</title>
+				<link rel="stylesheet" href=css_path>
 			</head>
 			<body>
-				<h1>{text!("{err_code}: {err_desc}")}</h1>
-				{custom_html}
+				<h1>{text!("{title}")}</h1>
+				{content}
 			</body>
 		</html>
 	);
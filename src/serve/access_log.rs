@@ -0,0 +1,446 @@
+use std::{
+	cell::RefCell,
+	fs::{self, File, OpenOptions},
+	io::{self, Write},
+	net::IpAddr,
+	path::{Path, PathBuf},
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc, Mutex, OnceLock,
+	},
+	time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Local};
+use mdbutler::{error, HttpHeader};
+
+use crate::config::AccessLogConfig;
+
+/// Apache's `common` format.
+const COMMON: &str = r#"%h %l %u %t "%r" %>s %b"#;
+/// Apache's `combined` format, what goaccess & co expect by default.
+const COMBINED: &str = r#"%h %l %u %t "%r" %>s %b "%{Referer}i" "%{User-Agent}i""#;
+
+static ACCESS_LOG: OnceLock<AccessLog> = OnceLock::new();
+
+thread_local! {
+	/// The request answered on this thread, logged once its response has been sent.
+	static PENDING: RefCell<Option<Pending>> = const { RefCell::new(None) };
+}
+
+/// Everything about a finished request that can end up in the log.
+pub struct Entry<'a> {
+	/// Unknown if the backend didn't tell us.
+	pub ip: Option<IpAddr>,
+	/// When the request came in.
+	pub time: DateTime<Local>,
+	pub method: &'a str,
+	/// Raw request target, query included.
+	pub url: &'a str,
+	/// Version from the request line, like `HTTP/1.0`.
+	pub protocol: &'a str,
+	pub headers: &'a [HttpHeader],
+	pub status: u16,
+	/// Body bytes actually written to the client.
+	pub bytes: u64,
+	pub duration: Duration,
+}
+
+/// # Pending
+/// A request that has been answered, but whose response hasn't been sent yet.
+///
+/// How much of the body reaches the client is only known after sending it: nothing for `HEAD`
+/// or a `304`, less than announced if the client hangs up halfway.
+pub struct Pending {
+	pub ip: Option<IpAddr>,
+	pub time: DateTime<Local>,
+	/// When handling the request started.
+	pub start: Instant,
+	pub method: &'static str,
+	pub url: String,
+	pub protocol: String,
+	pub headers: Vec<HttpHeader>,
+	pub status: u16,
+}
+
+/// A single `LogFormat` directive.
+enum Token {
+	Literal(String),
+	/// `%h`
+	RemoteHost,
+	/// `%l` and `%u`, we know neither.
+	Unknown,
+	/// `%t`
+	Time,
+	/// `%r`
+	RequestLine,
+	/// `%m`
+	Method,
+	/// `%U`
+	Path,
+	/// `%q`
+	Query,
+	/// `%H`
+	Protocol,
+	/// `%s` and `%>s`
+	Status,
+	/// `%b`, `-` instead of `0`.
+	BytesClf,
+	/// `%B`
+	Bytes,
+	/// `%D`
+	Microseconds,
+	/// `%T`
+	Seconds,
+	/// `%{Header}i`
+	Header(String),
+}
+
+enum Sink {
+	Stdout,
+	File { file: File, size: u64 },
+}
+
+struct AccessLog {
+	format: Vec<Token>,
+	/// Log file, `None` when logging to stdout.
+	path: Option<PathBuf>,
+	sink: Mutex<Sink>,
+	max_size: u64,
+	keep: usize,
+	/// Set from the SIGHUP handler, the next write reopens the file.
+	reopen: Arc<AtomicBool>,
+}
+
+/// # init
+/// Open the access log described by `config`, if any.
+///
+/// On unix a SIGHUP makes us reopen the file, so external tools like logrotate can move it
+/// away.
+pub fn init(config: &AccessLogConfig) -> io::Result<()> {
+	let Some(path) = &config.path else {
+		return Ok(());
+	};
+
+	let format = match config.format.as_str() {
+		"common" => parse_format(COMMON),
+		"combined" => parse_format(COMBINED),
+		custom => parse_format(custom),
+	};
+
+	let (path, sink) = match path.as_str() {
+		"-" => (None, Sink::Stdout),
+		path => {
+			let path = PathBuf::from(path);
+			let sink = open(&path)?;
+			(Some(path), sink)
+		}
+	};
+
+	let reopen = Arc::new(AtomicBool::new(false));
+	#[cfg(unix)]
+	signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(&reopen))?;
+
+	let _ = ACCESS_LOG.set(AccessLog {
+		format,
+		path,
+		sink: Mutex::new(sink),
+		max_size: config.max_size,
+		keep: config.keep,
+		reopen,
+	});
+
+	Ok(())
+}
+
+/// Whether [`record`] actually writes anything.
+pub fn enabled() -> bool {
+	ACCESS_LOG.get().is_some()
+}
+
+/// # record
+/// Append a line for a finished request.
+///
+/// Failing to write the access log never fails the request, errors are only printed.
+pub fn record(entry: &Entry) {
+	let Some(log) = ACCESS_LOG.get() else {
+		return;
+	};
+
+	let mut line = format_entry(&log.format, entry);
+	line.push('\n');
+
+	if let Err(why) = log.write(line.as_bytes()) {
+		error!("Failed to write access log: {why}");
+	}
+}
+
+/// # defer
+/// Hold on to `pending` until its response was sent, see [`sent`].
+pub fn defer(pending: Pending) {
+	PENDING.with(|cell| *cell.borrow_mut() = Some(pending));
+}
+
+/// # sent
+/// Record the request answered on this thread, now that `bytes` of its body were written.
+///
+/// Does nothing unless [`defer`] was called for it.
+pub fn sent(bytes: u64) {
+	let Some(pending) = PENDING.with(|cell| cell.borrow_mut().take()) else {
+		return;
+	};
+
+	record(&Entry {
+		ip: pending.ip,
+		time: pending.time,
+		method: pending.method,
+		url: &pending.url,
+		protocol: &pending.protocol,
+		headers: &pending.headers,
+		status: pending.status,
+		bytes,
+		duration: pending.start.elapsed(),
+	});
+}
+
+impl AccessLog {
+	fn write(&self, line: &[u8]) -> io::Result<()> {
+		let mut sink = self.sink.lock().unwrap();
+
+		if let Some(path) = &self.path {
+			if self.reopen.swap(false, Ordering::Relaxed) {
+				*sink = open(path)?;
+			}
+
+			if let Sink::File { size, .. } = &*sink {
+				if self.max_size > 0 && *size > 0 && *size + line.len() as u64 > self.max_size {
+					rotate(path, self.keep)?;
+					*sink = open(path)?;
+				}
+			}
+		}
+
+		match &mut *sink {
+			Sink::Stdout => io::stdout().lock().write_all(line),
+			Sink::File { file, size } => {
+				file.write_all(line)?;
+				*size += line.len() as u64;
+				Ok(())
+			}
+		}
+	}
+}
+
+fn open(path: &Path) -> io::Result<Sink> {
+	let file = OpenOptions::new().create(true).append(true).open(path)?;
+	let size = file.metadata()?.len();
+
+	Ok(Sink::File { file, size })
+}
+
+/// Shift `log.N` to `log.N+1` (dropping the oldest) and move the current file to `log.1`.
+fn rotate(path: &Path, keep: usize) -> io::Result<()> {
+	let rotated = |n: usize| {
+		let mut name = path.as_os_str().to_os_string();
+		name.push(format!(".{n}"));
+		PathBuf::from(name)
+	};
+
+	if keep == 0 {
+		return fs::remove_file(path);
+	}
+
+	for n in (1..keep).rev() {
+		let from = rotated(n);
+		if from.exists() {
+			fs::rename(from, rotated(n + 1))?;
+		}
+	}
+
+	fs::rename(path, rotated(1))
+}
+
+/// Split a `LogFormat` string into tokens, unknown directives are kept as-is.
+fn parse_format(format: &str) -> Vec<Token> {
+	let mut tokens = Vec::new();
+	let mut literal = String::new();
+	let mut chars = format.chars().peekable();
+
+	while let Some(c) = chars.next() {
+		if c != '%' {
+			literal.push(c);
+			continue;
+		}
+
+		let token = match chars.next() {
+			Some('%') => {
+				literal.push('%');
+				continue;
+			}
+			Some('>') if chars.peek() == Some(&'s') => {
+				chars.next();
+				Token::Status
+			}
+			Some('{') => {
+				let mut name = String::new();
+				for c in chars.by_ref() {
+					if c == '}' {
+						break;
+					}
+					name.push(c);
+				}
+				match chars.next() {
+					Some('i') => Token::Header(name),
+					Some(other) => {
+						literal.push_str(&format!("%{{{name}}}{other}"));
+						continue;
+					}
+					None => {
+						literal.push_str(&format!("%{{{name}}}"));
+						continue;
+					}
+				}
+			}
+			Some('h') => Token::RemoteHost,
+			Some('l') | Some('u') => Token::Unknown,
+			Some('t') => Token::Time,
+			Some('r') => Token::RequestLine,
+			Some('m') => Token::Method,
+			Some('U') => Token::Path,
+			Some('q') => Token::Query,
+			Some('H') => Token::Protocol,
+			Some('s') => Token::Status,
+			Some('b') => Token::BytesClf,
+			Some('B') => Token::Bytes,
+			Some('D') => Token::Microseconds,
+			Some('T') => Token::Seconds,
+			Some(other) => {
+				literal.push('%');
+				literal.push(other);
+				continue;
+			}
+			None => {
+				literal.push('%');
+				continue;
+			}
+		};
+
+		if !literal.is_empty() {
+			tokens.push(Token::Literal(std::mem::take(&mut literal)));
+		}
+		tokens.push(token);
+	}
+
+	if !literal.is_empty() {
+		tokens.push(Token::Literal(literal));
+	}
+
+	tokens
+}
+
+fn format_entry(format: &[Token], entry: &Entry) -> String {
+	let (path, query) = match entry.url.split_once('?') {
+		Some((path, query)) => (path, format!("?{query}")),
+		None => (entry.url, String::new()),
+	};
+
+	let mut line = String::new();
+	for token in format {
+		match token {
+			Token::Literal(literal) => line.push_str(literal),
+			Token::RemoteHost => match entry.ip {
+				Some(ip) => line.push_str(&ip.to_string()),
+				None => line.push('-'),
+			},
+			Token::Unknown => line.push('-'),
+			Token::Time => line.push_str(&entry.time.format("[%d/%b/%Y:%H:%M:%S %z]").to_string()),
+			Token::RequestLine => line.push_str(&escape(&format!(
+				"{} {} {}",
+				entry.method, entry.url, entry.protocol
+			))),
+			Token::Method => line.push_str(&escape(entry.method)),
+			Token::Path => line.push_str(&escape(path)),
+			Token::Query => line.push_str(&escape(&query)),
+			Token::Protocol => line.push_str(&escape(entry.protocol)),
+			Token::Status => line.push_str(&entry.status.to_string()),
+			Token::BytesClf => match entry.bytes {
+				0 => line.push('-'),
+				bytes => line.push_str(&bytes.to_string()),
+			},
+			Token::Bytes => line.push_str(&entry.bytes.to_string()),
+			Token::Microseconds => line.push_str(&entry.duration.as_micros().to_string()),
+			Token::Seconds => line.push_str(&entry.duration.as_secs().to_string()),
+			Token::Header(name) => {
+				let value = entry
+					.headers
+					.iter()
+					.find(|header| header.key.eq_ignore_ascii_case(name))
+					.map(|header| header.val.as_str());
+				match value {
+					Some(value) => line.push_str(&escape(value)),
+					None => line.push('-'),
+				}
+			}
+		}
+	}
+
+	line
+}
+
+/// Escape quotes, backslashes and control characters the way Apache does, so a client can't
+/// forge log lines.
+fn escape(value: &str) -> String {
+	let mut escaped = String::with_capacity(value.len());
+
+	for c in value.chars() {
+		match c {
+			'"' => escaped.push_str("\\\""),
+			'\\' => escaped.push_str("\\\\"),
+			'\n' => escaped.push_str("\\n"),
+			'\r' => escaped.push_str("\\r"),
+			'\t' => escaped.push_str("\\t"),
+			c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+			c => escaped.push(c),
+		}
+	}
+
+	escaped
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn entry<'a>(protocol: &'a str, headers: &'a [HttpHeader]) -> Entry<'a> {
+		Entry {
+			ip: None,
+			time: Local::now(),
+			method: "GET",
+			url: "/page?x=1",
+			protocol,
+			headers,
+			status: 200,
+			bytes: 0,
+			duration: Duration::from_micros(1500),
+		}
+	}
+
+	#[test]
+	fn request_line_keeps_the_request_version() {
+		let format = parse_format(r#""%r" %H"#);
+		assert_eq!(
+			format_entry(&format, &entry("HTTP/1.0", &[])),
+			r#""GET /page?x=1 HTTP/1.0" HTTP/1.0"#
+		);
+	}
+
+	#[test]
+	fn common_format() {
+		let line = format_entry(&parse_format(COMMON), &entry("HTTP/1.1", &[]));
+		assert!(line.starts_with("- - - ["), "{line}");
+		assert!(
+			line.ends_with(r#"] "GET /page?x=1 HTTP/1.1" 200 -"#),
+			"{line}"
+		);
+	}
+}
@@ -0,0 +1,221 @@
+use std::{fs, io, path::Path, time::SystemTime};
+
+use chrono::{DateTime, Local};
+use html_node::{
+	text,
+	typed::{elements::*, html},
+};
+
+use crate::config::Site;
+use crate::convert::markdown;
+use crate::format_page;
+
+use super::path;
+
+/// Column a listing is sorted by, picked with `?sort=`.
+#[derive(Clone, Copy, PartialEq)]
+enum Column {
+	Name,
+	Size,
+	Modified,
+}
+
+impl Column {
+	fn from_query(query: &str) -> Self {
+		match query {
+			"size" => Self::Size,
+			"modified" => Self::Modified,
+			_ => Self::Name,
+		}
+	}
+
+	fn as_query(&self) -> &'static str {
+		match self {
+			Self::Name => "name",
+			Self::Size => "size",
+			Self::Modified => "modified",
+		}
+	}
+}
+
+struct Entry {
+	/// File name as it is on disk, used for the link.
+	name: String,
+	/// What to show, the page title for markdown files.
+	label: String,
+	is_dir: bool,
+	size: u64,
+	modified: Option<SystemTime>,
+}
+
+/// Whether `url` (a directory, ending in `/`) lies below one of the site's autoindex prefixes.
+pub fn enabled(site: &Site, url: &str) -> bool {
+	site.autoindex.iter().any(|prefix| {
+		let prefix = prefix.trim_end_matches('/');
+		url.starts_with(&format!("{prefix}/"))
+	})
+}
+
+/// # listing
+/// Render the listing of `dir`, which is served at `url`.
+///
+/// `query` is the raw query string of the request, `sort=name|size|modified` and
+/// `order=asc|desc` pick the order of the entries; directories always come first.
+pub fn listing(
+	url: &str,
+	query: Option<&str>,
+	dir: &Path,
+	site: &Site,
+	pretty: bool,
+) -> Result<String, io::Error> {
+	let (mut column, mut descending) = (Column::Name, false);
+	for (key, value) in query
+		.unwrap_or_default()
+		.split('&')
+		.filter_map(|pair| pair.split_once('='))
+	{
+		match key {
+			"sort" => column = Column::from_query(value),
+			"order" => descending = value == "desc",
+			_ => (),
+		}
+	}
+
+	let mut entries = Vec::new();
+	for entry in fs::read_dir(dir)? {
+		let entry = entry?;
+		let name = entry.file_name().to_string_lossy().to_string();
+
+		// Hidden files stay hidden, as does anything the symlink policy wouldn't let us serve.
+		if name.starts_with('.') {
+			continue;
+		}
+		let Ok(path) = path::resolve(&site.root, &format!("{url}{name}"), site.follow_symlinks)
+		else {
+			continue;
+		};
+		if super::is_internal(&path, site, crate::config::get().file.as_deref()) {
+			continue;
+		}
+		let Ok(meta) = fs::metadata(&path) else {
+			continue;
+		};
+
+		let label = match name.ends_with(".md") && meta.is_file() {
+			true => markdown::page_title(&path).unwrap_or_else(|| name.clone()),
+			false => name.clone(),
+		};
+
+		entries.push(Entry {
+			label,
+			is_dir: meta.is_dir(),
+			size: meta.len(),
+			modified: meta.modified().ok(),
+			name,
+		});
+	}
+
+	entries.sort_by(|a, b| {
+		let order = match column {
+			Column::Name => a.label.to_lowercase().cmp(&b.label.to_lowercase()),
+			Column::Size => a.size.cmp(&b.size),
+			Column::Modified => a.modified.cmp(&b.modified),
+		};
+		let order = if descending { order.reverse() } else { order };
+
+		b.is_dir
+			.cmp(&a.is_dir)
+			.then(order)
+			.then_with(|| a.name.cmp(&b.name))
+	});
+
+	// Clicking the current column flips the order, any other column starts ascending.
+	let heading = |label: &str, target: Column| {
+		let order = match target == column && !descending {
+			true => "desc",
+			false => "asc",
+		};
+		html!(
+			<th>
+				<a href=format!("?sort={}&order={order}", target.as_query())>{text!("{label}")}</a>
+			</th>
+		)
+	};
+
+	let parent = match url {
+		"/" => html!(<>),
+		_ => html!(
+			<tr>
+				<td><a href="../">../</a></td>
+				<td></td>
+				<td></td>
+			</tr>
+		),
+	};
+
+	let content = html!(
+		<table class="autoindex">
+			<thead>
+				<tr>
+					{heading("Name", Column::Name)}
+					{heading("Size", Column::Size)}
+					{heading("Modified", Column::Modified)}
+				</tr>
+			</thead>
+			<tbody>
+				{parent}
+				{
+					entries.into_iter().map(|entry| {
+						// `./` so a name with a colon in it isn't taken for a scheme.
+						let href = format!("./{}", path::encode(&entry.name));
+						let (href, label) = match entry.is_dir {
+							true => (format!("{href}/"), format!("{}/", entry.label)),
+							false => (href, entry.label),
+						};
+						let size = match entry.is_dir {
+							true => String::from("-"),
+							false => format_size(entry.size),
+						};
+						let modified = entry
+							.modified
+							.map(|modified| DateTime::<Local>::from(modified).format("%Y-%m-%d %H:%M").to_string())
+							.unwrap_or_default();
+
+						html!(
+							<tr>
+								<td><a href=href>{text!("{label}")}</a></td>
+								<td>{text!("{size}")}</td>
+								<td>{text!("{modified}")}</td>
+							</tr>
+						)
+					})
+				}
+			</tbody>
+		</table>
+	);
+
+	Ok(format_page(
+		&format!("Index of {url}"),
+		content,
+		&site.css_path,
+		pretty,
+	))
+}
+
+/// Human readable file size, `1.5 KiB` and the like.
+fn format_size(size: u64) -> String {
+	const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+	if size < 1024 {
+		return format!("{size} B");
+	}
+
+	let mut size = size as f64 / 1024.0;
+	let mut unit = 0;
+	while size >= 1024.0 && unit < UNITS.len() - 1 {
+		size /= 1024.0;
+		unit += 1;
+	}
+
+	format!("{size:.1} {}", UNITS[unit])
+}
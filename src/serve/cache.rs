@@ -0,0 +1,288 @@
+use std::{
+	collections::HashMap,
+	fs,
+	path::{Path, PathBuf},
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc, Mutex, OnceLock,
+	},
+	time::SystemTime,
+};
+
+use mdbutler::debug;
+
+static CACHE: OnceLock<RenderCache> = OnceLock::new();
+
+/// The render cache for the active configuration, created on first use.
+pub fn cache() -> &'static RenderCache {
+	CACHE.get_or_init(|| {
+		let config = &crate::config::get().cache;
+		RenderCache::new(if config.enabled { config.max_bytes } else { 0 })
+	})
+}
+
+/// Cache key: the source file, whether the output is pretty-printed and the stylesheet the page
+/// links to, which depends on the site's `asset_path`.
+pub type Key = (PathBuf, bool, Option<String>);
+
+/// # RenderCache
+/// A bounded LRU cache for rendered markdown and SCSS.
+///
+/// Every entry remembers the files it was rendered from (for SCSS that includes every partial it
+/// imported) along with their mtimes. An entry is only handed out while all of those are
+/// unchanged, so editing a page or a partial is picked up on the next request.
+///
+/// The cache is bounded by the total size of the rendered output, the least recently used
+/// entries are evicted first. A `max_bytes` of 0 disables caching.
+pub struct RenderCache {
+	inner: Mutex<Inner>,
+	hits: AtomicU64,
+	misses: AtomicU64,
+}
+
+struct Inner {
+	entries: HashMap<Key, Entry>,
+	/// Total size of all cached content.
+	size: usize,
+	max_bytes: usize,
+	/// Monotonic counter used as the "last used" timestamp.
+	tick: u64,
+}
+
+struct Entry {
+	content: Arc<Vec<u8>>,
+	dependencies: Vec<(PathBuf, Option<SystemTime>)>,
+	last_used: u64,
+}
+
+/// Output of a render, whether it came from the cache or not.
+#[derive(Clone, Debug)]
+pub struct Rendered {
+	pub content: Arc<Vec<u8>>,
+	/// Files the output was rendered from, the source itself first.
+	pub dependencies: Vec<PathBuf>,
+	pub cached: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct CacheStats {
+	pub hits: u64,
+	pub misses: u64,
+	pub entries: usize,
+	pub size: usize,
+}
+
+impl RenderCache {
+	pub fn new(max_bytes: usize) -> Self {
+		Self {
+			inner: Mutex::new(Inner {
+				entries: HashMap::new(),
+				size: 0,
+				max_bytes,
+				tick: 0,
+			}),
+			hits: AtomicU64::new(0),
+			misses: AtomicU64::new(0),
+		}
+	}
+
+	/// # get_or_render
+	/// Look up `key`, calling `render` on a miss or if the entry is stale.
+	///
+	/// `render` returns the output together with every file it read.
+	pub fn get_or_render<F, E>(&self, key: Key, render: F) -> Result<Rendered, E>
+	where
+		F: FnOnce() -> Result<(Vec<u8>, Vec<PathBuf>), E>,
+	{
+		if let Some(rendered) = self.get(&key) {
+			self.hits.fetch_add(1, Ordering::Relaxed);
+			return Ok(rendered);
+		}
+		self.misses.fetch_add(1, Ordering::Relaxed);
+
+		// Stamp the source before rendering, if it changes while we render we'd rather render it
+		// again next time than keep stale output around. Other dependencies are only known
+		// afterwards.
+		let source_modified = modified(&key.0);
+		let (content, dependencies) = render()?;
+		let content = Arc::new(content);
+		let stamped = dependencies
+			.iter()
+			.map(|path| match path == &key.0 {
+				true => (path.clone(), source_modified),
+				false => (path.clone(), modified(path)),
+			})
+			.collect();
+
+		let evicted = self.insert(key, content.clone(), stamped);
+		if evicted > 0 {
+			let stats = self.stats();
+			debug!(
+				"Render cache full, evicted {evicted} entries ({} entries, {} bytes, {} hits, {} misses)",
+				stats.entries, stats.size, stats.hits, stats.misses
+			);
+		}
+
+		Ok(Rendered {
+			content,
+			dependencies,
+			cached: false,
+		})
+	}
+
+	/// # dependencies
+	/// The files the cached entry for `key` was rendered from, `None` if nothing is cached.
+	///
+	/// This is what conditional requests are checked against before anything is rendered. Should
+	/// the source have started importing something new, its own mtime changed too.
+	pub fn dependencies(&self, key: &Key) -> Option<Vec<PathBuf>> {
+		let inner = self.inner.lock().unwrap();
+		let entry = inner.entries.get(key)?;
+
+		Some(
+			entry
+				.dependencies
+				.iter()
+				.map(|(path, _)| path.clone())
+				.collect(),
+		)
+	}
+
+	fn get(&self, key: &Key) -> Option<Rendered> {
+		let mut inner = self.inner.lock().unwrap();
+		inner.tick += 1;
+		let tick = inner.tick;
+
+		let entry = inner.entries.get_mut(key)?;
+		let fresh = entry
+			.dependencies
+			.iter()
+			.all(|(path, mtime)| modified(path) == *mtime);
+
+		if !fresh {
+			inner.remove(key);
+			return None;
+		}
+
+		entry.last_used = tick;
+		Some(Rendered {
+			content: entry.content.clone(),
+			dependencies: entry
+				.dependencies
+				.iter()
+				.map(|(path, _)| path.clone())
+				.collect(),
+			cached: true,
+		})
+	}
+
+	/// Add an entry, returning how many others had to be evicted to make room.
+	fn insert(
+		&self,
+		key: Key,
+		content: Arc<Vec<u8>>,
+		dependencies: Vec<(PathBuf, Option<SystemTime>)>,
+	) -> usize {
+		let mut inner = self.inner.lock().unwrap();
+		if content.len() > inner.max_bytes {
+			return 0;
+		}
+
+		inner.remove(&key);
+		let mut evicted = 0;
+		while inner.size + content.len() > inner.max_bytes {
+			let Some(oldest) = inner
+				.entries
+				.iter()
+				.min_by_key(|(_, entry)| entry.last_used)
+				.map(|(key, _)| key.clone())
+			else {
+				break;
+			};
+			inner.remove(&oldest);
+			evicted += 1;
+		}
+
+		inner.tick += 1;
+		inner.size += content.len();
+		let last_used = inner.tick;
+		inner.entries.insert(
+			key,
+			Entry {
+				content,
+				dependencies,
+				last_used,
+			},
+		);
+
+		evicted
+	}
+
+	pub fn stats(&self) -> CacheStats {
+		let inner = self.inner.lock().unwrap();
+
+		CacheStats {
+			hits: self.hits.load(Ordering::Relaxed),
+			misses: self.misses.load(Ordering::Relaxed),
+			entries: inner.entries.len(),
+			size: inner.size,
+		}
+	}
+}
+
+impl Inner {
+	fn remove(&mut self, key: &Key) {
+		if let Some(entry) = self.entries.remove(key) {
+			self.size -= entry.content.len();
+		}
+	}
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+	fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn render(path: &Path, size: usize) -> Result<(Vec<u8>, Vec<PathBuf>), ()> {
+		Ok((
+			vec![b'x'; size],
+			vec![path.to_path_buf(), path.with_extension("partial")],
+		))
+	}
+
+	#[test]
+	fn dependencies_are_evicted_with_their_entries() {
+		let cache = RenderCache::new(10);
+		let (first, second) = (PathBuf::from("/first.scss"), PathBuf::from("/second.scss"));
+		let key = |path: &PathBuf| (path.clone(), false, None);
+
+		cache
+			.get_or_render(key(&first), || render(&first, 6))
+			.unwrap();
+		assert_eq!(cache.dependencies(&key(&first)).unwrap().len(), 2);
+
+		cache
+			.get_or_render(key(&second), || render(&second, 6))
+			.unwrap();
+		assert_eq!(cache.dependencies(&key(&first)), None);
+		assert_eq!(cache.dependencies(&key(&second)).unwrap().len(), 2);
+	}
+
+	#[test]
+	fn disabled_cache_keeps_nothing() {
+		let cache = RenderCache::new(0);
+		let source = PathBuf::from("/page.md");
+
+		for stylesheet in 0..100 {
+			let key = (source.clone(), false, Some(format!("/{stylesheet}.css")));
+			cache
+				.get_or_render(key.clone(), || render(&source, 1))
+				.unwrap();
+			assert_eq!(cache.dependencies(&key), None);
+		}
+		assert_eq!(cache.stats().entries, 0);
+	}
+}
@@ -0,0 +1,164 @@
+use std::{
+	fs,
+	io::Write,
+	path::{Path, PathBuf},
+};
+
+use flate2::{
+	write::{GzEncoder, ZlibEncoder},
+	Compression,
+};
+
+use crate::config::CompressionConfig;
+
+/// A `Content-Encoding` we can produce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+	#[cfg(feature = "brotli")]
+	Brotli,
+	Gzip,
+	Deflate,
+}
+
+impl Encoding {
+	/// Supported encodings, most preferred first.
+	const ALL: &'static [Encoding] = &[
+		#[cfg(feature = "brotli")]
+		Encoding::Brotli,
+		Encoding::Gzip,
+		Encoding::Deflate,
+	];
+
+	/// Name used in `Accept-Encoding` and `Content-Encoding`.
+	pub fn name(&self) -> &'static str {
+		match self {
+			#[cfg(feature = "brotli")]
+			Encoding::Brotli => "br",
+			Encoding::Gzip => "gzip",
+			Encoding::Deflate => "deflate",
+		}
+	}
+
+	/// Extension of precompressed siblings on disk, e.g. `style.css.gz`.
+	fn extension(&self) -> Option<&'static str> {
+		match self {
+			#[cfg(feature = "brotli")]
+			Encoding::Brotli => Some("br"),
+			Encoding::Gzip => Some("gz"),
+			Encoding::Deflate => None,
+		}
+	}
+}
+
+/// # accepted
+/// Encodings the client accepts according to its `Accept-Encoding` header, best first.
+///
+/// Ordered by q-value, ties are broken by our own preference (`br`, `gzip`, `deflate`).
+/// Anything with `q=0` is left out.
+pub fn accepted(accept_encoding: &str) -> Vec<Encoding> {
+	let mut wildcard = None;
+	let mut listed: Vec<(&str, f32)> = Vec::new();
+
+	for item in accept_encoding.split(',') {
+		let mut params = item.split(';').map(str::trim);
+		let Some(coding) = params.next().filter(|coding| !coding.is_empty()) else {
+			continue;
+		};
+
+		let q = params
+			.find_map(|param| param.strip_prefix("q="))
+			.and_then(|q| q.parse::<f32>().ok())
+			.unwrap_or(1.0);
+
+		if coding == "*" {
+			wildcard = Some(q);
+		} else {
+			listed.push((coding, q));
+		}
+	}
+
+	let mut encodings: Vec<(Encoding, f32)> = Encoding::ALL
+		.iter()
+		.filter_map(|encoding| {
+			let q = listed
+				.iter()
+				.find(|(coding, _)| coding.eq_ignore_ascii_case(encoding.name()))
+				.map(|(_, q)| *q)
+				.or(wildcard)?;
+
+			(q > 0.0).then_some((*encoding, q))
+		})
+		.collect();
+
+	// Stable, so equal q-values keep our preference order.
+	encodings.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+
+	encodings.into_iter().map(|(encoding, _)| encoding).collect()
+}
+
+/// Whether a response of this type is worth compressing at all.
+pub fn compressible(config: &CompressionConfig, mime_type: &str) -> bool {
+	let mime_type = mime_type.split(';').next().unwrap_or_default().trim();
+
+	config.enabled
+		&& config
+			.mime_types
+			.iter()
+			.any(|prefix| mime_type.starts_with(prefix.as_str()))
+}
+
+/// Compress a response body.
+pub fn compress(data: &[u8], encoding: Encoding, level: u32) -> std::io::Result<Vec<u8>> {
+	match encoding {
+		#[cfg(feature = "brotli")]
+		Encoding::Brotli => {
+			let mut out = Vec::new();
+			{
+				// Brotli's quality goes up to 11 instead of 9.
+				let quality = (level + level / 4).min(11);
+				let mut writer = brotli::CompressorWriter::new(&mut out, 4096, quality, 22);
+				writer.write_all(data)?;
+			}
+			Ok(out)
+		}
+		Encoding::Gzip => {
+			let mut encoder = GzEncoder::new(Vec::new(), Compression::new(level));
+			encoder.write_all(data)?;
+			encoder.finish()
+		}
+		Encoding::Deflate => {
+			// `deflate` in HTTP means the zlib format, not raw deflate.
+			let mut encoder = ZlibEncoder::new(Vec::new(), Compression::new(level));
+			encoder.write_all(data)?;
+			encoder.finish()
+		}
+	}
+}
+
+/// # precompressed
+/// Find a precompressed sibling of `path` (`file.br`, `file.gz`) in one of `encodings`.
+///
+/// Siblings older than the file itself are stale and ignored.
+pub fn precompressed(path: &Path, encodings: &[Encoding]) -> Option<(Encoding, PathBuf)> {
+	let modified = fs::metadata(path).and_then(|meta| meta.modified()).ok()?;
+
+	encodings.iter().find_map(|encoding| {
+		let mut sibling = path.as_os_str().to_owned();
+		sibling.push(".");
+		sibling.push(encoding.extension()?);
+		let sibling = PathBuf::from(sibling);
+
+		let sibling_modified = fs::metadata(&sibling).and_then(|meta| meta.modified()).ok()?;
+		(sibling_modified >= modified).then_some((*encoding, sibling))
+	})
+}
+
+/// Tag an ETag with the encoding, different encodings are different representations.
+///
+/// `"abc"` becomes `"abc-gzip"`; see [`super::conditional::weak_eq`] for the other direction.
+pub fn encoded_etag(etag: &str, encoding: Encoding) -> String {
+	match etag.strip_suffix('"') {
+		Some(tag) => format!("{tag}-{}\"", encoding.name()),
+		None => etag.to_string(),
+	}
+}
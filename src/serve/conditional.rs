@@ -0,0 +1,287 @@
+use std::{
+	collections::HashMap,
+	fs,
+	hash::Hasher,
+	path::{Path, PathBuf},
+	sync::Mutex,
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use mdbutler::{http_date, HttpRequest, HttpResponse};
+
+/// Files whose content hash is remembered, beyond that the memory starts over.
+const MAX_HASHED: usize = 1024;
+/// How much older than the moment it was hashed a file's mtime has to be for the hash to be
+/// reused. Filesystems with coarse timestamps (FAT has 2 seconds) can't tell edits in the same
+/// tick apart otherwise.
+const MTIME_GRANULARITY: Duration = Duration::from_secs(2);
+
+/// Content hashes of source files, see [`content_hash`].
+static HASHES: Mutex<Option<HashMap<PathBuf, Hashed>>> = Mutex::new(None);
+
+struct Hashed {
+	len: u64,
+	modified: Option<SystemTime>,
+	hashed_at: SystemTime,
+	hash: u64,
+}
+
+/// # Validators
+/// Cache validators (`ETag` and `Last-Modified`) for a response.
+///
+/// Raw files get a strong ETag from their size and mtime, which lets us answer conditional
+/// requests without reading the file. Rendered files (markdown, SCSS) hash the content of every
+/// file they are rendered from together with whatever else changes the output, so they can be
+/// checked before doing the work we are trying to avoid. Content hashes are remembered while a
+/// file's size and mtime stay the same.
+#[derive(Debug)]
+pub struct Validators {
+	pub etag: String,
+	pub last_modified: Option<SystemTime>,
+}
+
+impl Validators {
+	/// Validators for a file that is sent as-is.
+	pub fn for_raw(path: &Path) -> std::io::Result<Self> {
+		let meta = fs::metadata(path)?;
+		let modified = meta.modified().ok();
+
+		Ok(Self {
+			etag: format!("\"{:x}-{:x}\"", meta.len(), nanos(modified)),
+			last_modified: modified,
+		})
+	}
+
+	/// Validators for output rendered from `sources`.
+	///
+	/// `variant` distinguishes different renderings of the same sources (e.g. pretty-printed
+	/// output). `Last-Modified` is the newest mtime among the sources.
+	pub fn for_rendered(sources: &[PathBuf], variant: &[u8]) -> std::io::Result<Self> {
+		let mut hasher = Fnv1a::default();
+		let mut last_modified = None;
+
+		for source in sources {
+			let meta = fs::metadata(source)?;
+			let modified = meta.modified().ok();
+			hasher.write(source.as_os_str().as_encoded_bytes());
+			hasher.write_u64(content_hash(source, meta.len(), modified)?);
+			hasher.write_u128(nanos(modified));
+
+			if modified > last_modified {
+				last_modified = modified;
+			}
+		}
+		hasher.write(variant);
+
+		Ok(Self {
+			etag: format!("\"r-{:016x}\"", hasher.finish()),
+			last_modified,
+		})
+	}
+
+	/// Add `ETag` and `Last-Modified` to a response.
+	pub fn add_headers(&self, response: &mut HttpResponse) {
+		response.set_header("ETag", self.etag.clone());
+		if let Some(modified) = self.last_modified {
+			response.set_header("Last-Modified", http_date(modified));
+		}
+	}
+
+	/// Whether the client's cached copy is still fresh, meaning we can answer with a 304.
+	///
+	/// `If-None-Match` takes precedence over `If-Modified-Since`, as per RFC 9110 section 13.2.2.
+	pub fn not_modified(&self, request: &HttpRequest) -> bool {
+		if let Some(tags) = request.header("If-None-Match") {
+			return tags
+				.split(',')
+				.map(str::trim)
+				.any(|tag| tag == "*" || weak_eq(tag, &self.etag));
+		}
+
+		match (request.header("If-Modified-Since"), self.last_modified) {
+			(Some(since), Some(modified)) => match parse_http_date(since) {
+				// HTTP dates only have second precision.
+				Some(since) => secs(Some(modified)) <= secs(Some(since)),
+				None => false,
+			},
+			_ => false,
+		}
+	}
+}
+
+/// # content_hash
+/// Hash of the file at `path`, which is `len` bytes long and was last modified at `modified`.
+///
+/// The file is only read if it changed since the last time. A hash is only reused if the file
+/// was already older than [`MTIME_GRANULARITY`] when it was hashed, an edit right after that
+/// could leave size and mtime as they were.
+fn content_hash(path: &Path, len: u64, modified: Option<SystemTime>) -> std::io::Result<u64> {
+	let mut hashes = HASHES.lock().unwrap();
+	let hashes = hashes.get_or_insert_with(HashMap::new);
+
+	if let Some(hashed) = hashes.get(path) {
+		let settled = modified.is_some_and(|modified| {
+			hashed
+				.hashed_at
+				.duration_since(modified)
+				.unwrap_or_default()
+				>= MTIME_GRANULARITY
+		});
+		if settled && hashed.len == len && hashed.modified == modified {
+			return Ok(hashed.hash);
+		}
+	}
+
+	let hashed_at = SystemTime::now();
+	let mut hasher = Fnv1a::default();
+	hasher.write(&fs::read(path)?);
+	let hash = hasher.finish();
+
+	if hashes.len() >= MAX_HASHED {
+		hashes.clear();
+	}
+	hashes.insert(
+		path.to_path_buf(),
+		Hashed {
+			len,
+			modified,
+			hashed_at,
+			hash,
+		},
+	);
+
+	Ok(hash)
+}
+
+/// Weak comparison of two entity tags, `W/"a"` equals `"a"`.
+///
+/// Tags of compressed responses (`"a-gzip"`) are the same resource as far as freshness is
+/// concerned, so the encoding suffix is ignored too.
+pub fn weak_eq(a: &str, b: &str) -> bool {
+	base_tag(a) == base_tag(b)
+}
+
+fn base_tag(tag: &str) -> &str {
+	let tag = tag.trim_start_matches("W/");
+
+	for suffix in ["-br\"", "-gzip\"", "-deflate\""] {
+		if let Some(base) = tag.strip_suffix(suffix) {
+			// Keep the opening quote, drop the suffix and the closing one.
+			return base;
+		}
+	}
+
+	tag.strip_suffix('"').unwrap_or(tag)
+}
+
+/// Parse an HTTP date header.
+pub fn parse_http_date(date: &str) -> Option<SystemTime> {
+	chrono::DateTime::parse_from_rfc2822(date.trim())
+		.ok()
+		.map(SystemTime::from)
+}
+
+fn nanos(time: Option<SystemTime>) -> u128 {
+	time.and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+		.map_or(0, |since| since.as_nanos())
+}
+
+fn secs(time: Option<SystemTime>) -> u64 {
+	time.and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+		.map_or(0, |since| since.as_secs())
+}
+
+/// 64-bit FNV-1a.
+///
+/// `DefaultHasher` is randomly seeded per process, ETags have to survive restarts.
+struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+	fn default() -> Self {
+		Self(0xcbf29ce484222325)
+	}
+}
+
+impl Hasher for Fnv1a {
+	fn finish(&self) -> u64 {
+		self.0
+	}
+
+	fn write(&mut self, bytes: &[u8]) {
+		for byte in bytes {
+			self.0 ^= *byte as u64;
+			self.0 = self.0.wrapping_mul(0x100000001b3);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{fs, time::Duration};
+
+	use super::*;
+
+	fn request(header: &str, value: &str) -> HttpRequest {
+		HttpRequest {
+			method: mdbutler::HttpMethod::Get,
+			uri: String::from("/page"),
+			protocol_ver: String::from("HTTP/1.1"),
+			headers: vec![mdbutler::HttpHeader {
+				key: header.to_string(),
+				val: value.to_string(),
+			}],
+			content: Vec::new(),
+			peer: None,
+		}
+	}
+
+	#[test]
+	fn rendered_etags_follow_content_and_mtime() {
+		let dir = std::env::temp_dir().join(format!("mdbutler-conditional-{}", std::process::id()));
+		fs::create_dir_all(&dir).unwrap();
+		let page = dir.join("page.md");
+		let partial = dir.join("_partial.scss");
+		fs::write(&page, "# Page").unwrap();
+		fs::write(&partial, "a { b: c }").unwrap();
+		let sources = [page.clone(), partial.clone()];
+
+		let before = Validators::for_rendered(&sources, &[0]).unwrap();
+		assert_eq!(
+			before.etag,
+			Validators::for_rendered(&sources, &[0]).unwrap().etag
+		);
+		assert_ne!(
+			before.etag,
+			Validators::for_rendered(&sources, &[1]).unwrap().etag
+		);
+		assert!(before.not_modified(&request("If-None-Match", &before.etag)));
+
+		// Same content, newer mtime.
+		let later = before.last_modified.unwrap() + Duration::from_secs(5);
+		fs::File::options()
+			.write(true)
+			.open(&partial)
+			.unwrap()
+			.set_modified(later)
+			.unwrap();
+		let after = Validators::for_rendered(&sources, &[0]).unwrap();
+		assert_ne!(before.etag, after.etag);
+		assert_eq!(after.last_modified, Some(later));
+		assert!(!after.not_modified(&request("If-None-Match", &before.etag)));
+		assert!(after.not_modified(&request("If-Modified-Since", &http_date(later))));
+
+		// Same size and mtime, different content.
+		fs::write(&partial, "a { b: d }").unwrap();
+		fs::File::options()
+			.write(true)
+			.open(&partial)
+			.unwrap()
+			.set_modified(later)
+			.unwrap();
+		let edited = Validators::for_rendered(&sources, &[0]).unwrap();
+		assert_eq!(edited.last_modified, after.last_modified);
+		assert_ne!(edited.etag, after.etag);
+
+		let _ = fs::remove_dir_all(&dir);
+	}
+}
@@ -0,0 +1,84 @@
+use std::fs;
+
+use mdbutler::error;
+
+use crate::config::Site;
+use crate::convert::markdown;
+
+use super::path;
+
+/// # custom
+/// Render the site's own page for `status`, if it has one.
+///
+/// Starting at the directory of `url` and walking up to the root, the first `{status}.md` or
+/// `{status}.html` wins. Both may use `{{status}}`, `{{reason}}` and `{{url}}`, which are filled
+/// in (HTML escaped) after markdown is rendered; so a request URL can never turn into markup.
+pub fn custom(status: u16, url: &str, site: &Site, pretty: bool) -> Option<Vec<u8>> {
+	// Requests that failed to normalize are looked up from the root.
+	let normalized = path::normalize(url).unwrap_or_else(|_| String::from("/"));
+	let reason = mdbutler::status_text(status.into());
+	// Styled like the page that was asked for, or like the built-in error pages.
+	let css_path = super::stylesheet(&normalized, site).unwrap_or_else(|| site.css_path.clone());
+
+	let mut dir = match normalized.rfind('/') {
+		Some(pos) => &normalized[..=pos],
+		None => "/",
+	};
+
+	loop {
+		for extension in ["md", "html"] {
+			let candidate = format!("{dir}{status}.{extension}");
+			let Ok(page) = path::resolve(&site.root, &candidate, site.follow_symlinks) else {
+				continue;
+			};
+			let Ok(source) = fs::read_to_string(&page) else {
+				continue;
+			};
+
+			let page = match extension {
+				"md" => match markdown::convert_wiki_str(&source, Some(&css_path)) {
+					Ok(html) if pretty => html.pretty().to_string(),
+					Ok(html) => html.to_string(),
+					Err(why) => {
+						error!("Failed to render `{}`: {why}", page.display());
+						continue;
+					}
+				},
+				_ => source,
+			};
+
+			return Some(substitute(&page, status, reason, url).into_bytes());
+		}
+
+		if dir == "/" {
+			return None;
+		}
+		// Up one level, `/a/b/` becomes `/a/`.
+		let parent = dir[..dir.len() - 1].rfind('/').unwrap_or(0);
+		dir = &dir[..=parent];
+	}
+}
+
+fn substitute(page: &str, status: u16, reason: &str, url: &str) -> String {
+	page.replace("{{status}}", &status.to_string())
+		.replace("{{reason}}", &escape(reason))
+		.replace("{{url}}", &escape(url))
+}
+
+/// Escape text for HTML, both content and attribute values.
+fn escape(text: &str) -> String {
+	let mut escaped = String::with_capacity(text.len());
+
+	for c in text.chars() {
+		match c {
+			'&' => escaped.push_str("&amp;"),
+			'<' => escaped.push_str("&lt;"),
+			'>' => escaped.push_str("&gt;"),
+			'"' => escaped.push_str("&quot;"),
+			'\'' => escaped.push_str("&#39;"),
+			c => escaped.push(c),
+		}
+	}
+
+	escaped
+}
@@ -0,0 +1,201 @@
+use std::{
+	collections::{HashMap, VecDeque},
+	fs,
+	path::{Path, PathBuf},
+	sync::{Condvar, Mutex},
+	thread,
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use mdbutler::{info, HttpResponse};
+
+/// URL the injected script listens on.
+pub const ENDPOINT: &str = "/_mdbutler/livereload";
+
+/// How often the watcher rescans the site roots.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How long an event request is held open before we let the client reconnect.
+const KEEPALIVE: Duration = Duration::from_secs(25);
+
+/// How many changes we remember for clients that were reconnecting while they happened.
+const HISTORY: usize = 64;
+
+/// Injected right before `</body>` of rendered pages.
+const SCRIPT: &str = r#"<script>
+(() => {
+	const source = new EventSource("/_mdbutler/livereload");
+	source.addEventListener("change", (event) => {
+		if (/\.(css|scss|sass)$/.test(event.data)) {
+			for (const link of document.querySelectorAll('link[rel="stylesheet"]')) {
+				const url = new URL(link.href);
+				url.searchParams.set("livereload", Date.now());
+				link.href = url.href;
+			}
+		} else {
+			location.reload();
+		}
+	});
+	source.addEventListener("reload", () => location.reload());
+})();
+</script>"#;
+
+struct State {
+	/// Id of the newest change.
+	generation: u64,
+	/// Recent changes as `(generation, root, url)`.
+	history: VecDeque<(u64, String, String)>,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+	generation: 0,
+	history: VecDeque::new(),
+});
+static CHANGED: Condvar = Condvar::new();
+
+/// # watch
+/// Start watching the site roots for changes in a background thread.
+///
+/// This polls mtimes instead of using inotify & co, that is plenty for a development server and
+/// doesn't need any platform specific code.
+pub fn watch(roots: Vec<String>) {
+	// Start counting from the current time so a client that survived a restart of the server
+	// (and sends a `Last-Event-ID` from before it) can tell that something is off.
+	STATE.lock().unwrap().generation = SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map_or(0, |since| since.as_millis() as u64);
+
+	thread::spawn(move || {
+		let mut snapshots: Vec<HashMap<PathBuf, SystemTime>> =
+			roots.iter().map(|root| scan(Path::new(root))).collect();
+
+		loop {
+			thread::sleep(POLL_INTERVAL);
+
+			for (root, snapshot) in roots.iter().zip(snapshots.iter_mut()) {
+				let current = scan(Path::new(root));
+
+				let mut changed: Vec<&PathBuf> = current
+					.iter()
+					.filter(|(path, mtime)| snapshot.get(*path) != Some(*mtime))
+					.map(|(path, _)| path)
+					.collect();
+				changed.extend(snapshot.keys().filter(|path| !current.contains_key(*path)));
+
+				if !changed.is_empty() {
+					let mut state = STATE.lock().unwrap();
+					for path in changed {
+						let url = match path.strip_prefix(root) {
+							Ok(relative) => format!("/{}", relative.to_string_lossy()),
+							Err(_) => continue,
+						};
+						info!("Changed: {url}");
+
+						state.generation += 1;
+						let generation = state.generation;
+						state.history.push_back((generation, root.clone(), url));
+						if state.history.len() > HISTORY {
+							state.history.pop_front();
+						}
+					}
+					CHANGED.notify_all();
+				}
+
+				*snapshot = current;
+			}
+		}
+	});
+}
+
+/// Collect the mtimes of every file below `root`, skipping hidden directories and the usual
+/// dependency/build directories.
+fn scan(root: &Path) -> HashMap<PathBuf, SystemTime> {
+	let mut files = HashMap::new();
+	let mut dirs = vec![root.to_path_buf()];
+
+	while let Some(dir) = dirs.pop() {
+		let Ok(entries) = fs::read_dir(&dir) else {
+			continue;
+		};
+
+		for entry in entries.filter_map(|entry| entry.ok()) {
+			let name = entry.file_name();
+			let name = name.to_string_lossy();
+			let Ok(meta) = entry.metadata() else {
+				continue;
+			};
+
+			if meta.is_dir() {
+				if !name.starts_with('.') && name != "node_modules" && name != "target" {
+					dirs.push(entry.path());
+				}
+			} else if let Ok(modified) = meta.modified() {
+				files.insert(entry.path(), modified);
+			}
+		}
+	}
+
+	files
+}
+
+/// # events
+/// Answer a request to [`ENDPOINT`] for a page served from `root`.
+///
+/// The connection is closed after every batch of events, which keeps the backends simple; the
+/// browser's `EventSource` reconnects on its own and tells us the last id it saw through
+/// `Last-Event-ID`, so nothing gets lost in between. A request without one just learns the
+/// current id.
+pub fn events(root: &str, last_event_id: Option<&str>) -> HttpResponse {
+	let response = HttpResponse::builder(200)
+		.content_type("text/event-stream")
+		.cache_control("no-cache");
+
+	let state = STATE.lock().unwrap();
+	let Some(last) = last_event_id.and_then(|id| id.trim().parse::<u64>().ok()) else {
+		let body = format!("retry: 500\nid: {}\n\n", state.generation);
+		return response.body(body).build();
+	};
+
+	let (state, _) = CHANGED
+		.wait_timeout_while(state, KEEPALIVE, |state| state.generation == last)
+		.unwrap();
+
+	let mut body = format!("retry: 500\nid: {}\n", state.generation);
+
+	let oldest = state
+		.history
+		.front()
+		.map_or(state.generation, |(generation, _, _)| *generation);
+	if last > state.generation || last + 1 < oldest {
+		// The server restarted or we forgot what happened since, play it safe.
+		body.push_str("event: reload\ndata: \n\n");
+	} else if state.generation > last {
+		for (_, _, url) in state
+			.history
+			.iter()
+			.filter(|(generation, changed_root, _)| *generation > last && changed_root == root)
+		{
+			body.push_str(&format!("event: change\ndata: {url}\n\n"));
+		}
+	} else {
+		// Nothing happened, keep the connection alive.
+		body.push_str(": keepalive\n\n");
+	}
+
+	response.body(body).build()
+}
+
+/// Add the live reload script to a rendered page.
+pub fn inject(html: &mut Vec<u8>) {
+	let script = SCRIPT.as_bytes();
+
+	match html
+		.windows(7)
+		.rposition(|window| window.eq_ignore_ascii_case(b"</body>"))
+	{
+		Some(pos) => {
+			html.splice(pos..pos, script.iter().copied());
+		}
+		None => html.extend_from_slice(script),
+	}
+}
@@ -0,0 +1,124 @@
+use mdbutler::HttpMethod;
+
+use crate::config::{self, MethodPolicy};
+
+/// What files (and everything else we generate ourselves) can be requested with.
+pub const DEFAULT: [HttpMethod; 3] = [HttpMethod::Get, HttpMethod::Head, HttpMethod::Options];
+
+/// Order methods are listed in for `Allow`.
+const ALL: [HttpMethod; 9] = [
+	HttpMethod::Get,
+	HttpMethod::Head,
+	HttpMethod::Post,
+	HttpMethod::Put,
+	HttpMethod::Patch,
+	HttpMethod::Delete,
+	HttpMethod::Options,
+	HttpMethod::Connect,
+	HttpMethod::Trace,
+];
+
+/// # allowed
+/// The methods the normalized `path` can be requested with.
+///
+/// The longest `[[methods]]` policy matching `path` decides. Without one, proxied routes take
+/// anything (`None`, the upstream knows best) and everything else gets [`DEFAULT`].
+pub fn allowed(path: &str, proxied: bool) -> Option<Vec<HttpMethod>> {
+	let config = config::get();
+
+	match policy_for(&config.method_policies, path) {
+		Some(policy) => Some(methods(policy)),
+		None if proxied => None,
+		None => Some(DEFAULT.to_vec()),
+	}
+}
+
+/// Format `methods` for an `Allow` header.
+pub fn allow_header(methods: &[HttpMethod]) -> String {
+	ALL.iter()
+		.filter(|method| methods.contains(method))
+		.map(HttpMethod::as_str)
+		.collect::<Vec<_>>()
+		.join(", ")
+}
+
+/// Check that every policy only names methods we know, so typos show up on startup.
+pub fn validate(policies: &[MethodPolicy]) -> Result<(), String> {
+	for policy in policies {
+		for name in &policy.allow {
+			if HttpMethod::from(name.to_uppercase().as_str()) == HttpMethod::Unknown {
+				return Err(format!("unknown method `{name}` for `{}`", policy.prefix));
+			}
+		}
+	}
+
+	Ok(())
+}
+
+/// The longest of `policies` that covers `path`.
+fn policy_for<'a>(policies: &'a [MethodPolicy], path: &str) -> Option<&'a MethodPolicy> {
+	policies
+		.iter()
+		.filter(|policy| config::prefix_matches(&policy.prefix, path))
+		.max_by_key(|policy| policy.prefix.trim_end_matches('/').len())
+}
+
+fn methods(policy: &MethodPolicy) -> Vec<HttpMethod> {
+	let mut methods: Vec<HttpMethod> = policy
+		.allow
+		.iter()
+		.map(|name| HttpMethod::from(name.to_uppercase().as_str()))
+		.filter(|method| *method != HttpMethod::Unknown)
+		.collect();
+
+	if methods.contains(&HttpMethod::Get) {
+		methods.push(HttpMethod::Head);
+	}
+	methods.push(HttpMethod::Options);
+
+	methods
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::serve::path::normalize;
+
+	fn policy(prefix: &str, allow: &[&str]) -> MethodPolicy {
+		MethodPolicy {
+			prefix: prefix.to_string(),
+			allow: allow.iter().map(|name| name.to_string()).collect(),
+		}
+	}
+
+	#[test]
+	fn longest_prefix_wins() {
+		let policies = [policy("/api", &["GET"]), policy("/api/upload/", &["POST"])];
+
+		let found = |url: &str| {
+			policy_for(&policies, &normalize(url).unwrap()).map(|policy| &policy.prefix)
+		};
+		assert_eq!(found("/api/upload/file").unwrap(), "/api/upload/");
+		assert_eq!(found("/api/other").unwrap(), "/api");
+		assert_eq!(found("/api").unwrap(), "/api");
+		assert!(found("/apis").is_none());
+	}
+
+	#[test]
+	fn policies_match_normalized_paths() {
+		let policies = [policy("/upload", &["POST"])];
+
+		let found = |url: &str| policy_for(&policies, &normalize(url).unwrap()).is_some();
+		assert!(!found("/upload/../wiki/page"));
+		assert!(!found("/upload%2F..%2Fwiki/page"));
+		assert!(found("/./upload/file"));
+		assert!(found("//upload"));
+		assert!(found("/%75pload/file"));
+	}
+
+	#[test]
+	fn get_brings_head_and_options() {
+		let allowed = methods(&policy("/", &["get", "post", "bogus"]));
+		assert_eq!(allow_header(&allowed), "GET, HEAD, POST, OPTIONS");
+	}
+}
@@ -0,0 +1,192 @@
+use std::{collections::HashMap, path::Path, sync::OnceLock};
+
+/// Content types we know about out of the box, keyed by lowercase extension.
+const BUILTIN: &[(&str, &str)] = &[
+	// Text
+	("html", "text/html"),
+	("htm", "text/html"),
+	("css", "text/css"),
+	("js", "text/javascript"),
+	("mjs", "text/javascript"),
+	("json", "application/json"),
+	("map", "application/json"),
+	("webmanifest", "application/manifest+json"),
+	("xml", "application/xml"),
+	("rss", "application/rss+xml"),
+	("atom", "application/atom+xml"),
+	("txt", "text/plain; charset=utf-8"),
+	("csv", "text/csv; charset=utf-8"),
+	// Images
+	("png", "image/png"),
+	("jpg", "image/jpeg"),
+	("jpeg", "image/jpeg"),
+	("gif", "image/gif"),
+	("webp", "image/webp"),
+	("avif", "image/avif"),
+	("jxl", "image/jxl"),
+	("svg", "image/svg+xml"),
+	("ico", "image/x-icon"),
+	("bmp", "image/bmp"),
+	// Audio & video
+	("mp4", "video/mp4"),
+	("m4v", "video/mp4"),
+	("webm", "video/webm"),
+	("ogv", "video/ogg"),
+	("mp3", "audio/mpeg"),
+	("m4a", "audio/mp4"),
+	("ogg", "audio/ogg"),
+	("opus", "audio/ogg"),
+	("flac", "audio/flac"),
+	("wav", "audio/wav"),
+	// Fonts
+	("ttf", "font/ttf"),
+	("otf", "font/otf"),
+	("woff", "font/woff"),
+	("woff2", "font/woff2"),
+	// Everything else
+	("pdf", "application/pdf"),
+	("wasm", "application/wasm"),
+	("zip", "application/zip"),
+	("gz", "application/gzip"),
+	("tar", "application/x-tar"),
+	("xz", "application/x-xz"),
+	("zst", "application/zstd"),
+];
+
+/// Fallback for binary data we can't identify.
+pub const OCTET_STREAM: &str = "application/octet-stream";
+
+static REGISTRY: OnceLock<MimeRegistry> = OnceLock::new();
+
+/// The registry for the active configuration, built on first use.
+pub fn registry() -> &'static MimeRegistry {
+	REGISTRY.get_or_init(|| MimeRegistry::new(&crate::config::get().mime_types))
+}
+
+/// # MimeRegistry
+/// Maps file extensions to content types.
+///
+/// Starts out with a built-in table; entries from the `[mime_types]` table in the config are
+/// added on top and win over the built-in ones.
+#[derive(Debug)]
+pub struct MimeRegistry {
+	types: HashMap<String, String>,
+}
+
+impl MimeRegistry {
+	pub fn new(overrides: &HashMap<String, String>) -> Self {
+		let mut types: HashMap<String, String> = BUILTIN
+			.iter()
+			.map(|(ext, mime)| (ext.to_string(), mime.to_string()))
+			.collect();
+
+		for (ext, mime) in overrides {
+			types.insert(ext.trim_start_matches('.').to_lowercase(), mime.clone());
+		}
+
+		Self { types }
+	}
+
+	/// Content type for a path based on its extension.
+	pub fn lookup(&self, path: &Path) -> Option<&str> {
+		let ext = path.extension()?.to_str()?.to_lowercase();
+
+		self.types.get(&ext).map(String::as_str)
+	}
+}
+
+/// # sniff
+/// Guess a content type from the first few bytes of a file.
+///
+/// This only knows the formats people actually put on websites, anything it doesn't recognise is
+/// `text/plain` if it looks like UTF-8 text and `application/octet-stream` otherwise.
+pub fn sniff(content: &[u8]) -> &'static str {
+	const MAGIC: &[(&[u8], &str)] = &[
+		(b"\x89PNG\r\n\x1a\n", "image/png"),
+		(b"\xff\xd8\xff", "image/jpeg"),
+		(b"GIF87a", "image/gif"),
+		(b"GIF89a", "image/gif"),
+		(b"\xff\x0a", "image/jxl"),
+		(b"\0\0\0\x0cJXL \r\n\x87\n", "image/jxl"),
+		(b"\0\0\x01\0", "image/x-icon"),
+		(b"%PDF-", "application/pdf"),
+		(b"PK\x03\x04", "application/zip"),
+		(b"\x1f\x8b", "application/gzip"),
+		(b"\0asm", "application/wasm"),
+		(b"\x1aE\xdf\xa3", "video/webm"),
+		(b"OggS", "audio/ogg"),
+		(b"fLaC", "audio/flac"),
+		(b"ID3", "audio/mpeg"),
+		(b"wOFF", "font/woff"),
+		(b"wOF2", "font/woff2"),
+	];
+
+	for (magic, mime) in MAGIC {
+		if content.starts_with(magic) {
+			return mime;
+		}
+	}
+
+	// "BM" alone starts plenty of text files, so the reserved fields have to be zero and the
+	// DIB header one of the known sizes too.
+	if content.len() >= 18 && content.starts_with(b"BM") && content[6..10] == [0; 4] {
+		let dib_size = u32::from_le_bytes([content[14], content[15], content[16], content[17]]);
+		if matches!(dib_size, 12 | 40 | 52 | 56 | 64 | 108 | 124) {
+			return "image/bmp";
+		}
+	}
+
+	// Container formats with the interesting bit a few bytes in.
+	if content.len() >= 12 {
+		match (&content[0..4], &content[8..12]) {
+			(b"RIFF", b"WEBP") => return "image/webp",
+			(b"RIFF", b"WAVE") => return "audio/wav",
+			(_, b"avif") if &content[4..8] == b"ftyp" => return "image/avif",
+			_ if &content[4..8] == b"ftyp" => return "video/mp4",
+			_ => (),
+		}
+	}
+
+	// Only look at the start, text files can be huge.
+	let head = &content[..content.len().min(512)];
+	let text = match std::str::from_utf8(head) {
+		Ok(text) => text,
+		// We might have cut a multi-byte character in half.
+		Err(why) if why.error_len().is_none() => {
+			std::str::from_utf8(&head[..why.valid_up_to()]).unwrap_or_default()
+		}
+		Err(_) => return OCTET_STREAM,
+	};
+
+	if text.contains('\0') {
+		return OCTET_STREAM;
+	}
+
+	let start = text.trim_start().to_lowercase();
+	if start.starts_with("<!doctype html") || start.starts_with("<html") {
+		"text/html"
+	} else if start.starts_with("<svg") {
+		"image/svg+xml"
+	} else if start.starts_with("<?xml") {
+		"application/xml"
+	} else {
+		"text/plain; charset=utf-8"
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn sniffs_bitmaps_by_their_headers() {
+		let mut bmp = b"BM\x46\0\0\0\0\0\0\0\x36\0\0\0\x28\0\0\0".to_vec();
+		bmp.resize(70, 0);
+		assert_eq!(sniff(&bmp), "image/bmp");
+
+		assert_eq!(
+			sniff(b"BMW notes\n\nOil change at 30000 km."),
+			"text/plain; charset=utf-8"
+		);
+	}
+}
@@ -1,145 +1,775 @@
-use std::fs;
+use std::{
+	cell::Cell,
+	fs::File,
+	io::{self, Read, Seek, Write},
+	path::{Path, PathBuf},
+};
 
 //use html_node::Node;
-use snowboard::{headers, Headers, Request};
+use html_node::{
+	text,
+	typed::{elements::*, html},
+};
+use mdbutler::{error, Body, HttpRequest, HttpResponse, RequestError};
 
-use crate::format_error;
+use crate::config::Site;
+use crate::error::MdButlerError;
+use crate::{format_error, format_error_with_html};
 
 use crate::convert::markdown;
+#[cfg(feature = "sass")]
+use crate::convert::sass;
 
-pub fn serve_file(http_request: Request, working_dir: &str, pretty: bool) -> (u16, Headers, Vec<u8>) {
-	let mut status = 200;
-	let mut mime_type = "text/html";
+pub mod access_log;
+mod autoindex;
+mod cache;
+mod compress;
+mod conditional;
+mod error_pages;
+pub mod livereload;
+pub mod methods;
+mod mime;
+#[cfg(feature = "native-server")]
+pub mod native;
+pub mod path;
+pub mod proxy;
+mod range;
+pub mod rules;
+pub mod shutdown;
+#[cfg(feature = "snowboard")]
+pub mod snowboard;
+#[cfg(feature = "tls")]
+pub mod tls;
 
-	let content = match http_request.url {
-		_ if http_request.url.ends_with('/') => {
-			match serve_md(&(http_request.url.clone() + "index.md"), &working_dir, pretty) {
-				Ok(data) => Ok(data),
-				Err(_) => serve_html(&(http_request.url.clone() + "index.html"), &working_dir),
-			}
-		}
-		_ if http_request.url.ends_with(".md") => serve_md(&http_request.url, &working_dir, pretty),
-		_ if http_request.url.ends_with(".html") => serve_html(&http_request.url, &working_dir),
-		_ if http_request.url.ends_with(".css") => {
-			mime_type = "text/css";
-			serve_raw(&http_request.url, &working_dir)
-		}
-		#[cfg(feature = "sass")]
-		_ if http_request.url.ends_with(".scss") || http_request.url.ends_with(".sass") => {
-			mime_type = "text/css";
-			serve_scss(&http_request.url, &working_dir)
-		}
-		_ if http_request.url.ends_with(".js") => {
-			mime_type = "text/javascript";
-			serve_raw(&http_request.url, &working_dir)
+use cache::Rendered;
+use conditional::Validators;
+
+/// How much of a file is looked at to guess its content type.
+const SNIFF_LEN: u64 = 512;
+/// Static files bigger than this are sent as they are instead of compressed on the fly.
+const MAX_COMPRESS_SIZE: u64 = 8 * 1024 * 1024;
+
+/// What every backend hands its requests to.
+pub type Handler = fn(HttpRequest) -> HttpResponse;
+
+thread_local! {
+	/// Whether the request handled on this thread came in over TLS, every connection gets its
+	/// own thread.
+	static SECURE: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Mark the request on this thread as received over TLS (or not).
+#[cfg(feature = "tls")]
+pub fn set_secure(secure: bool) {
+	SECURE.with(|cell| cell.set(secure));
+}
+
+/// `https` or `http`, depending on how the current request came in.
+pub fn scheme() -> &'static str {
+	match SECURE.with(Cell::get) {
+		true => "https",
+		false => "http",
+	}
+}
+
+/// # send
+/// Write `response` to a client, without the body if it answers a `HEAD` request.
+///
+/// Connections are closed after every response. The request goes to the access log afterwards,
+/// with the body bytes that actually went out.
+pub fn send(mut response: HttpResponse, head: bool, out: &mut impl Write) -> io::Result<()> {
+	response.set_header("Connection", "close");
+
+	let (body, result) = write_response(&mut response, head, out);
+	access_log::sent(body);
+
+	result
+}
+
+/// Write `response`, returning how many body bytes were written even if it failed halfway.
+fn write_response(
+	response: &mut HttpResponse,
+	head: bool,
+	out: &mut impl Write,
+) -> (u64, io::Result<()>) {
+	let mut counted = Counted { out, written: 0 };
+	let result = match head {
+		true => response
+			.write_head_to(&mut counted)
+			.and_then(|_| counted.flush())
+			.map(|_| 0),
+		false => response.write_to(&mut counted),
+	};
+
+	match result {
+		Ok(body) => (body, Ok(())),
+		// Whatever went out after the head was the start of the body.
+		Err(why) => {
+			let mut head = Vec::new();
+			let _ = response.write_head_to(&mut head);
+			(counted.written.saturating_sub(head.len() as u64), Err(why))
 		}
-		_ if http_request.url.ends_with(".png") => {
-			mime_type = "image/png";
-			serve_raw(&http_request.url, &working_dir)
+	}
+}
+
+/// A writer that counts the bytes going through it.
+struct Counted<'a, W> {
+	out: &'a mut W,
+	written: u64,
+}
+
+impl<W: Write> Write for Counted<'_, W> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let written = self.out.write(buf)?;
+		self.written += written as u64;
+		Ok(written)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.out.flush()
+	}
+}
+
+/// The answer to a request that couldn't be read.
+pub fn bad_request(why: &RequestError) -> HttpResponse {
+	let status = why.status_code();
+	HttpResponse::builder(status)
+		.content_type("text/plain")
+		.body(format!(
+			"{status} {}: {why}\n",
+			mdbutler::status_text(status)
+		))
+		.build()
+}
+
+/// # serve_file
+/// Answer `http_request` from the files of `site`, `url` is its path as returned by
+/// [`path::normalize`].
+pub fn serve_file(http_request: HttpRequest, url: &str, site: &Site, pretty: bool) -> HttpResponse {
+	let target = match locate(url, site, crate::config::get().file.as_deref()) {
+		Ok(target) => target,
+		Err(MdButlerError::NotFound) => {
+			return serve_autoindex(&http_request, url, site, pretty)
+				.unwrap_or_else(|| error_page(404, &http_request.uri, site, pretty));
 		}
-		_ if http_request.url.ends_with(".jxl") => {
-			mime_type = "image/jxl";
-			serve_raw(&http_request.url, &working_dir)
+		Err(why) => return failure_page(&why, &http_request.uri, site, pretty),
+	};
+
+	// Rendered output is validated against the files its cached copy was rendered from, so a
+	// fresh copy on the client saves us the rendering too. Without one we only learn which files
+	// those are by rendering.
+	let dev = crate::config::get().dev;
+	let key = cache_key(&target, site, pretty);
+	let variant = match &key {
+		Some((_, pretty, stylesheet)) => {
+			let mut variant = vec![*pretty as u8, dev as u8];
+			variant.extend(stylesheet.as_deref().unwrap_or_default().bytes());
+			variant
 		}
-		_ if http_request.url.ends_with(".webp") => {
-			mime_type = "image/webp";
-			serve_raw(&http_request.url, &working_dir)
+		None => Vec::new(),
+	};
+	let mut validators = match &key {
+		Some(key) => cache::cache()
+			.dependencies(key)
+			.map(|dependencies| Validators::for_rendered(&dependencies, &variant)),
+		None => Some(Validators::for_raw(&target.path)),
+	};
+
+	let mut response = HttpResponse::default();
+	if let Some(Ok(validators)) = &validators {
+		validators.add_headers(&mut response);
+
+		if validators.not_modified(&http_request) {
+			response.status_code = 304;
+			return response;
 		}
-		_ if http_request.url.ends_with(".svg") => {
-			mime_type = "image/svg+xml";
-			serve_raw(&http_request.url, &working_dir)
+	}
+
+	let rendered = match (target.kind, key) {
+		(Kind::Markdown, Some(key)) => serve_md(&target, key).map(Some),
+		#[cfg(feature = "sass")]
+		(Kind::Scss, Some(key)) => serve_scss(&target, key).map(Some),
+		(_, _) => Ok(None),
+	};
+	let rendered = match rendered {
+		Ok(rendered) => rendered,
+		Err(why) => return failure_page(&why, &http_request.uri, site, pretty),
+	};
+
+	if let Some(rendered) = &rendered {
+		response.set_header("X-Cache", if rendered.cached { "HIT" } else { "MISS" });
+
+		// A first render is where we learn about the files it depends on (SCSS partials).
+		if !rendered.cached {
+			validators = Some(Validators::for_rendered(&rendered.dependencies, &variant));
+			if let Some(Ok(validators)) = &validators {
+				validators.add_headers(&mut response);
+
+				if validators.not_modified(&http_request) {
+					response.status_code = 304;
+					return response;
+				}
+			}
 		}
+	}
+
+	// Only static files can be served in parts, rendered output has no stable length.
+	if matches!(target.kind, Kind::Html | Kind::Raw) {
+		response.set_header("Accept-Ranges", "bytes");
+		// Browsers second-guessing the content type could run a sniffed file as something else.
+		response.set_header("X-Content-Type-Options", "nosniff");
 
-		_ if http_request.url.ends_with(".ttf") => {
-			mime_type = "application/x-font-ttf";
-			serve_raw(&http_request.url, &working_dir)
+		if let (Some(range), Some(Ok(validators))) = (http_request.header("Range"), &validators) {
+			if range::if_range_matches(&http_request, validators) {
+				match serve_ranges(&target, range, &mut response) {
+					Ok(true) => return response,
+					Ok(false) => (),
+					Err(why) => {
+						let why = MdButlerError::io(&target.path, why);
+						return failure_page(&why, &http_request.uri, site, pretty);
+					}
+				}
+			}
 		}
-		_ if http_request.url.ends_with(".woff2") => {
-			mime_type = "application/font-woff2";
-			serve_raw(&http_request.url, &working_dir)
+	}
+
+	let compression = &crate::config::get().compression;
+	let encodings = match http_request.header("Accept-Encoding") {
+		Some(accept_encoding) if compression.enabled => compress::accepted(accept_encoding),
+		_ => Vec::new(),
+	};
+
+	// Prefer a precompressed sibling from disk over compressing on the fly.
+	if matches!(target.kind, Kind::Html | Kind::Raw)
+		&& compression.precompressed
+		&& serve_precompressed(&target, &encodings, &mut response)
+	{
+		return response;
+	}
+
+	let content = match (rendered, target.kind) {
+		(Some(rendered), Kind::Markdown) => Ok((
+			String::from("text/html"),
+			Body::Bytes(rendered.content.to_vec()),
+		)),
+		#[cfg(feature = "sass")]
+		(Some(rendered), Kind::Scss) => Ok((
+			String::from("text/css"),
+			Body::Bytes(rendered.content.to_vec()),
+		)),
+		(_, _) => serve_raw(&target, !encodings.is_empty()),
+	};
+
+	let (mime_type, mut content) = match content {
+		Ok(content) => content,
+		Err(why) => return failure_page(&why, &http_request.uri, site, pretty),
+	};
+
+	// Pages rendered from markdown get the live reload script in development mode, after the
+	// cache so it never ends up in there.
+	if let (Kind::Markdown, true, Body::Bytes(content)) = (target.kind, dev, &mut content) {
+		livereload::inject(content);
+	}
+
+	if compress::compressible(compression, &mime_type) {
+		response.set_header("Vary", "Accept-Encoding");
+
+		// Files too big to compress in memory are streamed as they are.
+		if let (Some(&encoding), Body::Bytes(data)) = (encodings.first(), &content) {
+			if data.len() >= compression.min_size {
+				if let Ok(compressed) = compress::compress(data, encoding, compression.level) {
+					content = Body::Bytes(compressed);
+					set_content_encoding(&mut response, encoding);
+				}
+			}
 		}
+	}
+
+	response.set_header("Content-Type", mime_type);
+	response.content = content;
 
-		_ => match serve_md(&(http_request.url.clone() + ".md"), &working_dir, pretty) {
-			Ok(data) => Ok(data),
-			Err(_) => serve_html(&(http_request.url.clone() + ".html"), &working_dir),
+	response
+}
+
+/// Mark a response as encoded, which also makes it a different representation.
+fn set_content_encoding(response: &mut HttpResponse, encoding: compress::Encoding) {
+	response.set_header("Content-Encoding", encoding.name());
+	if let Some(etag) = response.header("ETag") {
+		let etag = compress::encoded_etag(etag, encoding);
+		response.set_header("ETag", etag);
+	}
+}
+
+/// Serve `file.br`/`file.gz` instead of `file` if the client accepts it and it is up to date.
+///
+/// Returns whether it did, `response` is left alone otherwise.
+fn serve_precompressed(
+	target: &Target,
+	encodings: &[compress::Encoding],
+	response: &mut HttpResponse,
+) -> bool {
+	// The sibling's own extension says nothing about what is inside, so we have to know the type
+	// of the original from its name.
+	let mime_type = match target.kind {
+		Kind::Html => "text/html",
+		_ => match mime::registry().lookup(&target.path) {
+			Some(mime_type) => mime_type,
+			None => return false,
 		},
 	};
 
-	let content = match content {
-		Ok(data) => data,
-		Err(_) => {
-			status = 404;
-			mime_type = "text/html";
-			format_error(
-				404,
-				"Not found",
-				"The page you are looking for has not been found.",
-				pretty,
-			)
-			.into()
-		}
+	let Some((encoding, sibling)) = compress::precompressed(&target.path, encodings) else {
+		return false;
+	};
+	let Ok((file, len)) = open(&sibling) else {
+		return false;
 	};
 
-	let headers = headers! {
-		"Content-Type" => mime_type,
+	response.set_header("Content-Type", mime_type);
+	response.set_header("Vary", "Accept-Encoding");
+	set_content_encoding(response, encoding);
+	response.content = Body::Stream {
+		reader: Box::new(file.take(len)),
+		length: Some(len),
 	};
 
-	(status, headers, content)
+	true
 }
 
-#[cfg(feature = "sass")]
-fn serve_scss(path: &str, working_dir: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-	let path = working_dir.to_string() + path;
+/// How a file is turned into a response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+	Markdown,
+	Html,
+	#[cfg(feature = "sass")]
+	Scss,
+	Raw,
+}
 
-	match grass::from_path(&path, &grass::Options::default()) {
-		Ok(css) => Ok(css.into()),
-		Err(why) => {
-			println!("Err: Failed to parse `{path}`: {why}");
-			Err(why)
+impl Kind {
+	fn from_url(url: &str) -> Self {
+		match url.rsplit_once('.').map(|(_, ext)| ext) {
+			Some("md") => Kind::Markdown,
+			Some("html") => Kind::Html,
+			#[cfg(feature = "sass")]
+			Some("scss" | "sass") => Kind::Scss,
+			_ => Kind::Raw,
 		}
 	}
 }
 
-fn serve_md(path: &str, working_dir: &str, pretty: bool) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-	let css_path = if path.starts_with("/wiki/") {
-		Some("/assets/scss/wiki/master.scss")
-	} else if path.starts_with("/read/") {
-		Some("/assets/scss/reader/master.scss")
-	} else if path == "/" || path == "/index" || path == "/index.html" || path == "/index.md" {
-		Some("/assets/scss/index.scss")
+/// A file on disk a request resolved to.
+#[derive(Debug)]
+struct Target {
+	/// URL path of the file that was found, which isn't necessarily the requested one.
+	url: String,
+	path: PathBuf,
+	kind: Kind,
+}
+
+/// # locate
+/// Find the file a normalised URL refers to.
+///
+/// Directories are served through their `index.md` or `index.html`, other URLs are tried as-is
+/// first and then as a page without its extension (`/about` -> `/about.md` -> `/about.html`).
+/// `config_file` is the active config file, which is never served.
+fn locate(url: &str, site: &Site, config_file: Option<&Path>) -> Result<Target, MdButlerError> {
+	let candidates = if url.ends_with('/') {
+		vec![url.to_string() + "index.md", url.to_string() + "index.html"]
 	} else {
-		None
+		vec![url.to_string(), url.to_string() + ".md", url.to_string() + ".html"]
 	};
 
-	let path = working_dir.to_string() + path;
+	for candidate in candidates {
+		let path = path::resolve(&site.root, &candidate, site.follow_symlinks)?;
+		if path.is_file() && !is_internal(&path, site, config_file) {
+			return Ok(Target {
+				kind: Kind::from_url(&candidate),
+				url: candidate,
+				path,
+			});
+		}
+	}
+
+	Err(MdButlerError::NotFound)
+}
+
+/// Whether `path` is one of our own files, which are read from the site root but never served.
+fn is_internal(path: &Path, site: &Site, config_file: Option<&Path>) -> bool {
+	if path == Path::new(&site.root).join(rules::FILE_NAME) {
+		return true;
+	}
+
+	match config_file {
+		Some(file) => path.canonicalize().is_ok_and(|path| path == file),
+		None => false,
+	}
+}
 
-	let html = markdown::convert_wiki(&path, css_path)?;
-	if pretty {
-		Ok(html.pretty().to_string().into_bytes())
+/// List a directory without an index, if the site enables that for it.
+fn serve_autoindex(
+	http_request: &HttpRequest,
+	url: &str,
+	site: &Site,
+	pretty: bool,
+) -> Option<HttpResponse> {
+	if !url.ends_with('/') || !autoindex::enabled(site, url) {
+		return None;
+	}
+
+	let dir = path::resolve(&site.root, url, site.follow_symlinks).ok()?;
+	if !dir.is_dir() {
+		return None;
+	}
+
+	let query = http_request.uri.split_once('?').map(|(_, query)| query);
+	match autoindex::listing(url, query, &dir, site, pretty) {
+		Ok(doc) => Some(html_response(200, doc)),
+		Err(why) => {
+			error!("Failed to list `{}`: {why}", dir.display());
+			None
+		}
+	}
+}
+
+/// A page we generated ourselves.
+fn html_response(status: u16, doc: impl Into<mdbutler::Body>) -> HttpResponse {
+	HttpResponse::builder(status.into())
+		.content_type("text/html")
+		.body(doc)
+		.build()
+}
+
+/// Build the response for an error, using the site's own page for `status` if there is one.
+pub fn error_page(status: u16, url: &str, site: &Site, pretty: bool) -> HttpResponse {
+	if let Some(doc) = error_pages::custom(status, url, site, pretty) {
+		return html_response(status, doc);
+	}
+
+	let (err_desc, err_details) = describe(status);
+	let doc = format_error(status.into(), err_desc, err_details, &site.css_path, pretty);
+
+	html_response(status, doc)
+}
+
+/// Title and explanation of the built-in page for `status`.
+fn describe(status: u16) -> (&'static str, &'static str) {
+	match status {
+		400 => ("Bad request", "The server could not understand your request."),
+		403 => ("Forbidden", "You are not allowed to access this page."),
+		405 => ("Method not allowed", "This page can't be requested like that."),
+		410 => ("Gone", "This page has been removed."),
+		500 => ("Internal server error", "Something went wrong while preparing this page."),
+		501 => ("Not implemented", "The server does not support this request method."),
+		502 => ("Bad gateway", "The service behind this page could not be reached."),
+		503 => ("Service unavailable", "The server is too busy right now, try again in a moment."),
+		504 => ("Gateway timeout", "The service behind this page took too long to answer."),
+		_ => ("Not found", "The page you are looking for has not been found."),
+	}
+}
+
+/// # failure_page
+/// Build the response for a file that couldn't be served, with the status `why` calls for.
+///
+/// In dev mode the page says what went wrong and where, rather than showing the site's own page.
+fn failure_page(why: &MdButlerError, url: &str, site: &Site, pretty: bool) -> HttpResponse {
+	let status = why.status();
+	if status >= 500 {
+		error!("Failed to serve `{url}`: {why}");
+	}
+
+	let mut response = match crate::config::get().dev {
+		true if !matches!(why, MdButlerError::NotFound) => dev_failure_page(why, site, pretty),
+		_ => error_page(status, url, site, pretty),
+	};
+	if matches!(why, MdButlerError::Unavailable(_)) {
+		response.set_header("Retry-After", "5");
+	}
+
+	response
+}
+
+/// The error page for dev mode, with the details of what went wrong.
+fn dev_failure_page(why: &MdButlerError, site: &Site, pretty: bool) -> HttpResponse {
+	let status = why.status();
+	let location = match why.location() {
+		Some(location) => html!(<p><code>{text!("{location}")}</code></p>),
+		None => html!(<>),
+	};
+	let details = html!(
+		{location}
+		<pre>{text!("{why}")}</pre>
+	);
+	let doc = format_error_with_html(status.into(), describe(status).0, details, &site.css_path, pretty);
+
+	html_response(status, doc)
+}
+
+#[cfg(feature = "sass")]
+fn serve_scss(target: &Target, key: cache::Key) -> Result<Rendered, MdButlerError> {
+	cache::cache().get_or_render(key, || {
+		let (css, dependencies) = sass::convert_with_dependencies(&target.path)?;
+		Ok((css.into_bytes(), dependencies))
+	})
+}
+
+/// The render cache key for `target`, `None` if it is served as-is.
+fn cache_key(target: &Target, site: &Site, pretty: bool) -> Option<cache::Key> {
+	match target.kind {
+		Kind::Markdown => Some((target.path.clone(), pretty, stylesheet(&target.url, site))),
+		#[cfg(feature = "sass")]
+		Kind::Scss => Some((target.path.clone(), false, None)),
+		Kind::Html | Kind::Raw => None,
+	}
+}
+
+/// The stylesheet a page at `url` links to, if any.
+fn stylesheet(url: &str, site: &Site) -> Option<String> {
+	let asset_path = &site.asset_path;
+	if url.starts_with("/wiki/") {
+		Some(format!("{asset_path}/scss/wiki/master.scss"))
+	} else if url.starts_with("/read/") {
+		Some(format!("{asset_path}/scss/reader/master.scss"))
+	} else if url == "/" || url == "/index" || url == "/index.html" || url == "/index.md" {
+		Some(format!("{asset_path}/scss/index.scss"))
 	} else {
-		Ok(html.to_string().into_bytes())
+		None
 	}
 }
 
-fn serve_html(path: &str, working_dir: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-	let path = working_dir.to_string() + path;
-	//println!("{path}");
-	Ok(fs::read(path)?)
+fn serve_md(target: &Target, key: cache::Key) -> Result<Rendered, MdButlerError> {
+	let (_, pretty, css_path) = key.clone();
+
+	cache::cache().get_or_render(key, || {
+		let html = markdown::convert_wiki(&target.path.to_string_lossy(), css_path.as_deref())?;
+		let html = if pretty {
+			html.pretty().to_string()
+		} else {
+			html.to_string()
+		};
+
+		Ok((html.into_bytes(), vec![target.path.clone()]))
+	})
 }
 
-fn serve_raw(path: &str, working_dir: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-	let path = working_dir.to_string() + path;
+/// Open a file along with its current length.
+fn open(path: &Path) -> io::Result<(File, u64)> {
+	let file = File::open(path)?;
+	let len = file.metadata()?.len();
 
-	Ok(fs::read(path)?)
+	Ok((file, len))
 }
 
-#[derive(Debug)]
-struct NotFoundError;
+/// Content type of a static file, sniffed from its first bytes if the extension doesn't say.
+fn content_type(target: &Target, file: &mut File) -> io::Result<String> {
+	if target.kind == Kind::Html {
+		return Ok(String::from("text/html"));
+	}
+	if let Some(mime_type) = mime::registry().lookup(&target.path) {
+		return Ok(mime_type.to_string());
+	}
+
+	let mut head = Vec::new();
+	(&mut *file).take(SNIFF_LEN).read_to_end(&mut head)?;
+	file.rewind()?;
+
+	Ok(mime::sniff(&head).to_string())
+}
 
-impl std::fmt::Display for NotFoundError {
-	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-		write!(f, "File not found!")
+/// # serve_raw
+/// Serve a file as-is, returning its content type along with the body.
+///
+/// The file is streamed from disk, unless it may be compressed on the fly (`may_compress`) and is
+/// small enough to do that in memory.
+fn serve_raw(target: &Target, may_compress: bool) -> Result<(String, Body), MdButlerError> {
+	let (mut file, len) = open(&target.path).map_err(|why| MdButlerError::io(&target.path, why))?;
+	let mime_type =
+		content_type(target, &mut file).map_err(|why| MdButlerError::io(&target.path, why))?;
+
+	if may_compress
+		&& len <= MAX_COMPRESS_SIZE
+		&& compress::compressible(&crate::config::get().compression, &mime_type)
+	{
+		let mut content = Vec::with_capacity(len as usize);
+		file.read_to_end(&mut content)
+			.map_err(|why| MdButlerError::io(&target.path, why))?;
+		return Ok((mime_type, Body::Bytes(content)));
 	}
+
+	let body = Body::Stream {
+		reader: Box::new(file.take(len)),
+		length: Some(len),
+	};
+	Ok((mime_type, body))
+}
+
+/// Serve the parts of a static file asked for in a `Range` header.
+///
+/// Returns whether it did, if not the header should be ignored and the full file sent instead.
+fn serve_ranges(target: &Target, range: &str, response: &mut HttpResponse) -> io::Result<bool> {
+	let (mut file, len) = open(&target.path)?;
+
+	let ranges = match range::parse(range, len) {
+		Some(Ok(ranges)) => ranges,
+		Some(Err(range::Unsatisfiable)) => {
+			response.status_code = 416;
+			range::unsatisfiable(response, len);
+			return Ok(true);
+		}
+		None => return Ok(false),
+	};
+
+	let mime_type = content_type(target, &mut file)?;
+	range::partial_response(response, &mime_type, len, &ranges, file);
+
+	Ok(true)
 }
 
-impl std::error::Error for NotFoundError {}
+#[cfg(test)]
+mod tests {
+	use std::fs;
+
+	use super::*;
+	use crate::config::SymlinkPolicy;
+
+	/// A site with a page, our own files and some dotfiles in it.
+	fn site(name: &str) -> (PathBuf, Site) {
+		let dir =
+			std::env::temp_dir().join(format!("mdbutler-serve-{}-{name}", std::process::id()));
+		let _ = fs::remove_dir_all(&dir);
+		fs::create_dir_all(dir.join(".git")).unwrap();
+		fs::create_dir_all(dir.join(".well-known")).unwrap();
+		fs::write(dir.join("page.md"), "# Page").unwrap();
+		fs::write(dir.join(crate::config::FILE_NAME), "port = 8080").unwrap();
+		fs::write(dir.join(rules::FILE_NAME), "/old /new").unwrap();
+		fs::write(dir.join(".env"), "SECRET=1").unwrap();
+		fs::write(dir.join(".git/config"), "[core]").unwrap();
+		fs::write(dir.join(".well-known/security.txt"), "Contact: me").unwrap();
+
+		let site = Site {
+			root: dir.to_string_lossy().into_owned(),
+			asset_path: String::from("/assets"),
+			css_path: String::from("/assets/css/master.css"),
+			pretty: false,
+			follow_symlinks: SymlinkPolicy::WithinRoot,
+			autoindex: Vec::new(),
+		};
+		(dir, site)
+	}
+
+	/// The site's config file, as `config::get().file` would have it.
+	fn config_file(site: &Site) -> PathBuf {
+		Path::new(&site.root)
+			.join(crate::config::FILE_NAME)
+			.canonicalize()
+			.unwrap()
+	}
+
+	fn assert_hidden(url: &str, site: &Site) {
+		let config_file = config_file(site);
+		assert!(
+			matches!(
+				locate(url, site, Some(&config_file)),
+				Err(MdButlerError::NotFound)
+			),
+			"`{url}` should not be found"
+		);
+	}
+
+	/// Takes `left` bytes, then fails like a client that hung up.
+	struct HangUp {
+		left: usize,
+	}
+
+	impl Write for HangUp {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+			match self.left {
+				0 => Err(io::ErrorKind::BrokenPipe.into()),
+				left => {
+					self.left -= left.min(buf.len());
+					Ok(left.min(buf.len()))
+				}
+			}
+		}
+
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn only_body_bytes_sent_are_counted() {
+		let response = |status| HttpResponse::builder(status).body("0123456789").build();
+		let mut head = Vec::new();
+		response(200).write_head_to(&mut head).unwrap();
+
+		let (body, result) = write_response(&mut response(200), false, &mut Vec::new());
+		assert_eq!((body, result.is_ok()), (10, true));
+		let (body, _) = write_response(&mut response(200), true, &mut Vec::new());
+		assert_eq!(body, 0);
+		let (body, _) = write_response(&mut response(304), false, &mut Vec::new());
+		assert_eq!(body, 0);
+
+		let mut out = HangUp {
+			left: head.len() + 4,
+		};
+		let (body, result) = write_response(&mut response(200), false, &mut out);
+		assert_eq!((body, result.is_err()), (4, true));
+	}
+
+	#[test]
+	fn locate_hides_internal_files() {
+		let (dir, site) = site("internal");
+
+		let config_file = config_file(&site);
+		let locate = |url| locate(url, &site, Some(&config_file));
+
+		assert_eq!(locate("/page").unwrap().url, "/page.md");
+		assert_eq!(locate("/.well-known/security.txt").unwrap().kind, Kind::Raw);
+		// Only hidden as the active config file.
+		assert!(super::locate("/mdbutler.toml", &site, None).is_ok());
+
+		assert_hidden("/mdbutler.toml", &site);
+		assert_hidden("/_redirects", &site);
+		assert_hidden("/.git/config", &site);
+		assert_hidden("/.env", &site);
+		assert_hidden("/.git/", &site);
+
+		fs::remove_dir_all(dir).unwrap();
+	}
+
+	#[test]
+	fn rendered_pages_are_cached_per_stylesheet() {
+		let (dir, site) = site("stylesheet");
+		fs::write(dir.join("index.md"), "# Home").unwrap();
+		let other = Site {
+			asset_path: String::from("/static"),
+			..site.clone()
+		};
+
+		let target = locate("/index", &site, None).unwrap();
+		let key = cache_key(&target, &site, false).unwrap();
+		let other_key = cache_key(&target, &other, false).unwrap();
+		assert_ne!(key, other_key);
+
+		let page = serve_md(&target, key).unwrap();
+		let other_page = serve_md(&target, other_key).unwrap();
+		assert!(String::from_utf8_lossy(&page.content).contains("/assets/scss/index.scss"));
+		assert!(String::from_utf8_lossy(&other_page.content).contains("/static/scss/index.scss"));
+
+		fs::remove_dir_all(dir).unwrap();
+	}
+
+	#[test]
+	fn custom_error_pages_are_styled() {
+		let (dir, site) = site("error-pages");
+		fs::write(dir.join("404.md"), "# {{reason}}").unwrap();
+
+		let page = error_pages::custom(404, "/missing", &site, false).unwrap();
+		assert!(String::from_utf8_lossy(&page).contains("/assets/css/master.css"));
+		let page = error_pages::custom(404, "/wiki/missing", &site, false).unwrap();
+		assert!(String::from_utf8_lossy(&page).contains("/assets/scss/wiki/master.scss"));
+
+		fs::remove_dir_all(dir).unwrap();
+	}
+}
@@ -0,0 +1,91 @@
+use std::{
+	io,
+	net::{SocketAddr, TcpListener, TcpStream},
+	thread,
+	time::Duration,
+};
+
+use mdbutler::{
+	error, warn, HttpMethod, HttpRequest, HttpResponse, RequestError, RequestLimits, ThreadPool,
+};
+
+use super::Handler;
+use crate::config::PoolConfig;
+
+/// How long a client gets to send its request.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// # run
+/// Accept connections on `listener` until shutdown, handing each to a worker of our own
+/// [`ThreadPool`]. Returns once the workers finished what they were doing.
+///
+/// Connections beyond what the pool's queue holds are handled as `pool` says; rejected ones get
+/// a 503 right from the accept loop, written without waiting on the client.
+///
+/// Requests are read and answered with the library's [`HttpRequest`] and [`HttpResponse`].
+/// Without `threads` the pool gets four workers per CPU; keep in mind every page open in dev
+/// mode holds one while it long-polls.
+pub fn run(listener: TcpListener, threads: Option<usize>, pool: &PoolConfig, handler: Handler) {
+	let threads = threads
+		.filter(|threads| *threads > 0)
+		.unwrap_or_else(|| thread::available_parallelism().map_or(4, |cpus| cpus.get() * 4));
+	let pool = ThreadPool::with_queue(threads, pool.queue_size.max(1), pool.overflow);
+
+	loop {
+		let (stream, ip) = match listener.accept() {
+			Ok(accepted) => accepted,
+			Err(why) => {
+				error!("Failed to accept connection: {why}");
+				continue;
+			}
+		};
+
+		let in_flight = super::shutdown::track();
+		if super::shutdown::requested() {
+			break;
+		}
+
+		// Kept to answer on if the pool turns the connection away.
+		let overflow = stream.try_clone();
+		let job = pool.execute(move || {
+			let _in_flight = in_flight;
+			if let Err(why) = handle(stream, ip, handler) {
+				warn!("Connection from {ip}: {why}");
+			}
+		});
+
+		if let (Err(why), Ok(mut stream)) = (job, overflow) {
+			warn!("Turning away {ip}: {why} ({:?})", pool.stats());
+			// The answer fits in the socket's buffer, a client that doesn't take it must not hold
+			// up the accept loop.
+			let _ = stream.set_nonblocking(true);
+			let _ = HttpResponse::builder(503)
+				.header("Connection", "close")
+				.retry_after(1)
+				.content_type("text/plain")
+				.body("Too busy, try again in a moment\n")
+				.build()
+				.write_to(&mut stream);
+		}
+	}
+
+	drop(pool);
+}
+
+fn handle(mut stream: TcpStream, ip: SocketAddr, handler: Handler) -> io::Result<()> {
+	stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+	stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+
+	let (response, head) = match HttpRequest::read_from(&mut stream, &RequestLimits::default()) {
+		Ok(mut request) => {
+			request.peer = Some(ip);
+			let head = request.method == HttpMethod::Head;
+			(handler(request), head)
+		}
+		// The client hung up before sending anything, nobody left to answer.
+		Err(RequestError::Io(why)) if why.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+		Err(why) => (super::bad_request(&why), false),
+	};
+
+	super::send(response, head, &mut stream)
+}
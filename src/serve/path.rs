@@ -0,0 +1,389 @@
+use std::path::{Component, Path, PathBuf};
+
+use crate::config::SymlinkPolicy;
+use crate::error::MdButlerError;
+
+/// The one dot-directory that is meant to be public (RFC 8615).
+const WELL_KNOWN: &str = ".well-known";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ResolveError {
+	/// The URL is not valid (bad percent encoding, control characters, ...).
+	BadRequest,
+	/// The URL points outside of the document root.
+	Forbidden,
+	/// The URL points at a hidden file or directory, which we pretend isn't there.
+	Hidden,
+}
+
+impl ResolveError {
+	pub fn status(&self) -> u16 {
+		match self {
+			Self::BadRequest => 400,
+			Self::Forbidden => 403,
+			Self::Hidden => 404,
+		}
+	}
+}
+
+impl std::fmt::Display for ResolveError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			Self::BadRequest => write!(f, "Malformed request path"),
+			Self::Forbidden => write!(f, "Path is outside of the document root"),
+			Self::Hidden => write!(f, "Path is hidden"),
+		}
+	}
+}
+
+impl std::error::Error for ResolveError {}
+
+impl From<ResolveError> for MdButlerError {
+	fn from(why: ResolveError) -> Self {
+		match why {
+			ResolveError::Forbidden => Self::Forbidden(why.to_string()),
+			// Nothing can live at a path that doesn't even parse.
+			ResolveError::BadRequest | ResolveError::Hidden => Self::NotFound,
+		}
+	}
+}
+
+/// # normalize
+/// Turn a raw request URL into a canonical URL path.
+///
+/// The query string and fragment are dropped, the path is percent-decoded and `.`, `..` and empty
+/// segments are collapsed. The result always starts with a `/` and keeps a trailing `/` if the
+/// request had one, so `/wiki/./../wiki//page%20one` becomes `/wiki/page one`.
+///
+/// Any `..` that would climb above the root is rejected instead of being clamped, there is no
+/// legitimate reason for a client to send one.
+pub fn normalize(url: &str) -> Result<String, ResolveError> {
+	let path = url.split(['?', '#']).next().unwrap_or_default();
+	let path = percent_decode(path)?;
+
+	// Control characters (line breaks in particular) have no business in a path, and would end up
+	// in headers like `Location`.
+	if !path.starts_with('/') || path.contains(|c: char| c == '\\' || c.is_ascii_control()) {
+		return Err(ResolveError::BadRequest);
+	}
+
+	let mut segments: Vec<&str> = Vec::new();
+	for segment in path.split('/') {
+		match segment {
+			"" | "." => (),
+			".." => {
+				if segments.pop().is_none() {
+					return Err(ResolveError::Forbidden);
+				}
+			}
+			_ => segments.push(segment),
+		}
+	}
+
+	let mut normalized = String::with_capacity(path.len());
+	for segment in &segments {
+		normalized.push('/');
+		normalized.push_str(segment);
+	}
+
+	let is_dir = path.ends_with('/') || path.ends_with("/.") || path.ends_with("/..");
+	if is_dir || normalized.is_empty() {
+		normalized.push('/');
+	}
+
+	Ok(normalized)
+}
+
+/// # resolve
+/// Map a path returned by [`normalize`] to a file below `root`.
+///
+/// The file doesn't need to exist; if it does, `policy` decides whether symlinks on the way to it
+/// are acceptable. Dotfiles and anything below a dot-directory (`.git`, `.env`) are hidden, except
+/// for `/.well-known/`.
+pub fn resolve(root: &str, path: &str, policy: SymlinkPolicy) -> Result<PathBuf, ResolveError> {
+	let root = Path::new(root);
+	let relative = Path::new(path.trim_start_matches('/'));
+
+	// `normalize` already takes care of this, but we don't want to rely on every caller using it.
+	if !relative
+		.components()
+		.all(|component| matches!(component, Component::Normal(_)))
+	{
+		return Err(ResolveError::Forbidden);
+	}
+	let hidden = |segment: &str| segment.starts_with('.') && segment != WELL_KNOWN;
+	if path.split('/').any(hidden) {
+		return Err(ResolveError::Hidden);
+	}
+
+	let full = root.join(relative);
+
+	match policy {
+		SymlinkPolicy::Follow => (),
+		SymlinkPolicy::WithinRoot => {
+			if let (Ok(root), Ok(target)) = (root.canonicalize(), full.canonicalize()) {
+				if !target.starts_with(root) {
+					return Err(ResolveError::Forbidden);
+				}
+			}
+		}
+		SymlinkPolicy::Never => {
+			let mut current = root.to_path_buf();
+			for component in relative.components() {
+				current.push(component);
+				match current.symlink_metadata() {
+					Ok(meta) if meta.file_type().is_symlink() => {
+						return Err(ResolveError::Forbidden)
+					}
+					Ok(_) => (),
+					// Nothing below a missing component can exist either.
+					Err(_) => break,
+				}
+			}
+		}
+	}
+
+	Ok(full)
+}
+
+/// The query string of a raw request URL, without the `?`.
+pub fn query(url: &str) -> Option<&str> {
+	let url = url.split('#').next().unwrap_or_default();
+	url.split_once('?').map(|(_, query)| query)
+}
+
+/// # encode
+/// Percent-encode a path returned by [`normalize`] so it can be sent on as a URL again.
+///
+/// Everything but unreserved characters, sub-delimiters, `:`, `@` and the `/` between segments
+/// is escaped (RFC 3986, section 3.3).
+pub fn encode(path: &str) -> String {
+	let mut encoded = String::with_capacity(path.len());
+
+	for byte in path.bytes() {
+		match byte {
+			b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+				encoded.push(byte as char)
+			}
+			b'!' | b'$' | b'&' | b'\'' | b'(' | b')' | b'*' | b'+' | b',' | b';' | b'=' => {
+				encoded.push(byte as char)
+			}
+			b':' | b'@' | b'/' => encoded.push(byte as char),
+			_ => encoded.push_str(&format!("%{byte:02X}")),
+		}
+	}
+
+	encoded
+}
+
+/// Decode `%XX` escapes, the result has to be valid UTF-8.
+fn percent_decode(input: &str) -> Result<String, ResolveError> {
+	let bytes = input.as_bytes();
+	let mut decoded = Vec::with_capacity(bytes.len());
+
+	let mut i = 0;
+	while i < bytes.len() {
+		if bytes[i] == b'%' {
+			let hex = match bytes.get(i + 1..i + 3) {
+				Some(hex) if hex.iter().all(u8::is_ascii_hexdigit) => hex,
+				_ => return Err(ResolveError::BadRequest),
+			};
+			// Only hex digits left, so neither of these can fail.
+			let hex = std::str::from_utf8(hex).map_err(|_| ResolveError::BadRequest)?;
+			let byte = u8::from_str_radix(hex, 16).map_err(|_| ResolveError::BadRequest)?;
+			decoded.push(byte);
+			i += 3;
+		} else {
+			decoded.push(bytes[i]);
+			i += 1;
+		}
+	}
+
+	String::from_utf8(decoded).map_err(|_| ResolveError::BadRequest)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// A fresh directory with `root/page.md`, `outside/secret.md` and symlinks to both.
+	fn site(name: &str) -> (PathBuf, String) {
+		let dir = std::env::temp_dir().join(format!("mdbutler-path-{}-{name}", std::process::id()));
+		let _ = std::fs::remove_dir_all(&dir);
+		std::fs::create_dir_all(dir.join("root/docs")).unwrap();
+		std::fs::create_dir_all(dir.join("outside")).unwrap();
+		std::fs::write(dir.join("root/page.md"), "page").unwrap();
+		std::fs::write(dir.join("outside/secret.md"), "secret").unwrap();
+
+		#[cfg(unix)]
+		{
+			use std::os::unix::fs::symlink;
+			symlink(dir.join("root/page.md"), dir.join("root/inside.md")).unwrap();
+			symlink(dir.join("outside/secret.md"), dir.join("root/escape.md")).unwrap();
+			symlink(dir.join("outside"), dir.join("root/docs/linked")).unwrap();
+		}
+
+		let root = dir.join("root").to_string_lossy().into_owned();
+		(dir, root)
+	}
+
+	#[test]
+	fn normalize_collapses_segments() {
+		assert_eq!(
+			normalize("/wiki/./../wiki//page%20one").unwrap(),
+			"/wiki/page one"
+		);
+		assert_eq!(normalize("/wiki/?q=1#top").unwrap(), "/wiki/");
+		assert_eq!(normalize("/wiki/page/..").unwrap(), "/wiki/");
+		assert_eq!(normalize("/").unwrap(), "/");
+		assert_eq!(normalize("/.").unwrap(), "/");
+	}
+
+	#[test]
+	fn normalize_rejects_climbing_above_root() {
+		assert_eq!(normalize("/.."), Err(ResolveError::Forbidden));
+		assert_eq!(
+			normalize("/wiki/../../etc/passwd"),
+			Err(ResolveError::Forbidden)
+		);
+		assert_eq!(
+			normalize("/%2e%2e/etc/passwd"),
+			Err(ResolveError::Forbidden)
+		);
+		assert_eq!(
+			normalize("/wiki/%2E%2E/%2e%2e/etc"),
+			Err(ResolveError::Forbidden)
+		);
+	}
+
+	#[test]
+	fn normalize_decodes_encoded_slashes() {
+		assert_eq!(normalize("/wiki%2fpage").unwrap(), "/wiki/page");
+		assert_eq!(
+			normalize("/wiki%2F..%2F..%2Fetc"),
+			Err(ResolveError::Forbidden)
+		);
+	}
+
+	#[test]
+	fn normalize_rejects_bad_input() {
+		assert_eq!(normalize("/page%00.md"), Err(ResolveError::BadRequest));
+		assert_eq!(normalize("/page\0.md"), Err(ResolveError::BadRequest));
+		assert_eq!(normalize("/page%0d%0a.md"), Err(ResolveError::BadRequest));
+		assert_eq!(normalize("/page%7F.md"), Err(ResolveError::BadRequest));
+		assert_eq!(normalize("/..\\..\\etc"), Err(ResolveError::BadRequest));
+		assert_eq!(normalize("/..%5C..%5Cetc"), Err(ResolveError::BadRequest));
+		assert_eq!(normalize("/page%zz"), Err(ResolveError::BadRequest));
+		assert_eq!(normalize("/page%2"), Err(ResolveError::BadRequest));
+		assert_eq!(normalize("/%ff"), Err(ResolveError::BadRequest));
+		assert_eq!(normalize("page.md"), Err(ResolveError::BadRequest));
+	}
+
+	#[test]
+	fn encode_round_trips() {
+		for url in ["/wiki/page one", "/a%3Fb#c", "/ünï/(x)+y;z=1", "/100%25"] {
+			let path = normalize(url).unwrap();
+			assert_eq!(normalize(&encode(&path)).unwrap(), path);
+		}
+		assert_eq!(encode("/wiki/page one?"), "/wiki/page%20one%3F");
+		assert_eq!(encode("/~user/a:b@c"), "/~user/a:b@c");
+	}
+
+	#[test]
+	fn query_strings() {
+		assert_eq!(query("/page?a=1&b"), Some("a=1&b"));
+		assert_eq!(query("/page?a=1#top"), Some("a=1"));
+		assert_eq!(query("/page?"), Some(""));
+		assert_eq!(query("/page#a?b"), None);
+		assert_eq!(query("/page"), None);
+	}
+
+	#[test]
+	fn resolve_rejects_unnormalized_paths() {
+		for path in ["/../etc/passwd", "/docs/../page.md", "/./page.md"] {
+			assert_eq!(
+				resolve("/srv/site", path, SymlinkPolicy::Follow),
+				Err(ResolveError::Forbidden)
+			);
+		}
+		assert_eq!(
+			resolve("/srv/site", "/docs/page.md", SymlinkPolicy::Follow).unwrap(),
+			Path::new("/srv/site/docs/page.md")
+		);
+	}
+
+	#[test]
+	fn resolve_hides_dotfiles() {
+		for path in [
+			"/.env",
+			"/.git/config",
+			"/docs/.secret/page.md",
+			"/docs/.htaccess",
+		] {
+			assert_eq!(
+				resolve("/srv/site", path, SymlinkPolicy::Follow),
+				Err(ResolveError::Hidden)
+			);
+		}
+		assert_eq!(
+			resolve(
+				"/srv/site",
+				"/.well-known/security.txt",
+				SymlinkPolicy::Follow
+			)
+			.unwrap(),
+			Path::new("/srv/site/.well-known/security.txt")
+		);
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn resolve_follow() {
+		let (dir, root) = site("follow");
+
+		assert!(resolve(&root, "/inside.md", SymlinkPolicy::Follow).is_ok());
+		assert!(resolve(&root, "/escape.md", SymlinkPolicy::Follow).is_ok());
+		assert!(resolve(&root, "/docs/linked/secret.md", SymlinkPolicy::Follow).is_ok());
+
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn resolve_within_root() {
+		let (dir, root) = site("within-root");
+
+		assert!(resolve(&root, "/page.md", SymlinkPolicy::WithinRoot).is_ok());
+		assert!(resolve(&root, "/inside.md", SymlinkPolicy::WithinRoot).is_ok());
+		assert!(resolve(&root, "/missing.md", SymlinkPolicy::WithinRoot).is_ok());
+		assert_eq!(
+			resolve(&root, "/escape.md", SymlinkPolicy::WithinRoot),
+			Err(ResolveError::Forbidden)
+		);
+		assert_eq!(
+			resolve(&root, "/docs/linked/secret.md", SymlinkPolicy::WithinRoot),
+			Err(ResolveError::Forbidden)
+		);
+
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn resolve_never() {
+		let (dir, root) = site("never");
+
+		assert!(resolve(&root, "/page.md", SymlinkPolicy::Never).is_ok());
+		assert!(resolve(&root, "/docs/missing.md", SymlinkPolicy::Never).is_ok());
+		assert_eq!(
+			resolve(&root, "/inside.md", SymlinkPolicy::Never),
+			Err(ResolveError::Forbidden)
+		);
+		assert_eq!(
+			resolve(&root, "/docs/linked/secret.md", SymlinkPolicy::Never),
+			Err(ResolveError::Forbidden)
+		);
+
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+}
@@ -0,0 +1,649 @@
+use std::{
+	fmt,
+	io::{self, BufRead, BufReader, Read, Write},
+	net::{TcpStream, ToSocketAddrs},
+	sync::{
+		atomic::{AtomicUsize, Ordering},
+		OnceLock,
+	},
+	time::Duration,
+};
+
+use mdbutler::{Body, HttpHeader, HttpMethod, HttpRequest, HttpResponse};
+
+use super::path;
+use crate::config::{self, ProxyMount};
+
+/// Headers that only concern a single connection and are never forwarded, in either direction.
+const HOP_BY_HOP: [&str; 9] = [
+	"Connection",
+	"Keep-Alive",
+	"Proxy-Authenticate",
+	"Proxy-Authorization",
+	"Proxy-Connection",
+	"TE",
+	"Trailer",
+	"Transfer-Encoding",
+	"Upgrade",
+];
+
+/// Upper bound on the size of an upstream's status line and headers.
+const MAX_HEAD: u64 = 64 * 1024;
+
+/// # ProxyError
+/// Why a request couldn't be forwarded.
+#[derive(Debug)]
+pub enum ProxyError {
+	/// No upstream accepted the connection.
+	Connect(io::Error),
+	/// The upstream took too long.
+	Timeout,
+	/// The upstream sent something that isn't HTTP.
+	BadResponse(String),
+	Io(io::Error),
+}
+
+impl ProxyError {
+	pub fn status(&self) -> u16 {
+		match self {
+			Self::Timeout => 504,
+			_ => 502,
+		}
+	}
+}
+
+impl fmt::Display for ProxyError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Connect(why) => write!(f, "Failed to connect to upstream: {why}"),
+			Self::Timeout => write!(f, "Upstream timed out"),
+			Self::BadResponse(why) => write!(f, "Bad response from upstream: {why}"),
+			Self::Io(why) => write!(f, "Upstream connection failed: {why}"),
+		}
+	}
+}
+
+impl std::error::Error for ProxyError {}
+
+impl From<io::Error> for ProxyError {
+	fn from(why: io::Error) -> Self {
+		match why.kind() {
+			io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => Self::Timeout,
+			_ => Self::Io(why),
+		}
+	}
+}
+
+/// An upstream address, `http://host:port/base` in the config.
+struct Upstream {
+	/// `host:port`
+	authority: String,
+	/// Path prepended to forwarded requests, without a trailing slash.
+	base: String,
+}
+
+impl Upstream {
+	fn parse(upstream: &str) -> Result<Self, String> {
+		let rest = match upstream.split_once("://") {
+			Some(("http", rest)) => rest,
+			Some((scheme, _)) => {
+				return Err(format!("unsupported scheme `{scheme}` in `{upstream}`"))
+			}
+			None => upstream,
+		};
+
+		let (authority, base) = match rest.find('/') {
+			Some(pos) => (&rest[..pos], rest[pos..].trim_end_matches('/')),
+			None => (rest, ""),
+		};
+		if authority.is_empty() {
+			return Err(format!("missing host in `{upstream}`"));
+		}
+
+		// Default to port 80, minding IPv6 literals like `[::1]`.
+		let authority = match authority.rsplit_once(':') {
+			Some((_, port)) if !port.contains(']') => authority.to_string(),
+			_ => format!("{authority}:80"),
+		};
+
+		Ok(Self {
+			authority,
+			base: base.to_string(),
+		})
+	}
+}
+
+/// Response head from an upstream with its (de-chunked) body still on the wire.
+pub struct UpstreamResponse {
+	pub status: u16,
+	/// End-to-end headers in the order the upstream sent them, repeated ones stay separate.
+	pub headers: Vec<HttpHeader>,
+	pub body: Box<dyn Read + Send>,
+	/// Size of `body`, unknown for chunked bodies and ones that end with the connection.
+	pub length: Option<u64>,
+}
+
+impl UpstreamResponse {
+	/// Turn this into a response that streams the body from the upstream as the client reads it.
+	pub fn into_response(self) -> HttpResponse {
+		HttpResponse {
+			status_code: self.status.into(),
+			headers: self.headers,
+			content: Body::Stream {
+				reader: self.body,
+				length: self.length,
+			},
+			..Default::default()
+		}
+	}
+}
+
+/// Check the upstream addresses of every mount, so typos show up on startup.
+pub fn validate(mounts: &[ProxyMount]) -> Result<(), String> {
+	for mount in mounts {
+		if mount.prefix.is_none() && mount.hosts.is_empty() {
+			return Err(String::from(
+				"proxy mounts need a `prefix`, `hosts` or both",
+			));
+		}
+		if mount.upstreams.is_empty() {
+			return Err(String::from("proxy mounts need at least one upstream"));
+		}
+		for upstream in &mount.upstreams {
+			Upstream::parse(upstream)?;
+		}
+	}
+
+	Ok(())
+}
+
+/// Index of the first mount `request` should be forwarded to, `path` is its normalized path.
+pub fn find(request: &HttpRequest, path: &str) -> Option<usize> {
+	// We can't forward a method we don't know the name of.
+	if request.method == HttpMethod::Unknown {
+		return None;
+	}
+
+	let host = request.header("Host");
+	config::get()
+		.proxies
+		.iter()
+		.position(|mount| mount.matches(host, path))
+}
+
+/// # forward
+/// Send `request` to the next upstream of mount number `index`.
+///
+/// The upstream gets the normalized `path` the mount was picked by, so it sees the same
+/// resource we matched. Upstreams are picked round-robin; one that refuses the connection is
+/// skipped in favour of the next.
+pub fn forward(
+	index: usize,
+	request: &HttpRequest,
+	path: &str,
+) -> Result<UpstreamResponse, ProxyError> {
+	static NEXT: OnceLock<Vec<AtomicUsize>> = OnceLock::new();
+
+	let config = config::get();
+	let mount = &config.proxies[index];
+	let next = NEXT.get_or_init(|| config.proxies.iter().map(|_| AtomicUsize::new(0)).collect());
+
+	let path = upstream_path(mount, path, path::query(&request.uri));
+
+	let start = next[index].fetch_add(1, Ordering::Relaxed);
+	let mut last_error = None;
+	for offset in 0..mount.upstreams.len() {
+		let upstream = &mount.upstreams[(start + offset) % mount.upstreams.len()];
+		let upstream = Upstream::parse(upstream).map_err(ProxyError::BadResponse)?;
+
+		match connect(&upstream, Duration::from_secs(mount.connect_timeout)) {
+			Ok(stream) => {
+				stream.set_read_timeout(Some(Duration::from_secs(mount.read_timeout)))?;
+				stream.set_write_timeout(Some(Duration::from_secs(mount.read_timeout)))?;
+
+				let path = format!("{}{path}", upstream.base);
+				return send(stream, &upstream, &path, request);
+			}
+			Err(why) => last_error = Some(why),
+		}
+	}
+
+	Err(ProxyError::Connect(last_error.unwrap_or_else(|| {
+		io::Error::new(io::ErrorKind::NotFound, "no upstreams")
+	})))
+}
+
+/// The request target for an upstream of `mount`, from the normalized `path` and the `query`
+/// the client sent.
+fn upstream_path(mount: &ProxyMount, path: &str, query: Option<&str>) -> String {
+	let path = match (&mount.prefix, mount.strip_prefix) {
+		(Some(prefix), true) => match &path[prefix.trim_end_matches('/').len()..] {
+			"" => "/",
+			rest => rest,
+		},
+		_ => path,
+	};
+
+	match query {
+		Some(query) => format!("{}?{query}", path::encode(path)),
+		None => path::encode(path),
+	}
+}
+
+fn connect(upstream: &Upstream, timeout: Duration) -> io::Result<TcpStream> {
+	let mut last_error = None;
+
+	for addr in upstream.authority.to_socket_addrs()? {
+		match TcpStream::connect_timeout(&addr, timeout) {
+			Ok(stream) => return Ok(stream),
+			Err(why) => last_error = Some(why),
+		}
+	}
+
+	Err(last_error
+		.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "host did not resolve")))
+}
+
+fn send(
+	mut stream: TcpStream,
+	upstream: &Upstream,
+	path: &str,
+	request: &HttpRequest,
+) -> Result<UpstreamResponse, ProxyError> {
+	stream.write_all(request_head(upstream, path, request).as_bytes())?;
+	stream.write_all(&request.content)?;
+	stream.flush()?;
+
+	read_response(BufReader::new(stream), request.method == HttpMethod::Head)
+}
+
+/// The head of `request` as it goes to `upstream`, with the hop-by-hop headers swapped for ours.
+fn request_head(upstream: &Upstream, path: &str, request: &HttpRequest) -> String {
+	let mut head = format!("{} {path} HTTP/1.1\r\n", request.method);
+	head.push_str(&format!("Host: {}\r\n", upstream.authority));
+
+	let skip = [
+		"Host",
+		"Content-Length",
+		"X-Forwarded-For",
+		"X-Forwarded-Host",
+		"X-Forwarded-Proto",
+	];
+	for header in end_to_end(&request.headers) {
+		if skip
+			.iter()
+			.any(|skip| skip.eq_ignore_ascii_case(&header.key))
+		{
+			continue;
+		}
+		head.push_str(&format!("{}: {}\r\n", header.key, header.val));
+	}
+
+	// Add ourselves to the chain of proxies the request passed through, the chain may be split
+	// over several headers.
+	let mut chain: Vec<String> = request
+		.headers
+		.iter()
+		.filter(|header| header.key.eq_ignore_ascii_case("X-Forwarded-For"))
+		.map(|header| header.val.trim().to_string())
+		.collect();
+	chain.extend(request.peer.map(|peer| peer.ip().to_string()));
+	if !chain.is_empty() {
+		head.push_str(&format!("X-Forwarded-For: {}\r\n", chain.join(", ")));
+	}
+	if let Some(host) = request.header("Host") {
+		head.push_str(&format!("X-Forwarded-Host: {host}\r\n"));
+	}
+	head.push_str(&format!("X-Forwarded-Proto: {}\r\n", super::scheme()));
+
+	if !request.content.is_empty()
+		|| matches!(
+			request.method,
+			HttpMethod::Post | HttpMethod::Put | HttpMethod::Patch
+		) {
+		head.push_str(&format!("Content-Length: {}\r\n", request.content.len()));
+	}
+	head.push_str("Connection: close\r\n\r\n");
+
+	head
+}
+
+fn read_response<R: BufRead + Send + 'static>(
+	mut reader: R,
+	head_only: bool,
+) -> Result<UpstreamResponse, ProxyError> {
+	let mut limited = (&mut reader).take(MAX_HEAD);
+
+	let mut line = String::new();
+	limited.read_line(&mut line)?;
+	let status = line
+		.split_whitespace()
+		.nth(1)
+		.and_then(|status| status.parse::<u16>().ok())
+		.filter(|_| line.starts_with("HTTP/"))
+		.ok_or_else(|| ProxyError::BadResponse(format!("status line `{}`", line.trim_end())))?;
+
+	let mut headers = Vec::new();
+	let (mut chunked, mut length) = (false, None);
+	loop {
+		line.clear();
+		if limited.read_line(&mut line)? == 0 {
+			return Err(ProxyError::BadResponse(String::from("headers cut short")));
+		}
+		let line = line.trim_end();
+		if line.is_empty() {
+			break;
+		}
+
+		let Some((name, value)) = line.split_once(':') else {
+			return Err(ProxyError::BadResponse(format!("header `{line}`")));
+		};
+		let (name, value) = (name.trim(), value.trim());
+
+		if name.eq_ignore_ascii_case("Transfer-Encoding") {
+			chunked = value.to_ascii_lowercase().contains("chunked");
+		} else if name.eq_ignore_ascii_case("Content-Length") {
+			length = value.parse::<u64>().ok();
+		}
+		headers.push(HttpHeader {
+			key: name.to_string(),
+			val: value.to_string(),
+		});
+	}
+
+	// Answers to HEAD keep the length the upstream announced, otherwise we send our own.
+	let headers = end_to_end(&headers)
+		.filter(|header| head_only || !header.key.eq_ignore_ascii_case("Content-Length"))
+		.cloned()
+		.collect();
+
+	let (body, length): (Box<dyn Read + Send>, _) =
+		if head_only || status == 204 || status == 304 || status < 200 {
+			(Box::new(io::empty()), Some(0))
+		} else if chunked {
+			let body = Chunked {
+				inner: reader,
+				remaining: 0,
+				done: false,
+			};
+			(Box::new(body), None)
+		} else if let Some(length) = length {
+			(Box::new(reader.take(length)), Some(length))
+		} else {
+			(Box::new(reader), None)
+		};
+
+	Ok(UpstreamResponse {
+		status,
+		headers,
+		body,
+		length,
+	})
+}
+
+/// The headers meant for the other end of the chain: the fixed hop-by-hop ones and those named in
+/// `Connection` are left out.
+fn end_to_end(headers: &[HttpHeader]) -> impl Iterator<Item = &HttpHeader> {
+	let listed: Vec<&str> = headers
+		.iter()
+		.filter(|header| header.key.eq_ignore_ascii_case("Connection"))
+		.flat_map(|header| header.val.split(','))
+		.map(str::trim)
+		.collect();
+
+	headers.iter().filter(move |header| {
+		!HOP_BY_HOP
+			.iter()
+			.chain(&listed)
+			.any(|hop| hop.eq_ignore_ascii_case(&header.key))
+	})
+}
+
+/// Decoder for `Transfer-Encoding: chunked` bodies.
+struct Chunked<R> {
+	inner: R,
+	/// Bytes left in the current chunk.
+	remaining: u64,
+	done: bool,
+}
+
+impl<R: BufRead> Read for Chunked<R> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		if self.done || buf.is_empty() {
+			return Ok(0);
+		}
+
+		if self.remaining == 0 {
+			let mut line = String::new();
+			self.inner.read_line(&mut line)?;
+			let size = line.trim().split(';').next().unwrap_or_default();
+			self.remaining = u64::from_str_radix(size, 16)
+				.map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad chunk size"))?;
+
+			if self.remaining == 0 {
+				// Skip trailers up to the final empty line.
+				loop {
+					line.clear();
+					if self.inner.read_line(&mut line)? == 0 || line.trim().is_empty() {
+						break;
+					}
+				}
+				self.done = true;
+				return Ok(0);
+			}
+		}
+
+		let max = self.remaining.min(buf.len() as u64) as usize;
+		let read = self.inner.read(&mut buf[..max])?;
+		if read == 0 {
+			return Err(io::Error::new(
+				io::ErrorKind::UnexpectedEof,
+				"chunk cut short",
+			));
+		}
+		self.remaining -= read as u64;
+
+		// Every chunk is followed by CRLF.
+		if self.remaining == 0 {
+			let mut crlf = [0; 2];
+			self.inner.read_exact(&mut crlf)?;
+		}
+
+		Ok(read)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::Cursor;
+
+	use super::*;
+
+	fn header(key: &str, val: &str) -> HttpHeader {
+		HttpHeader {
+			key: key.to_string(),
+			val: val.to_string(),
+		}
+	}
+
+	fn mount(prefix: &str, strip_prefix: bool) -> ProxyMount {
+		ProxyMount {
+			prefix: Some(prefix.to_string()),
+			hosts: Vec::new(),
+			upstreams: vec![String::from("http://127.0.0.1:9000")],
+			strip_prefix,
+			connect_timeout: 5,
+			read_timeout: 30,
+		}
+	}
+
+	fn response(raw: &str, head_only: bool) -> UpstreamResponse {
+		read_response(Cursor::new(raw.as_bytes().to_vec()), head_only).unwrap()
+	}
+
+	fn body(response: UpstreamResponse) -> String {
+		let mut body = String::new();
+		let mut reader = response.body;
+		reader.read_to_string(&mut body).unwrap();
+		body
+	}
+
+	#[test]
+	fn mounts_match_normalized_paths() {
+		let mount = mount("/upload", false);
+		let matches = |url: &str| mount.matches(None, &path::normalize(url).unwrap());
+
+		assert!(matches("/upload/file"));
+		assert!(matches("/./upload/../upload/file"));
+		assert!(!matches("/upload/../wiki/page"));
+		assert!(!matches("/uploads"));
+	}
+
+	#[test]
+	fn upstream_paths() {
+		let path = |mount: &ProxyMount, url: &str| {
+			let normalized = path::normalize(url).unwrap();
+			upstream_path(mount, &normalized, path::query(url))
+		};
+
+		let kept = mount("/app/", false);
+		assert_eq!(path(&kept, "/app/./x/../page?a=1&b"), "/app/page?a=1&b");
+		assert_eq!(path(&kept, "/app/a%20b%3Fc"), "/app/a%20b%3Fc");
+
+		let stripped = mount("/app/", true);
+		assert_eq!(path(&stripped, "/app/page"), "/page");
+		assert_eq!(path(&stripped, "/app"), "/");
+		assert_eq!(path(&stripped, "/app/?q"), "/?q");
+	}
+
+	#[test]
+	fn request_head_drops_hop_by_hop_headers() {
+		let request = HttpRequest {
+			method: HttpMethod::Get,
+			uri: String::from("/app"),
+			protocol_ver: String::from("HTTP/1.1"),
+			headers: vec![
+				header("Host", "example.com"),
+				header("Connection", "keep-alive, X-Secret"),
+				header("X-Secret", "hunter2"),
+				header("Keep-Alive", "timeout=5"),
+				header("Accept", "text/html"),
+				header("Accept", "text/plain"),
+				header("X-Forwarded-For", "10.0.0.1"),
+				header("X-Forwarded-For", "10.0.0.2"),
+			],
+			content: Vec::new(),
+			peer: Some("192.0.2.7:4000".parse().unwrap()),
+		};
+		let upstream = Upstream::parse("http://127.0.0.1:9000").unwrap();
+		let head = request_head(&upstream, "/app", &request);
+
+		assert!(
+			head.starts_with("GET /app HTTP/1.1\r\nHost: 127.0.0.1:9000\r\n"),
+			"{head}"
+		);
+		assert!(!head.contains("X-Secret"), "{head}");
+		assert!(!head.contains("Keep-Alive"), "{head}");
+		assert!(!head.contains("keep-alive"), "{head}");
+		assert!(
+			head.contains("Accept: text/html\r\nAccept: text/plain\r\n"),
+			"{head}"
+		);
+		assert!(
+			head.contains("X-Forwarded-For: 10.0.0.1, 10.0.0.2, 192.0.2.7\r\n"),
+			"{head}"
+		);
+		assert!(head.contains("X-Forwarded-Host: example.com\r\n"), "{head}");
+		assert!(head.ends_with("Connection: close\r\n\r\n"), "{head}");
+	}
+
+	#[test]
+	fn response_keeps_repeated_headers() {
+		let response = response(
+			"HTTP/1.1 200 OK\r\n\
+			Set-Cookie: a=1; Path=/\r\n\
+			Set-Cookie: b=2, c=3\r\n\
+			Content-Length: 2\r\n\
+			\r\n\
+			ok",
+			false,
+		);
+
+		let cookies: Vec<&str> = response
+			.headers
+			.iter()
+			.filter(|header| header.key == "Set-Cookie")
+			.map(|header| header.val.as_str())
+			.collect();
+		assert_eq!(cookies, ["a=1; Path=/", "b=2, c=3"]);
+		assert_eq!(response.length, Some(2));
+		assert_eq!(body(response), "ok");
+	}
+
+	#[test]
+	fn response_drops_headers_listed_in_connection() {
+		let response = response(
+			"HTTP/1.1 200 OK\r\n\
+			X-Internal: 1\r\n\
+			Connection: close, X-Internal\r\n\
+			Keep-Alive: timeout=5\r\n\
+			Content-Type: text/plain\r\n\
+			\r\n",
+			false,
+		);
+
+		let names: Vec<&str> = response
+			.headers
+			.iter()
+			.map(|header| header.key.as_str())
+			.collect();
+		assert_eq!(names, ["Content-Type"]);
+		// Without a length the body lasts until the upstream hangs up.
+		assert_eq!(response.length, None);
+	}
+
+	#[test]
+	fn chunked_body_is_streamed() {
+		let response = response(
+			"HTTP/1.1 200 OK\r\n\
+			Transfer-Encoding: chunked\r\n\
+			\r\n\
+			5\r\nhello\r\n\
+			6;ext=1\r\n world\r\n\
+			0\r\nX-Trailer: 1\r\n\r\n",
+			false,
+		);
+
+		assert_eq!(response.length, None);
+		assert!(response.headers.is_empty());
+		assert_eq!(body(response), "hello world");
+	}
+
+	#[test]
+	fn head_keeps_the_announced_length() {
+		let response =
+			response("HTTP/1.1 200 OK\r\nContent-Length: 1234\r\n\r\n", true).into_response();
+
+		assert_eq!(response.header("Content-Length"), Some("1234"));
+		assert_eq!(response.content.len(), Some(0));
+	}
+
+	#[test]
+	fn response_is_streamed() {
+		let response = response(
+			"HTTP/1.1 404 Not Found\r\nContent-Length: 4\r\n\r\ngone",
+			false,
+		)
+		.into_response();
+
+		assert_eq!(response.status_code, 404);
+		assert_eq!(response.header("Content-Length"), None);
+		assert!(matches!(
+			response.content,
+			Body::Stream {
+				length: Some(4),
+				..
+			}
+		));
+	}
+}
@@ -0,0 +1,347 @@
+use std::{
+	collections::VecDeque,
+	fs::File,
+	io::{self, Read, Seek, SeekFrom},
+	ops::RangeInclusive,
+};
+
+use mdbutler::{Body, HttpRequest, HttpResponse};
+
+use super::conditional::Validators;
+
+/// More ranges than this in one request are almost certainly an attempt to make us do silly
+/// amounts of work, so we just send the whole file instead.
+const MAX_RANGES: usize = 16;
+
+/// The requested ranges can't be served for a file of this length.
+#[derive(Debug)]
+pub struct Unsatisfiable;
+
+/// # parse
+/// Parse a `Range` header for a file of `len` bytes.
+///
+/// Returns `None` if the header should be ignored (unknown unit, malformed, too many ranges) in
+/// which case the full file is sent, as RFC 9110 section 14.2 allows. Ranges that start past the
+/// end of the file are dropped; if none are left the request is unsatisfiable. Overlapping and
+/// adjacent ranges are merged, in order.
+pub fn parse(range: &str, len: u64) -> Option<Result<Vec<RangeInclusive<u64>>, Unsatisfiable>> {
+	let (unit, specs) = range.trim().split_once('=')?;
+	if !unit.trim().eq_ignore_ascii_case("bytes") {
+		return None;
+	}
+
+	let mut ranges = Vec::new();
+	for spec in specs
+		.split(',')
+		.map(str::trim)
+		.filter(|spec| !spec.is_empty())
+	{
+		let (start, end) = spec.split_once('-')?;
+		let (start, end) = (start.trim(), end.trim());
+
+		let range = if start.is_empty() {
+			// `-500`: the last 500 bytes.
+			let suffix: u64 = end.parse().ok()?;
+			if suffix == 0 || len == 0 {
+				continue;
+			}
+			len.saturating_sub(suffix)..=len - 1
+		} else {
+			let start: u64 = start.parse().ok()?;
+			let end = if end.is_empty() {
+				u64::MAX
+			} else {
+				end.parse().ok()?
+			};
+			if end < start {
+				return None;
+			}
+			if start >= len {
+				continue;
+			}
+			start..=end.min(len - 1)
+		};
+
+		ranges.push(range);
+		if ranges.len() > MAX_RANGES {
+			return None;
+		}
+	}
+
+	if ranges.is_empty() {
+		return Some(Err(Unsatisfiable));
+	}
+
+	// Merged, asking for the same bytes over and over never adds up to more than the file.
+	Some(Ok(merge(ranges)))
+}
+
+/// Sort `ranges` and join the ones that overlap or touch.
+fn merge(mut ranges: Vec<RangeInclusive<u64>>) -> Vec<RangeInclusive<u64>> {
+	ranges.sort_by_key(|range| *range.start());
+
+	let mut merged: Vec<RangeInclusive<u64>> = Vec::with_capacity(ranges.len());
+	for range in ranges {
+		match merged.last_mut() {
+			Some(last) if *range.start() <= last.end() + 1 => {
+				*last = *last.start()..=*last.end().max(range.end());
+			}
+			_ => merged.push(range),
+		}
+	}
+
+	merged
+}
+
+/// Whether an `If-Range` precondition (if any) allows us to honour the `Range` header.
+///
+/// Only strong validators count, a weak ETag or a date that doesn't match exactly means the
+/// client gets the full, current file.
+pub fn if_range_matches(request: &HttpRequest, validators: &Validators) -> bool {
+	let Some(if_range) = request.header("If-Range") else {
+		return true;
+	};
+	let if_range = if_range.trim();
+
+	if if_range.starts_with('"') {
+		if_range == validators.etag
+	} else {
+		match validators.last_modified {
+			Some(modified) => mdbutler::http_date(modified) == if_range,
+			None => false,
+		}
+	}
+}
+
+/// # partial_response
+/// Turn `response` into a 206 that streams the requested ranges of `file`.
+///
+/// A single range is sent as-is with a `Content-Range` header, multiple ranges are wrapped in a
+/// `multipart/byteranges` body.
+pub fn partial_response(
+	response: &mut HttpResponse,
+	mime_type: &str,
+	len: u64,
+	ranges: &[RangeInclusive<u64>],
+	file: File,
+) {
+	let mut pieces = VecDeque::new();
+
+	if let [range] = ranges {
+		response.set_header("Content-Type", mime_type);
+		response.set_header("Content-Range", content_range(range, len));
+		pieces.push_back(Piece::File(range.clone()));
+	} else {
+		let boundary = boundary(response.header("ETag").unwrap_or_default(), ranges);
+		response.set_header(
+			"Content-Type",
+			format!("multipart/byteranges; boundary={boundary}"),
+		);
+
+		for range in ranges {
+			let head = format!(
+				"--{boundary}\r\nContent-Type: {mime_type}\r\nContent-Range: {}\r\n\r\n",
+				content_range(range, len)
+			);
+			pieces.push_back(Piece::Bytes(head.into_bytes()));
+			pieces.push_back(Piece::File(range.clone()));
+			pieces.push_back(Piece::Bytes(b"\r\n".to_vec()));
+		}
+		pieces.push_back(Piece::Bytes(format!("--{boundary}--\r\n").into_bytes()));
+	}
+
+	let length = pieces.iter().map(Piece::len).sum();
+	response.status_code = 206;
+	response.content = Body::Stream {
+		reader: Box::new(Pieces { file, pieces }),
+		length: Some(length),
+	};
+}
+
+/// Headers for a 416 response.
+pub fn unsatisfiable(response: &mut HttpResponse, len: u64) {
+	response.set_header("Content-Range", format!("bytes */{len}"));
+}
+
+fn content_range(range: &RangeInclusive<u64>, len: u64) -> String {
+	format!("bytes {}-{}/{len}", range.start(), range.end())
+}
+
+/// A multipart boundary that is very unlikely to show up in the file itself.
+fn boundary(etag: &str, ranges: &[RangeInclusive<u64>]) -> String {
+	use std::hash::{Hash, Hasher};
+
+	let mut hasher = std::collections::hash_map::DefaultHasher::new();
+	etag.hash(&mut hasher);
+	ranges.hash(&mut hasher);
+	std::time::SystemTime::now().hash(&mut hasher);
+
+	format!("mdbutler-{:016x}", hasher.finish())
+}
+
+/// Part of a partial response body.
+enum Piece {
+	/// Multipart framing, what's left of it.
+	Bytes(Vec<u8>),
+	/// Bytes of the file still to be sent.
+	File(RangeInclusive<u64>),
+}
+
+impl Piece {
+	fn len(&self) -> u64 {
+		match self {
+			Piece::Bytes(bytes) => bytes.len() as u64,
+			Piece::File(range) => range.end() - range.start() + 1,
+		}
+	}
+}
+
+/// Reads the pieces of a partial response one after the other, seeking through the file as it
+/// goes.
+struct Pieces {
+	file: File,
+	pieces: VecDeque<Piece>,
+}
+
+impl Read for Pieces {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let Some(piece) = self.pieces.front_mut() else {
+			return Ok(0);
+		};
+
+		let (read, done) = match piece {
+			Piece::Bytes(bytes) => {
+				let read = bytes.len().min(buf.len());
+				buf[..read].copy_from_slice(&bytes[..read]);
+				bytes.drain(..read);
+				(read, bytes.is_empty())
+			}
+			Piece::File(range) => {
+				let max = (range.end() - range.start() + 1).min(buf.len() as u64) as usize;
+				self.file.seek(SeekFrom::Start(*range.start()))?;
+				let read = self.file.read(&mut buf[..max])?;
+				if read == 0 {
+					return Err(io::Error::new(
+						io::ErrorKind::UnexpectedEof,
+						"file shrank while it was sent",
+					));
+				}
+
+				let done = read as u64 == range.end() - range.start() + 1;
+				*range = range.start() + read as u64..=*range.end();
+				(read, done)
+			}
+		};
+		if done {
+			self.pieces.pop_front();
+		}
+
+		Ok(read)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{fs, path::PathBuf};
+
+	use super::*;
+
+	fn ranges(header: &str, len: u64) -> Vec<RangeInclusive<u64>> {
+		parse(header, len).unwrap().unwrap()
+	}
+
+	/// A file holding `0123456789`.
+	fn file(name: &str) -> (PathBuf, File) {
+		let path =
+			std::env::temp_dir().join(format!("mdbutler-range-{}-{name}", std::process::id()));
+		fs::write(&path, "0123456789").unwrap();
+		let file = File::open(&path).unwrap();
+		(path, file)
+	}
+
+	fn body(response: HttpResponse) -> (Option<u64>, String) {
+		let Body::Stream { mut reader, length } = response.content else {
+			panic!("partial responses are streamed");
+		};
+		let mut body = String::new();
+		reader.read_to_string(&mut body).unwrap();
+		(length, body)
+	}
+
+	#[test]
+	fn parses_ranges() {
+		assert_eq!(ranges("bytes=0-4", 10), [0..=4]);
+		assert_eq!(ranges("bytes=5-", 10), [5..=9]);
+		assert_eq!(ranges("bytes=-3", 10), [7..=9]);
+		assert_eq!(ranges("bytes=8-100", 10), [8..=9]);
+		assert_eq!(ranges("bytes=0-1, 20-30", 10), [0..=1]);
+		assert!(parse("items=0-4", 10).is_none());
+		assert!(parse("bytes=4-2", 10).is_none());
+		assert!(parse("bytes=x-2", 10).is_none());
+	}
+
+	#[test]
+	fn merges_overlapping_and_adjacent_ranges() {
+		assert_eq!(ranges("bytes=6-7,0-2,3-4", 10), [0..=4, 6..=7]);
+		assert_eq!(ranges("bytes=0-4,2-6", 20), [0..=6]);
+		assert_eq!(ranges("bytes=5-9,-2", 10), [5..=9]);
+	}
+
+	#[test]
+	fn unsatisfiable_ranges() {
+		assert!(matches!(parse("bytes=10-", 10), Some(Err(Unsatisfiable))));
+		assert!(matches!(parse("bytes=-5", 0), Some(Err(Unsatisfiable))));
+		assert!(matches!(
+			parse("bytes=20-,-0", 10),
+			Some(Err(Unsatisfiable))
+		));
+	}
+
+	#[test]
+	fn overlapping_ranges_are_satisfiable() {
+		assert_eq!(ranges("bytes=0-5,2-8", 10), [0..=8]);
+		assert_eq!(ranges("bytes=0-,0-", 10), [0..=9]);
+		assert_eq!(
+			ranges(&format!("bytes={}", vec!["0-9"; MAX_RANGES].join(",")), 10),
+			[0..=9]
+		);
+	}
+
+	#[test]
+	fn too_many_ranges_are_ignored() {
+		let header = format!("bytes={}", vec!["0-0"; MAX_RANGES + 1].join(","));
+		assert!(parse(&header, 100).is_none());
+	}
+
+	#[test]
+	fn single_range_is_streamed() {
+		let (path, file) = file("single");
+		let mut response = HttpResponse::default();
+		partial_response(&mut response, "text/plain", 10, &[2..=5], file);
+
+		assert_eq!(response.status_code, 206);
+		assert_eq!(response.header("Content-Range"), Some("bytes 2-5/10"));
+		assert_eq!(response.header("Content-Type"), Some("text/plain"));
+		assert_eq!(body(response), (Some(4), String::from("2345")));
+		fs::remove_file(path).unwrap();
+	}
+
+	#[test]
+	fn multiple_ranges_are_multipart() {
+		let (path, file) = file("multi");
+		let mut response = HttpResponse::default();
+		partial_response(&mut response, "text/plain", 10, &[0..=1, 8..=9], file);
+
+		let content_type = response.header("Content-Type").unwrap().to_string();
+		let boundary = content_type
+			.strip_prefix("multipart/byteranges; boundary=")
+			.unwrap();
+		let expected = format!(
+			"--{boundary}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/10\r\n\r\n01\r\n\
+			--{boundary}\r\nContent-Type: text/plain\r\nContent-Range: bytes 8-9/10\r\n\r\n89\r\n\
+			--{boundary}--\r\n"
+		);
+		assert_eq!(body(response), (Some(expected.len() as u64), expected));
+		fs::remove_file(path).unwrap();
+	}
+}
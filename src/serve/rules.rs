@@ -0,0 +1,434 @@
+use std::{
+	collections::HashMap,
+	fmt, fs,
+	path::Path,
+	sync::{Arc, Mutex, OnceLock},
+	time::SystemTime,
+};
+
+use mdbutler::error;
+use regex::Regex;
+
+use super::path;
+use crate::config::{self, Site};
+
+/// Name of the rules file looked up in the site root.
+pub const FILE_NAME: &str = "_redirects";
+
+/// # Rule
+/// A single redirect/rewrite rule.
+///
+/// Rules come from `[[rule]]` tables in the config and from a `_redirects` file in the site root,
+/// one rule per line:
+///
+/// ```text
+/// # from                 to                  status
+/// /old-page              /new-page
+/// /blog/*                /news/$1            302
+/// ~^/wiki/(\d+)$         /wiki/page-$1       308
+/// /internal/*            /wiki/$1            200
+/// /removed                                   410
+/// ```
+///
+/// `from` is matched against the request path (without the query string) and is either exact,
+/// a prefix ending in `*` (the rest is captured as `$1`) or a regex starting with `~`.
+/// `to` can refer to capture groups as `$1` or `${name}`. The status defaults to `301`; `301`,
+/// `302`, `307` and `308` redirect, `200` rewrites internally and `410` answers with Gone.
+/// The first matching rule wins and rules are applied only once, so rewrites can't loop.
+pub struct Rule {
+	/// Where the rule was defined, for error messages and the dry-run.
+	pub origin: String,
+	/// Pattern as written.
+	pub from: String,
+	pattern: Regex,
+	pub to: String,
+	pub status: u16,
+}
+
+/// What to do with a request.
+#[derive(Debug, PartialEq)]
+pub enum Action {
+	/// Answer with a redirect to the URL.
+	Redirect(u16, String),
+	/// Serve a different URL in place of the requested one.
+	Rewrite(String),
+	/// Answer with 410 Gone.
+	Gone,
+}
+
+/// # RuleError
+/// A rule that can't be used.
+#[derive(Clone, Debug)]
+pub struct RuleError {
+	origin: String,
+	reason: String,
+}
+
+impl fmt::Display for RuleError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "Invalid rule at {}: {}", self.origin, self.reason)
+	}
+}
+
+impl std::error::Error for RuleError {}
+
+impl Rule {
+	pub fn new(origin: String, from: &str, to: &str, status: u16) -> Result<Self, RuleError> {
+		let error = |reason: String| RuleError {
+			origin: origin.clone(),
+			reason,
+		};
+
+		let pattern = if let Some(regex) = from.strip_prefix('~') {
+			regex.trim().to_string()
+		} else if let Some(prefix) = from.strip_suffix('*') {
+			format!("^{}(.*)$", regex::escape(prefix))
+		} else {
+			format!("^{}$", regex::escape(from))
+		};
+		let pattern = Regex::new(&pattern).map_err(|why| error(why.to_string()))?;
+
+		match status {
+			301 | 302 | 307 | 308 | 200 if to.is_empty() => {
+				return Err(error(format!("status {status} needs a target")))
+			}
+			301 | 302 | 307 | 308 | 200 | 410 => (),
+			_ => return Err(error(format!("unsupported status {status}"))),
+		}
+
+		Ok(Self {
+			origin,
+			from: from.to_string(),
+			pattern,
+			to: to.to_string(),
+			status,
+		})
+	}
+
+	/// Match a normalized `path` against this rule, the `query` string is carried over unless
+	/// `to` has its own.
+	pub fn apply(&self, path: &str, query: Option<&str>) -> Option<Action> {
+		let captures = self.pattern.captures(path)?;
+
+		if self.status == 410 {
+			return Some(Action::Gone);
+		}
+
+		let mut target = expand(&self.to, &captures);
+		if let (Some(query), false) = (query, target.contains('?')) {
+			target = format!("{target}?{query}");
+		}
+
+		Some(match self.status {
+			200 => Action::Rewrite(target),
+			status => Action::Redirect(status, target),
+		})
+	}
+}
+
+/// # expand
+/// Replace `$1`, `$name` and `${name}` in `to` with what the rule captured, `$$` is a `$`.
+///
+/// Same syntax as [`regex::Captures::expand`], but the captures come from the decoded request
+/// path and are percent-encoded again before they end up in a `Location` header.
+fn expand(to: &str, captures: &regex::Captures) -> String {
+	let mut target = String::with_capacity(to.len());
+	let mut rest = to;
+
+	while let Some(dollar) = rest.find('$') {
+		target.push_str(&rest[..dollar]);
+		rest = &rest[dollar + 1..];
+
+		let (name, next) = if let Some(after) = rest.strip_prefix('$') {
+			target.push('$');
+			rest = after;
+			continue;
+		} else if let Some((name, after)) =
+			rest.strip_prefix('{').and_then(|rest| rest.split_once('}'))
+		{
+			(name, after)
+		} else {
+			let end = rest
+				.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+				.unwrap_or(rest.len());
+			(&rest[..end], &rest[end..])
+		};
+
+		if name.is_empty() {
+			target.push('$');
+			continue;
+		}
+		let capture = match name.parse::<usize>() {
+			Ok(index) => captures.get(index),
+			Err(_) => captures.name(name),
+		};
+		if let Some(capture) = capture {
+			target.push_str(&path::encode(capture.as_str()));
+		}
+		rest = next;
+	}
+	target.push_str(rest);
+
+	target
+}
+
+/// Parse a `_redirects` file, `origin` is used to point at offending lines.
+pub fn parse(source: &str, origin: &str) -> Result<Vec<Rule>, RuleError> {
+	let mut rules = Vec::new();
+
+	for (number, line) in source.lines().enumerate() {
+		let line = line.trim();
+		if line.is_empty() || line.starts_with('#') {
+			continue;
+		}
+
+		let origin = format!("{origin}:{}", number + 1);
+		let mut fields: Vec<&str> = line.split_whitespace().collect();
+
+		let status = match fields.last().map(|field| field.parse::<u16>()) {
+			Some(Ok(status)) if fields.len() > 1 => {
+				fields.pop();
+				status
+			}
+			_ => 301,
+		};
+
+		let rule = match fields.as_slice() {
+			[from] => Rule::new(origin, from, "", status)?,
+			[from, to] => Rule::new(origin, from, to, status)?,
+			_ => {
+				return Err(RuleError {
+					origin,
+					reason: String::from("expected `from [to] [status]`"),
+				})
+			}
+		};
+		rules.push(rule);
+	}
+
+	Ok(rules)
+}
+
+/// Rules from the config, compiled once on first use.
+fn config_rules() -> &'static Result<Vec<Rule>, RuleError> {
+	static RULES: OnceLock<Result<Vec<Rule>, RuleError>> = OnceLock::new();
+
+	RULES.get_or_init(|| {
+		config::get()
+			.rules
+			.iter()
+			.enumerate()
+			.map(|(i, rule)| {
+				let origin = format!("{}, rule {}", config::FILE_NAME, i + 1);
+				Rule::new(origin, &rule.from, &rule.to, rule.status)
+			})
+			.collect()
+	})
+}
+
+/// The `_redirects` file of a site, reloaded whenever it changes.
+fn file_rules(root: &str) -> Result<Arc<Vec<Rule>>, RuleError> {
+	type Loaded = HashMap<String, (Option<SystemTime>, Arc<Vec<Rule>>)>;
+	static LOADED: OnceLock<Mutex<Loaded>> = OnceLock::new();
+
+	let path = Path::new(root).join(FILE_NAME);
+	let modified = fs::metadata(&path).and_then(|meta| meta.modified()).ok();
+
+	let loaded = LOADED.get_or_init(Default::default);
+	if let Some((stamp, rules)) = loaded.lock().unwrap().get(root) {
+		if *stamp == modified {
+			return Ok(Arc::clone(rules));
+		}
+	}
+
+	// Read and parse without holding the lock, other sites' requests shouldn't wait on our disk.
+	// Threads racing to reload the same file just do the work twice.
+	let rules = match fs::read_to_string(&path) {
+		Ok(source) => parse(&source, &path.display().to_string()),
+		Err(_) => Ok(Vec::new()),
+	};
+	// A broken file is reported once and then ignored until it changes again.
+	let (rules, result) = match rules {
+		Ok(rules) => {
+			let rules = Arc::new(rules);
+			(Arc::clone(&rules), Ok(rules))
+		}
+		Err(why) => (Arc::new(Vec::new()), Err(why)),
+	};
+	loaded
+		.lock()
+		.unwrap()
+		.insert(root.to_string(), (modified, rules));
+
+	result
+}
+
+/// Check that every rule for `site` compiles, so mistakes show up on startup.
+pub fn validate(site: &Site) -> Result<(), RuleError> {
+	if let Err(why) = config_rules() {
+		return Err(why.clone());
+	}
+	file_rules(&site.root).map(|_| ())
+}
+
+/// # evaluate
+/// Find the first rule for `site` that matches `path`, as returned by
+/// [`normalize`](super::path::normalize).
+///
+/// Returns where the rule came from along with what to do. A broken `_redirects` file is logged
+/// and skipped as a whole, so a typo can't take the site down.
+pub fn evaluate(site: &Site, path: &str, query: Option<&str>) -> Option<(String, Action)> {
+	if let Ok(rules) = config_rules() {
+		if let Some(found) = first_match(rules, path, query) {
+			return Some(found);
+		}
+	}
+
+	match file_rules(&site.root) {
+		Ok(rules) => first_match(&rules, path, query),
+		Err(why) => {
+			error!("{why}");
+			None
+		}
+	}
+}
+
+fn first_match(rules: &[Rule], path: &str, query: Option<&str>) -> Option<(String, Action)> {
+	rules.iter().find_map(|rule| {
+		rule.apply(path, query)
+			.map(|action| (format!("{} (`{}`)", rule.origin, rule.from), action))
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{fs::File, time::Duration};
+
+	use super::*;
+	use crate::config::SymlinkPolicy;
+	use crate::serve::path::{normalize, query, ResolveError};
+
+	/// Apply the first of `rules` to a raw request URL, the way requests are matched.
+	fn apply(rules: &[Rule], url: &str) -> Option<Action> {
+		let path = normalize(url).unwrap();
+		first_match(rules, &path, query(url)).map(|(_, action)| action)
+	}
+
+	fn rules(source: &str) -> Vec<Rule> {
+		parse(source, "test").unwrap()
+	}
+
+	#[test]
+	fn rules_match_normalized_paths() {
+		let rules = rules("/old-page /new-page");
+		let moved = Some(Action::Redirect(301, String::from("/new-page")));
+
+		assert_eq!(apply(&rules, "/old-page"), moved);
+		assert_eq!(apply(&rules, "/./old-page"), moved);
+		assert_eq!(apply(&rules, "//old-page"), moved);
+		assert_eq!(apply(&rules, "/old%2Dpage"), moved);
+		assert_eq!(apply(&rules, "/wiki/../old-page"), moved);
+		assert_eq!(apply(&rules, "/old-pages"), None);
+	}
+
+	#[test]
+	fn queries_are_carried_over() {
+		let rules = rules("/a /b\n/c /d?x=1 302");
+
+		assert_eq!(
+			apply(&rules, "/a?q=1"),
+			Some(Action::Redirect(301, String::from("/b?q=1")))
+		);
+		assert_eq!(
+			apply(&rules, "/c?q=1"),
+			Some(Action::Redirect(302, String::from("/d?x=1")))
+		);
+	}
+
+	#[test]
+	fn splats_regexes_rewrites_and_gone() {
+		let rules = rules(
+			"/blog/* /news/$1 302\n~^/wiki/(\\d+)$ /wiki/page-$1 308\n/internal/* /wiki/$1 200\n/removed 410",
+		);
+
+		assert_eq!(
+			apply(&rules, "/blog/2024/post"),
+			Some(Action::Redirect(302, String::from("/news/2024/post")))
+		);
+		assert_eq!(
+			apply(&rules, "/wiki/%34%32"),
+			Some(Action::Redirect(308, String::from("/wiki/page-42")))
+		);
+		assert_eq!(
+			apply(&rules, "/internal/page"),
+			Some(Action::Rewrite(String::from("/wiki/page")))
+		);
+		assert_eq!(apply(&rules, "/removed"), Some(Action::Gone));
+	}
+
+	#[test]
+	fn captures_are_encoded() {
+		let rules = rules("~^/blog/([^/]+)$ /news/$1\n~^/a/(?P<page>.*)$ /b/${page}-$$ 302");
+
+		assert_eq!(
+			normalize("/blog/x%0d%0aSet-Cookie:%20pwn=1"),
+			Err(ResolveError::BadRequest)
+		);
+		// Even if a line break got this far, it doesn't make it into the target.
+		assert_eq!(
+			first_match(&rules, "/blog/x\r\nSet-Cookie: pwn=1", None).map(|(_, action)| action),
+			Some(Action::Redirect(
+				301,
+				String::from("/news/x%0D%0ASet-Cookie:%20pwn=1")
+			))
+		);
+		assert_eq!(
+			apply(&rules, "/a/page%20one"),
+			Some(Action::Redirect(302, String::from("/b/page%20one-$")))
+		);
+	}
+
+	#[test]
+	fn bad_lines_are_reported() {
+		let why = parse("/ok /fine\n/a /b /c", "_redirects").err().unwrap();
+		assert_eq!(why.origin, "_redirects:2");
+	}
+
+	#[test]
+	fn file_rules_reload_when_changed() {
+		let dir = std::env::temp_dir().join(format!("mdbutler-rules-{}", std::process::id()));
+		let _ = fs::remove_dir_all(&dir);
+		fs::create_dir_all(&dir).unwrap();
+		let root = dir.to_string_lossy().into_owned();
+		let file = dir.join(FILE_NAME);
+		let site = Site {
+			root: root.clone(),
+			asset_path: String::from("/assets"),
+			css_path: String::from("/assets/css/master.css"),
+			pretty: false,
+			follow_symlinks: SymlinkPolicy::WithinRoot,
+			autoindex: Vec::new(),
+		};
+
+		assert!(file_rules(&root).unwrap().is_empty());
+
+		fs::write(&file, "/old-page /new-page").unwrap();
+		assert_eq!(file_rules(&root).unwrap().len(), 1);
+		assert!(evaluate(&site, "/old-page", None).is_some());
+
+		// A broken file is reported, then left out until it changes.
+		fs::write(&file, "/a /b /c").unwrap();
+		let later = SystemTime::now() + Duration::from_secs(5);
+		File::options()
+			.write(true)
+			.open(&file)
+			.unwrap()
+			.set_modified(later)
+			.unwrap();
+		assert!(file_rules(&root).is_err());
+		assert!(file_rules(&root).unwrap().is_empty());
+		assert!(evaluate(&site, "/old-page", None).is_none());
+
+		fs::remove_dir_all(&dir).unwrap();
+	}
+}
@@ -0,0 +1,135 @@
+use std::{
+	io,
+	net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream},
+	process,
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Condvar, Mutex,
+	},
+	thread,
+	time::{Duration, Instant},
+};
+
+use mdbutler::{info, warn};
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+static IN_FLIGHT: Mutex<usize> = Mutex::new(0);
+static DRAINED: Condvar = Condvar::new();
+/// Listeners with an accept loop of our own, poked so they notice the shutdown.
+static LISTENERS: Mutex<Vec<SocketAddr>> = Mutex::new(Vec::new());
+
+/// Marks a request as in flight until dropped.
+pub struct InFlight(());
+
+impl Drop for InFlight {
+	fn drop(&mut self) {
+		let mut in_flight = IN_FLIGHT.lock().unwrap();
+		*in_flight -= 1;
+		if *in_flight == 0 {
+			DRAINED.notify_all();
+		}
+	}
+}
+
+/// Count a request as in flight, shutdown waits for it until the returned guard is dropped.
+///
+/// Accept loops call this as soon as they have a connection, before checking [`requested`], and
+/// hand the guard to whoever answers it. A connection is either counted before the drain starts
+/// or turned away.
+pub fn track() -> InFlight {
+	*IN_FLIGHT.lock().unwrap() += 1;
+	InFlight(())
+}
+
+/// Whether we are shutting down, accept loops should stop and new requests be turned away.
+pub fn requested() -> bool {
+	REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Have [`install`] wake up the accept loop of `listener` on shutdown.
+pub fn register(listener: &TcpListener) -> io::Result<()> {
+	let mut addr = listener.local_addr()?;
+	// Connecting to `0.0.0.0` isn't portable, the loopback address always works.
+	if addr.ip().is_unspecified() {
+		addr.set_ip(match addr.ip() {
+			IpAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+			IpAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+		});
+	}
+	LISTENERS.lock().unwrap().push(addr);
+
+	Ok(())
+}
+
+/// # install
+/// Shut down gracefully on SIGINT or SIGTERM.
+///
+/// New connections are turned away while requests in flight get up to `grace` to finish,
+/// then the process exits. A second signal exits right away.
+pub fn install(grace: Duration) -> io::Result<()> {
+	#[cfg(unix)]
+	{
+		use signal_hook::consts::{SIGINT, SIGTERM};
+
+		let mut signals = signal_hook::iterator::Signals::new([SIGINT, SIGTERM])?;
+		thread::spawn(move || {
+			let mut signals = signals.forever();
+			if signals.next().is_none() {
+				return;
+			}
+			thread::spawn(move || drain(grace));
+
+			if signals.next().is_some() {
+				warn!("Shutting down immediately");
+				process::exit(1);
+			}
+		});
+	}
+
+	Ok(())
+}
+
+/// Never returns, for accept loops that stopped while the drain finishes in the background.
+pub fn wait() -> ! {
+	loop {
+		thread::park();
+	}
+}
+
+fn drain(grace: Duration) -> ! {
+	// Set while holding the count, so a connection tracked after this sees it.
+	let in_flight = {
+		let in_flight = IN_FLIGHT.lock().unwrap();
+		REQUESTED.store(true, Ordering::SeqCst);
+		*in_flight
+	};
+	info!(
+		"Shutting down, waiting up to {}s for {in_flight} request(s) in flight",
+		grace.as_secs()
+	);
+
+	for addr in LISTENERS.lock().unwrap().iter() {
+		let _ = TcpStream::connect_timeout(addr, Duration::from_secs(1));
+	}
+
+	let deadline = Instant::now() + grace;
+	let mut in_flight = IN_FLIGHT.lock().unwrap();
+	while *in_flight > 0 {
+		let left = deadline.saturating_duration_since(Instant::now());
+		if left.is_zero() {
+			break;
+		}
+		in_flight = DRAINED.wait_timeout(in_flight, left).unwrap().0;
+	}
+
+	match *in_flight {
+		0 => {
+			info!("All requests finished, bye");
+			process::exit(0)
+		}
+		left => {
+			warn!("Grace period is over, dropping {left} request(s)");
+			process::exit(1)
+		}
+	}
+}
@@ -0,0 +1,63 @@
+use std::{
+	io,
+	net::{SocketAddr, TcpListener, TcpStream},
+	thread,
+	time::Duration,
+};
+
+use mdbutler::{error, HttpMethod, HttpRequest, RequestError, RequestLimits};
+
+use super::Handler;
+
+/// How long a client gets to send its request.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// # run
+/// Accept connections on `listener` until shutdown, one thread per connection.
+///
+/// This is how the `snowboard` crate served requests, which the backend was built on at first.
+/// Requests are read and answered with the library's [`HttpRequest`] and
+/// [`mdbutler::HttpResponse`] like the other backends do.
+pub fn run(listener: TcpListener, handler: Handler) {
+	loop {
+		let (stream, ip) = match listener.accept() {
+			Ok(accepted) => accepted,
+			Err(why) => {
+				error!("Failed to accept connection: {why}");
+				continue;
+			}
+		};
+
+		let in_flight = super::shutdown::track();
+		if super::shutdown::requested() {
+			break;
+		}
+
+		thread::spawn(move || {
+			let _in_flight = in_flight;
+			if let Err(why) = handle(stream, ip, handler) {
+				if why.kind() != io::ErrorKind::BrokenPipe {
+					error!("Failed to answer {ip}: {why}");
+				}
+			}
+		});
+	}
+}
+
+fn handle(mut stream: TcpStream, ip: SocketAddr, handler: Handler) -> io::Result<()> {
+	stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+	stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+
+	let (response, head) = match HttpRequest::read_from(&mut stream, &RequestLimits::default()) {
+		Ok(mut request) => {
+			request.peer = Some(ip);
+			let head = request.method == HttpMethod::Head;
+			(handler(request), head)
+		}
+		// The client hung up before sending anything, nobody left to answer.
+		Err(RequestError::Io(why)) if why.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+		Err(why) => (super::bad_request(&why), false),
+	};
+
+	super::send(response, head, &mut stream)
+}
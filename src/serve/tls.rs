@@ -0,0 +1,236 @@
+use std::{
+	fmt, fs,
+	io::{self, BufReader, Write},
+	net::{SocketAddr, TcpListener, TcpStream},
+	sync::{Arc, RwLock},
+	thread,
+	time::Duration,
+};
+
+use rustls::{
+	crypto::{ring, CryptoProvider},
+	server::{ClientHello, ResolvesServerCert},
+	sign::CertifiedKey,
+	ServerConfig, ServerConnection, StreamOwned,
+};
+
+use mdbutler::{debug, error, info, HttpMethod, HttpRequest, RequestError, RequestLimits};
+
+use super::Handler;
+use crate::config::{self, TlsConfig};
+
+/// How long a client gets for the handshake and its request.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// # TlsError
+/// Why the certificates couldn't be loaded.
+#[derive(Debug)]
+pub enum TlsError {
+	Io(String, io::Error),
+	/// The file has no certificate or key in it.
+	Missing(String, &'static str),
+	Rustls(rustls::Error),
+	/// Neither `cert`/`key` nor any `[[tls.certificate]]` are set.
+	NoCertificates,
+}
+
+impl fmt::Display for TlsError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Io(path, why) => write!(f, "Failed to read `{path}`: {why}"),
+			Self::Missing(path, what) => write!(f, "No {what} found in `{path}`"),
+			Self::Rustls(why) => write!(f, "TLS error: {why}"),
+			Self::NoCertificates => write!(f, "TLS is enabled but no certificates are configured"),
+		}
+	}
+}
+
+impl std::error::Error for TlsError {}
+
+impl From<rustls::Error> for TlsError {
+	fn from(why: rustls::Error) -> Self {
+		Self::Rustls(why)
+	}
+}
+
+/// Every certificate we can present, loaded from disk.
+#[derive(Debug)]
+struct Certificates {
+	default: Option<Arc<CertifiedKey>>,
+	named: Vec<(Vec<String>, Arc<CertifiedKey>)>,
+}
+
+impl Certificates {
+	fn load(tls: &TlsConfig, provider: &CryptoProvider) -> Result<Self, TlsError> {
+		let default = match (&tls.cert, &tls.key) {
+			(Some(cert), Some(key)) => Some(Arc::new(load_key_pair(cert, key, provider)?)),
+			_ => None,
+		};
+
+		let named = tls
+			.certificates
+			.iter()
+			.map(|entry| {
+				let key = load_key_pair(&entry.cert, &entry.key, provider)?;
+				Ok((entry.names.clone(), Arc::new(key)))
+			})
+			.collect::<Result<Vec<_>, TlsError>>()?;
+
+		if default.is_none() && named.is_empty() {
+			return Err(TlsError::NoCertificates);
+		}
+
+		Ok(Self { default, named })
+	}
+}
+
+fn load_key_pair(cert: &str, key: &str, provider: &CryptoProvider) -> Result<CertifiedKey, TlsError> {
+	let open = |path: &str| {
+		fs::File::open(path)
+			.map(BufReader::new)
+			.map_err(|why| TlsError::Io(path.to_string(), why))
+	};
+
+	let chain = rustls_pemfile::certs(&mut open(cert)?)
+		.collect::<Result<Vec<_>, _>>()
+		.map_err(|why| TlsError::Io(cert.to_string(), why))?;
+	if chain.is_empty() {
+		return Err(TlsError::Missing(cert.to_string(), "certificate"));
+	}
+
+	let private_key = rustls_pemfile::private_key(&mut open(key)?)
+		.map_err(|why| TlsError::Io(key.to_string(), why))?
+		.ok_or_else(|| TlsError::Missing(key.to_string(), "private key"))?;
+	let signing_key = provider.key_provider.load_private_key(private_key)?;
+
+	Ok(CertifiedKey::new(chain, signing_key))
+}
+
+/// Picks a certificate by the name the client asked for (SNI).
+#[derive(Debug)]
+struct Resolver {
+	certificates: RwLock<Arc<Certificates>>,
+}
+
+impl ResolvesServerCert for Resolver {
+	fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+		let certificates = Arc::clone(&self.certificates.read().unwrap());
+
+		// Same rules as for vhosts: exact names beat wildcards, longer wildcards beat shorter ones.
+		let best = client_hello.server_name().and_then(|name| {
+			let name = config::normalize_host(name);
+			certificates
+				.named
+				.iter()
+				.filter_map(|(names, key)| {
+					names
+						.iter()
+						.filter_map(|pattern| config::host_matches(pattern, &name))
+						.max()
+						.map(|specificity| (specificity, key))
+				})
+				.max_by_key(|(specificity, _)| *specificity)
+				.map(|(_, key)| Arc::clone(key))
+		});
+
+		best.or_else(|| certificates.default.clone())
+			.or_else(|| certificates.named.first().map(|(_, key)| Arc::clone(key)))
+	}
+}
+
+/// # server_config
+/// Load the certificates and build the rustls configuration.
+///
+/// On unix the certificates are reloaded on SIGHUP; if that fails the old ones stay in use.
+pub fn server_config(tls: &TlsConfig) -> Result<Arc<ServerConfig>, TlsError> {
+	let provider = Arc::new(ring::default_provider());
+	let resolver = Arc::new(Resolver {
+		certificates: RwLock::new(Arc::new(Certificates::load(tls, &provider)?)),
+	});
+
+	#[cfg(unix)]
+	{
+		let mut signals =
+			signal_hook::iterator::Signals::new([signal_hook::consts::SIGHUP]).map_err(|why| {
+				TlsError::Io(String::from("SIGHUP handler"), why)
+			})?;
+		let (tls, provider, resolver) = (tls.clone(), Arc::clone(&provider), Arc::clone(&resolver));
+
+		thread::spawn(move || {
+			for _ in signals.forever() {
+				match Certificates::load(&tls, &provider) {
+					Ok(certificates) => {
+						*resolver.certificates.write().unwrap() = Arc::new(certificates);
+						info!("Reloaded TLS certificates");
+					}
+					Err(why) => error!("Keeping the old TLS certificates: {why}"),
+				}
+			}
+		});
+	}
+
+	let mut config = ServerConfig::builder_with_provider(provider)
+		.with_safe_default_protocol_versions()?
+		.with_no_client_auth()
+		.with_cert_resolver(resolver);
+	config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+	Ok(Arc::new(config))
+}
+
+/// # run
+/// Accept HTTPS connections on `listener` until shutdown, one thread per connection.
+pub fn run(listener: TcpListener, config: Arc<ServerConfig>, handler: Handler) {
+	loop {
+		let (stream, ip) = match listener.accept() {
+			Ok(accepted) => accepted,
+			Err(why) => {
+				error!("Failed to accept connection: {why}");
+				continue;
+			}
+		};
+		let in_flight = super::shutdown::track();
+		if super::shutdown::requested() {
+			break;
+		}
+
+		let config = Arc::clone(&config);
+		thread::spawn(move || {
+			let _in_flight = in_flight;
+			if let Err(why) = handle(stream, ip, config, handler) {
+				// Mostly clients hanging up or failing the handshake, not worth more than a note.
+				if why.kind() != io::ErrorKind::UnexpectedEof {
+					debug!("TLS connection from {ip}: {why}");
+				}
+			}
+		});
+	}
+}
+
+fn handle(
+	stream: TcpStream,
+	ip: SocketAddr,
+	config: Arc<ServerConfig>,
+	handler: Handler,
+) -> io::Result<()> {
+	stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+	stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+
+	let connection = ServerConnection::new(config).map_err(io::Error::other)?;
+	let mut stream = StreamOwned::new(connection, stream);
+
+	let (response, head) = match HttpRequest::read_from(&mut stream, &RequestLimits::default()) {
+		Ok(mut request) => {
+			request.peer = Some(ip);
+			super::set_secure(true);
+			let head = request.method == HttpMethod::Head;
+			(handler(request), head)
+		}
+		Err(RequestError::Io(why)) => return Err(why),
+		Err(why) => (super::bad_request(&why), false),
+	};
+
+	super::send(response, head, &mut stream)?;
+	stream.conn.send_close_notify();
+	stream.flush()
+}